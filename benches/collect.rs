@@ -0,0 +1,85 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use account_balance_calculator::account_manager::AccountManager;
+use account_balance_calculator::common::{Transaction, TransactionType};
+
+/// Number of clients spread over the workers, every possible client id.
+const CLIENTS: u32 = u16::MAX as u32 + 1;
+
+const NUM_WORKERS: usize = 8;
+
+/// Counts every allocation, so the allocations made while collecting can be reported.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns an `AccountManager` that was given a deposit for each of `CLIENTS` clients.
+async fn populated_account_manager() -> AccountManager {
+    let account_manager = AccountManager::new(NUM_WORKERS);
+    for client in 0..CLIENTS {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: client as u16,
+                tx: client,
+                amount: Some(1.into()),
+                expected_balance: None,
+            })
+            .await
+            .unwrap();
+    }
+    account_manager
+}
+
+fn collect_account_states(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let account_manager = runtime.block_on(populated_account_manager());
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let states = runtime.block_on(account_manager.collect_account_states());
+    eprintln!(
+        "Collecting {} clients from {} workers made {} allocations",
+        states.unwrap().len(),
+        NUM_WORKERS,
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    );
+
+    let mut group = c.benchmark_group("collect");
+    group.throughput(Throughput::Elements(u64::from(CLIENTS)));
+    group.sample_size(10);
+    group.bench_function("collect_account_states", |b| {
+        b.iter_batched(
+            || runtime.block_on(populated_account_manager()),
+            |account_manager| runtime.block_on(account_manager.collect_account_states()),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, collect_account_states);
+criterion_main!(benches);
@@ -0,0 +1,282 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use account_balance_calculator::account_manager::AccountManager;
+use account_balance_calculator::common::{Transaction, TransactionType};
+use account_balance_calculator::{run_with_args, Args};
+
+/// Number of rows in the generated input.
+const ROWS: u64 = 100_000;
+
+/// Input sizes (in rows) compared between a single worker and many workers.
+const WORKER_COMPARISON_ROWS: [u64; 6] = [10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+/// Number of clients (and so output rows) of the output formatting benchmark.
+const OUTPUT_CLIENTS: u32 = 65_536;
+
+/// Number of workers the multi-worker runs use, regardless of the number of cpus.
+const MANY_WORKERS: usize = 8;
+
+/// Worker channel sizes compared by `channel_buffer_sizes`, the default and a large one.
+const CHANNEL_BUFFER_SIZES: [usize; 2] = [32, 1024];
+
+/// Number of transactions given to the `AccountManager` by `process_worker_counts`.
+const MANAGER_ROWS: u64 = 1_000_000;
+
+/// Worker counts compared by `process_worker_counts`.
+const MANAGER_WORKER_COUNTS: [usize; 4] = [1, 2, 4, 8];
+
+/// Writes an input of `rows` deposits and withdrawals spread over many clients and returns
+/// its path.
+fn generate_input(rows: u64) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_bench_{}_{}.csv",
+        std::process::id(),
+        rows
+    ));
+    let mut input = "type,client,tx,amount\n".to_string();
+    for tx in 0..rows {
+        let transaction_type = if tx % 3 == 2 { "withdrawal" } else { "deposit" };
+        input.push_str(&format!(
+            "{},{},{},{}.{:04}\n",
+            transaction_type,
+            tx % 1000,
+            tx,
+            tx % 100,
+            tx % 10000
+        ));
+    }
+    std::fs::write(&path, input).unwrap();
+    path
+}
+
+fn end_to_end(c: &mut Criterion) {
+    let input_path = generate_input(ROWS);
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("end_to_end");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    group.bench_function("read_parse_dispatch", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let args =
+                Args::parse_from(["account_balance_calculator", input_path.to_str().unwrap()]);
+            run_with_args(args, tokio::io::sink()).await.unwrap();
+        })
+    });
+    group.finish();
+    std::fs::remove_file(&input_path).unwrap();
+}
+
+/// Compares a single worker against `MANY_WORKERS` workers for every size in
+/// `WORKER_COMPARISON_ROWS`, to find the input size where the setup and dispatch overhead of
+/// many workers starts to pay off. The worker count is given explicitly, so the input size
+/// heuristic does not apply.
+fn single_vs_many_workers(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("single_vs_many_workers");
+    group.sample_size(10);
+    for rows in WORKER_COMPARISON_ROWS {
+        let input_path = generate_input(rows);
+        group.throughput(Throughput::Elements(rows));
+        for workers in [1, MANY_WORKERS] {
+            // Only read when the run starts, so this picks the worker count of the next runs.
+            std::env::set_var("ACCOUNT_WORKER_SPAWNS", workers.to_string());
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_workers", workers), rows),
+                &input_path,
+                |b, input_path| {
+                    b.to_async(&runtime).iter(|| async {
+                        let args = Args::parse_from([
+                            "account_balance_calculator",
+                            input_path.to_str().unwrap(),
+                        ]);
+                        run_with_args(args, tokio::io::sink()).await.unwrap();
+                    })
+                },
+            );
+        }
+        std::fs::remove_file(&input_path).unwrap();
+    }
+    std::env::remove_var("ACCOUNT_WORKER_SPAWNS");
+    group.finish();
+}
+
+/// Compares the throughput of `MANY_WORKERS` workers with each of `CHANNEL_BUFFER_SIZES` as
+/// the size of their channels, to see how much waiting on full channels costs.
+fn channel_buffer_sizes(c: &mut Criterion) {
+    let input_path = generate_input(ROWS);
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("channel_buffer_sizes");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    for channel_buffer_size in CHANNEL_BUFFER_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(channel_buffer_size),
+            &channel_buffer_size,
+            |b, channel_buffer_size| {
+                b.to_async(&runtime).iter(|| async {
+                    let args = Args::parse_from([
+                        "account_balance_calculator",
+                        "--workers",
+                        &MANY_WORKERS.to_string(),
+                        "--channel-buffer-size",
+                        &channel_buffer_size.to_string(),
+                        input_path.to_str().unwrap(),
+                    ]);
+                    run_with_args(args, tokio::io::sink()).await.unwrap();
+                })
+            },
+        );
+    }
+    group.finish();
+    std::fs::remove_file(&input_path).unwrap();
+}
+
+/// Sends transactions straight to the workers, skipping the csv reading and parsing, so the
+/// cost of the maps keyed by client and tx id (see the `fast-hash` feature) is not hidden by
+/// everything else.
+fn process_without_parsing(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("process_without_parsing");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    group.bench_function("deposits_and_disputes", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let account_manager = AccountManager::new(1);
+            let mut batcher = account_manager.batcher(256);
+            for row in 0..ROWS {
+                let tx = (row / 2) as u32;
+                let (transaction_type, amount) = if row % 2 == 0 {
+                    (TransactionType::Deposit, Some(1.into()))
+                } else {
+                    (TransactionType::Dispute, None)
+                };
+                let transaction = Transaction {
+                    transaction_type,
+                    client: (tx % 1000) as u16,
+                    tx,
+                    amount,
+                    expected_balance: None,
+                };
+                batcher
+                    .process_transaction_from_row(row as usize, transaction)
+                    .await
+                    .unwrap();
+            }
+            batcher.flush().await.unwrap();
+            drop(batcher);
+            account_manager.collect_account_states().await.unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// Measures how many deposits and withdrawals per second an `AccountManager` applies with each
+/// of `MANAGER_WORKER_COUNTS` workers, without reading or parsing any csv. Every client id is
+/// used, so a fair share of the transactions create their client.
+fn process_worker_counts(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("process_worker_counts");
+    group.throughput(Throughput::Elements(MANAGER_ROWS));
+    group.sample_size(10);
+    for workers in MANAGER_WORKER_COUNTS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(workers),
+            &workers,
+            |b, &workers| {
+                b.to_async(&runtime).iter(|| async move {
+                    let account_manager = AccountManager::new(workers);
+                    let mut batcher = account_manager.batcher(256);
+                    for row in 0..MANAGER_ROWS {
+                        // Every client gets a deposit of 2 followed by a withdrawal of 1.
+                        let (transaction_type, amount) = if (row >> 16) % 2 == 0 {
+                            (TransactionType::Deposit, 2)
+                        } else {
+                            (TransactionType::Withdrawal, 1)
+                        };
+                        let transaction = Transaction {
+                            transaction_type,
+                            client: row as u16,
+                            tx: row as u32,
+                            amount: Some(amount.into()),
+                            expected_balance: None,
+                        };
+                        batcher
+                            .process_transaction_from_row(row as usize, transaction)
+                            .await
+                            .unwrap();
+                    }
+                    batcher.flush().await.unwrap();
+                    drop(batcher);
+                    account_manager.collect_account_states().await.unwrap();
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Writes an input with a deposit for each of `clients` clients, so the output has a row per
+/// deposit, and returns its path.
+fn generate_one_deposit_per_client_input(clients: u32) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_bench_{}_clients_{}.csv",
+        std::process::id(),
+        clients
+    ));
+    let mut input = "type,client,tx,amount\n".to_string();
+    for client in 0..clients {
+        input.push_str(&format!(
+            "deposit,{},{},{}.{:06}\n",
+            client,
+            client,
+            client % 1000,
+            client * 37 % 1_000_000
+        ));
+    }
+    std::fs::write(&path, input).unwrap();
+    path
+}
+
+/// Compares formatting the output rows one by one against `--parallel-format`, with as many
+/// clients (and so output rows) as there can be.
+fn output_formatting(c: &mut Criterion) {
+    let input_path = generate_one_deposit_per_client_input(OUTPUT_CLIENTS);
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("output_formatting");
+    group.throughput(Throughput::Elements(OUTPUT_CLIENTS.into()));
+    group.sample_size(10);
+    for (name, extra_args) in [
+        ("sequential", &[][..]),
+        ("parallel", &["--parallel-format"][..]),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                let mut args = vec!["account_balance_calculator", "--with-netflow"];
+                args.extend(extra_args);
+                args.push(input_path.to_str().unwrap());
+                run_with_args(Args::parse_from(args), tokio::io::sink())
+                    .await
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+    std::fs::remove_file(&input_path).unwrap();
+}
+
+criterion_group!(
+    benches,
+    end_to_end,
+    single_vs_many_workers,
+    channel_buffer_sizes,
+    process_without_parsing,
+    process_worker_counts,
+    output_formatting
+);
+criterion_main!(benches);
@@ -1,27 +1,392 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use crate::common::{ClientId, Transaction, TransactionType, TxId};
+use crate::output::{round_amount, AccountBalance, OutputRounding, RoundingMode};
+use crate::rejections::{Rejection, RejectionCategory};
 use crate::{make_other_err, Error, ErrorKind};
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, Signed, Zero};
+use clap::ArgEnum;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use smallvec::{smallvec, SmallVec};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 
+/// Hasher of the maps keyed by client or tx id. SipHash (the std default) resists inputs
+/// crafted to collide, the `fast-hash` feature trades that for throughput when the input is
+/// trusted.
+#[cfg(not(feature = "fast-hash"))]
+type IdHasher = std::collections::hash_map::RandomState;
+#[cfg(feature = "fast-hash")]
+type IdHasher = fxhash::FxBuildHasher;
+
+/// A map keyed by client or tx id.
+type IdMap<K, V> = HashMap<K, V, IdHasher>;
+
+/// A set of client or tx ids.
+type IdSet<K> = HashSet<K, IdHasher>;
+
 /// Number of messages allowed to be in cross-spawn channel before backpressure
-/// is applied to writer.
-const CHANNEL_BUFFER_SIZE: usize = 32;
+/// is applied to writer, unless `ProcessingConfig::channel_buffer_size` is set.
+pub const CHANNEL_BUFFER_SIZE: usize = 32;
 
-/// Holds the current state of a client (account).
+/// Configuration shared by every worker spawned by an `AccountManager`.
+#[derive(Clone, Default, Debug)]
+pub struct ProcessingConfig {
+    /// When set, the client's available balance is compared against the transaction's
+    /// `expected_balance` after every transaction. Any difference larger than this value is
+    /// recorded as a `BalanceMismatch`.
+    pub running_balance_tolerance: Option<BigDecimal>,
+    /// When set, every client keeps a history of its applied transactions and once all
+    /// transactions are processed the last N of them are undone for every client.
+    pub rewind: Option<usize>,
+    /// How clients are assigned to workers.
+    pub route_by: RouteBy,
+    /// When set, every rejected transaction is recorded as a `Rejection` in the results.
+    pub collect_rejections: bool,
+    /// Every client additionally keeps one state per policy in here, computed from the same
+    /// transactions. Accessible with `ClientState::scenarios`.
+    pub scenarios: Vec<DisputePolicy>,
+    /// How far below zero withdrawals may take the available funds of every client.
+    pub overdraft_limit: Option<BigDecimal>,
+    /// Number of batches each worker channel holds before senders have to wait. Defaults to
+    /// `CHANNEL_BUFFER_SIZE`.
+    pub channel_buffer_size: Option<usize>,
+    /// Maximum number of transactions accepted for any single client. Every transaction past
+    /// it is rejected, whether or not it would otherwise have been applied.
+    pub max_tx_per_client: Option<usize>,
+    /// When set, amounts are rounded with this context before they are applied and
+    /// `available` and `held` are kept at exactly its scale, so stored balances always add up
+    /// exactly. A disputed deposit holds its rounded amount.
+    pub decimal_context: Option<DecimalContext>,
+    /// Reject every transaction with a tx id of 0, for feeds that use it to mean "no id".
+    pub forbid_zero_tx: bool,
+    /// Reject deposits to locked accounts, so an account is frozen entirely once it is
+    /// charged back. By default deposits to locked accounts are still applied.
+    pub lock_freezes_deposits: bool,
+    /// Allow disputing withdrawals (eg: a withdrawal the client did not authorize), not only
+    /// deposits. See `ClientState::dispute_withdrawal` for how the funds move.
+    pub allow_withdrawal_disputes: bool,
+    /// How disputes of deposits whose funds are no longer available are handled. Scenario
+    /// states keep their own policy.
+    pub dispute_policy: DisputePolicy,
+    /// How deposits reusing the tx id of an earlier deposit or withdrawal are handled.
+    pub deposit_collision_policy: DepositCollisionPolicy,
+    /// The first rejected transaction fails the whole run (`collect_results` returns its
+    /// error) and nothing after it is applied.
+    pub fail_on_rejection: bool,
+    /// Do not print rejected transactions to stderr, eg: when the caller reports the collected
+    /// rejections itself.
+    pub quiet_rejections: bool,
+}
+
+/// Scale and rounding every amount is brought to before any arithmetic is done with it.
+/// Additions and subtractions of numbers of the same scale keep that scale, so balances never
+/// grow in scale no matter how many transactions (eg: dispute/resolve cycles) are applied.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecimalContext {
+    /// Number of decimal places.
+    pub scale: i64,
+    pub rounding: RoundingMode,
+}
+
+impl DecimalContext {
+    /// Returns `amount` rounded with `rounding` to exactly `scale` decimal places.
+    pub fn apply(&self, amount: &BigDecimal) -> BigDecimal {
+        round_amount(amount, self.scale, self.rounding).with_scale(self.scale)
+    }
+}
+
+/// How disputes are handled when the client no longer has the disputed funds available
+/// (eg: they were withdrawn after the deposit).
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputePolicy {
+    /// The dispute is rejected.
+    #[default]
+    Strict,
+    /// The dispute is applied anyway and the available funds go negative.
+    AllowNegative,
+}
+
+impl DisputePolicy {
+    /// Name of the policy as it is given on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisputePolicy::Strict => "strict",
+            DisputePolicy::AllowNegative => "allow-negative",
+        }
+    }
+}
+
+/// How a deposit reusing the tx id of an earlier deposit or withdrawal of the same client is
+/// handled. A deposit with the same amount as the earlier deposit is assumed to be a harmless
+/// redelivery of it, anything else is a collision of two different transactions.
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DepositCollisionPolicy {
+    /// Redeliveries are skipped without being reported, collisions are rejected.
+    Ignore,
+    /// Redeliveries and collisions are both rejected.
+    #[default]
+    Reject,
+    /// Redeliveries are skipped without being reported, a collision fails the whole run.
+    Error,
+}
+
+/// Why a deposit's tx id is already in use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TxReuse {
+    /// Same amount as the earlier deposit with the tx id.
+    Redelivery,
+    /// The tx id belongs to a withdrawal or a deposit with a different amount.
+    Collision,
+}
+
+/// Strategy used to pick the worker a client's transactions are sent to. Every strategy
+/// always sends the same client to the same worker, so per-client ordering is preserved.
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteBy {
+    /// `client % num_workers`. Cheap, but clients with ids sharing a common factor with the
+    /// number of workers all land on the same few workers.
+    Modulus,
+    /// Hash of the client id, which spreads sequential or clustered ids evenly.
+    #[default]
+    Hash,
+}
+
+impl RouteBy {
+    /// Returns the index of the worker `client` is routed to.
+    pub fn worker_index(self, client: ClientId, num_workers: usize) -> usize {
+        match self {
+            RouteBy::Modulus => (client as usize) % num_workers,
+            RouteBy::Hash => (mix64(client.into()) % num_workers as u64) as usize,
+        }
+    }
+}
+
+/// MurmurHash3's 64 bit finalizer. Every input bit affects every output bit, so the low bits
+/// used for picking a worker are well distributed even for clustered inputs.
+fn mix64(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Returns the amount of a deposit or withdrawal, which must be given and more than zero.
+fn positive_amount(transaction: &Transaction) -> Result<BigDecimal, Rejected> {
+    match &transaction.amount {
+        None => Err((
+            RejectionCategory::MissingAmount,
+            make_other_err!(
+                "Amount must be provided in {} : {:?}",
+                transaction.transaction_type.as_str(),
+                transaction
+            ),
+        )),
+        Some(amount) if !amount.is_positive() => Err((
+            RejectionCategory::InvalidAmount,
+            make_other_err!(
+                "Amount ({}) of a {} must be more than zero : {:?}",
+                amount,
+                transaction.transaction_type.as_str(),
+                transaction
+            ),
+        )),
+        Some(amount) => Ok(amount.clone()),
+    }
+}
+
+/// A transaction whose `expected_balance` did not match the computed available balance.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BalanceMismatch {
+    /// Row of the input the transaction came from (if known).
+    pub row_number: Option<usize>,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub expected: BigDecimal,
+    pub actual: BigDecimal,
+}
+
+/// Load information about a single worker, useful to see if clients are evenly spread
+/// across the workers.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct WorkerStats {
+    pub worker_index: usize,
+    /// Number of clients owned by this worker.
+    pub clients: usize,
+    /// Number of transactions this worker received (including ones that were rejected).
+    pub transactions_processed: u64,
+    /// Deposits that were skipped as redeliveries of an earlier deposit (see
+    /// `DepositCollisionPolicy`). They are neither applied nor rejected.
+    pub redeliveries_skipped: u64,
+    /// Wall-clock time spent processing transactions (excludes time waiting for work).
+    pub busy_time: Duration,
+}
+
+/// Everything collected from the workers once they have finished processing.
 #[derive(Default, Debug)]
+pub struct ProcessingResults {
+    pub client_states: VecDeque<ClientState>,
+    pub balance_mismatches: Vec<BalanceMismatch>,
+    /// Transactions that were rejected by a worker, only collected if requested in the
+    /// `ProcessingConfig`. Ordered by worker, not by row.
+    pub rejections: Vec<Rejection>,
+    /// Clients that only had disputes, resolves or chargebacks (which were all rejected since
+    /// they referenced a tx that does not exist). They are not in `client_states`. Sorted.
+    pub dangling_clients: Vec<ClientId>,
+    /// Stats for each worker, indexed by worker.
+    pub worker_stats: Vec<WorkerStats>,
+}
+
+impl ProcessingResults {
+    /// Moves everything from `other` into these results, eg: to combine the results yielded
+    /// by `AccountManager::finished_workers`.
+    pub fn append(&mut self, mut other: ProcessingResults) {
+        self.client_states.append(&mut other.client_states);
+        self.balance_mismatches
+            .append(&mut other.balance_mismatches);
+        self.rejections.append(&mut other.rejections);
+        self.dangling_clients.append(&mut other.dangling_clients);
+        self.dangling_clients.sort_unstable();
+        self.worker_stats.append(&mut other.worker_stats);
+        self.worker_stats
+            .sort_unstable_by_key(|stats| stats.worker_index);
+    }
+
+    fn add_worker_results(&mut self, worker_index: usize, mut worker_results: WorkerResults) {
+        self.worker_stats.push(WorkerStats {
+            worker_index,
+            ..worker_results.stats
+        });
+        self.client_states.extend(worker_results.client_states);
+        self.balance_mismatches
+            .append(&mut worker_results.balance_mismatches);
+        self.rejections.append(&mut worker_results.rejections);
+        self.dangling_clients
+            .append(&mut worker_results.dangling_clients);
+    }
+}
+
+/// A single transaction sent to a worker.
+struct TransactionMessage {
+    row_number: Option<usize>,
+    transaction: Transaction,
+}
+
+/// Transactions are sent across the worker channel in batches to reduce the number of
+/// channel operations. Batches up to this size do not need a heap allocation.
+const INLINE_BATCH_SIZE: usize = 16;
+
+/// Transactions sent together across the worker channel.
+type TransactionBatch = SmallVec<[TransactionMessage; INLINE_BATCH_SIZE]>;
+
+/// Message sent across the worker channel.
+// Boxing the batch would add an allocation to every batch only to shrink the rare snapshot
+// requests.
+#[allow(clippy::large_enum_variant)]
+enum WorkerMessage {
+    Transactions(TransactionBatch),
+    /// Asks the worker for the current balance of each of its clients. Since the channel is
+    /// ordered, every transaction sent before this message is reflected in the reply.
+    Snapshot {
+        rounding: OutputRounding,
+        reply: oneshot::Sender<Vec<AccountBalance>>,
+    },
+    /// Asks the worker for a copy of the current state of a single client, with the same
+    /// ordering guarantee as `Snapshot`.
+    ClientState {
+        client: ClientId,
+        reply: oneshot::Sender<Option<ClientState>>,
+    },
+}
+
+/// Final output of a single worker.
+#[derive(Default)]
+struct WorkerResults {
+    client_states: Vec<ClientState>,
+    balance_mismatches: Vec<BalanceMismatch>,
+    rejections: Vec<Rejection>,
+    dangling_clients: Vec<ClientId>,
+    stats: WorkerStats,
+}
+
+/// Holds the current state of a client (account).
+///
+/// Serialized into `--dump-final-state` snapshots. Every field added after `client`,
+/// `available`, `held`, `locked` and `tx_for_transaction_state` must be `#[serde(default)]`,
+/// so snapshots written before it was added can still be resumed.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ClientState {
     pub client: ClientId,
     pub available: BigDecimal,
     pub held: BigDecimal,
     pub locked: bool,
+    /// Set once the account has been closed with a `CloseAccount` transaction.
+    #[serde(default)]
+    pub closed: bool,
+    /// Sum of all accepted deposits.
+    #[serde(default)]
+    pub deposited: BigDecimal,
+    /// Sum of all accepted withdrawals.
+    #[serde(default)]
+    pub withdrawn: BigDecimal,
+    /// Sum of all funds removed by chargebacks.
+    #[serde(default)]
+    pub charged_back: BigDecimal,
+    /// Highest `available` balance the client reached, raised by deposits and resolves. It is
+    /// not lowered again when transactions are rewound.
+    #[serde(default)]
+    pub peak_available: BigDecimal,
+
+    tx_for_transaction_state: IdMap<TxId, (TransactionType, BigDecimal)>,
+    /// Withdrawals that were ever disputed. Their state in `tx_for_transaction_state` (eg:
+    /// `Dispute`) is the same as for deposits, so this is what tells them apart.
+    #[serde(default)]
+    disputed_withdrawals: IdSet<TxId>,
+    /// Applied transactions in order, only tracked (`Some`) when a rewind was requested.
+    #[serde(skip)]
+    history: Option<Vec<HistoryEntry>>,
+    #[serde(skip)]
+    dispute_policy: DisputePolicy,
+    #[serde(skip)]
+    overdraft_limit: Option<BigDecimal>,
+    /// Number of transactions received for this client, including rejected ones.
+    #[serde(skip)]
+    transactions_received: usize,
+    #[serde(skip)]
+    max_transactions: Option<usize>,
+    #[serde(skip)]
+    decimal_context: Option<DecimalContext>,
+    #[serde(skip)]
+    forbid_zero_tx: bool,
+    #[serde(skip)]
+    lock_freezes_deposits: bool,
+    #[serde(skip)]
+    allow_withdrawal_disputes: bool,
+    /// One state per scenario in `ProcessingConfig::scenarios`, in the same order.
+    #[serde(skip)]
+    scenarios: Vec<ClientState>,
+}
 
-    tx_for_transaction_state: HashMap<TxId, (TransactionType, BigDecimal)>,
+/// A transaction that was applied to a `ClientState` with enough information to undo it.
+#[derive(Debug, Clone)]
+struct HistoryEntry {
+    transaction_type: TransactionType,
+    tx: TxId,
+    amount: BigDecimal,
+    /// Value of `locked` before the transaction was applied.
+    was_locked: bool,
+    /// Whether `tx` was in `disputed_withdrawals` before the transaction was applied.
+    was_disputed_withdrawal: bool,
 }
 
 impl PartialEq for ClientState {
@@ -30,6 +395,19 @@ impl PartialEq for ClientState {
             && self.available == other.available
             && self.held == other.held
             && self.locked == other.locked
+            && self.closed == other.closed
+    }
+}
+
+/// Error of a transaction that could not be applied, along with why it was rejected.
+type Rejected = (RejectionCategory, Error);
+
+/// Returns the line printed to stderr for a rejected transaction, prefixed by the line of the
+/// input it was read from when known so it can be found in large inputs.
+pub(crate) fn rejection_log_line(row_number: Option<usize>, err: &Error) -> String {
+    match row_number {
+        Some(row_number) => format!("line {}: {}", row_number, err.messages.join(" : ")),
+        None => err.to_string(),
     }
 }
 
@@ -37,132 +415,438 @@ impl PartialEq for ClientState {
 /// returns a mutable reference of the transaction state  and the amount of the transaction.
 /// We reuse TransactionType here to also represent the state of the transaction (eg: if it's being
 /// disputed it will be in state `TransactionType::Dispute`).
+///
+/// The states and the transitions between them are:
+///
+/// ```text
+/// Deposit    --dispute-->    Dispute    --resolve-->    Deposit
+/// Withdrawal --dispute-->    Dispute    --resolve-->    Withdrawal  (allow_withdrawal_disputes)
+///                            Dispute    --chargeback--> Chargeback  (final)
+/// ```
+///
+/// Every transition out of `Dispute` takes the amount out of `held`, and the only way into
+/// `Dispute` puts it in. So as long as a resolve or chargeback is only applied to a tx in
+/// `Dispute`, `held` is the sum of the amounts of the disputed txs and can never go negative:
+/// a resolve or chargeback sent twice finds the tx out of `Dispute` and is rejected here
+/// before touching any balance. Txs are per client, so clients sharing a worker cannot
+/// affect each other's `held`.
 // Note: This was not placed into `ClientState` because it returns a mutable reference and if it
 // was a part of `ClientState` the mutable reference would be to `self`, resulting in being unable
 // to mutate anything else on `ClientState` as long as the result of this function lived.
 fn get_tx_state_and_check_state<'a>(
-    tx_for_transaction_state: &'a mut HashMap<TxId, (TransactionType, BigDecimal)>,
+    tx_for_transaction_state: &'a mut IdMap<TxId, (TransactionType, BigDecimal)>,
     transaction: &Transaction,
     allowed_tx_state: &TransactionType,
-) -> Result<(&'a mut TransactionType, &'a BigDecimal), Error> {
+) -> Result<(&'a mut TransactionType, &'a BigDecimal), Rejected> {
     let (tx_state, amount) = tx_for_transaction_state
         .get_mut(&transaction.tx)
         .ok_or_else(|| {
-            make_other_err!("Tx ({}) does not exist : {:?}", transaction.tx, transaction)
+            (
+                RejectionCategory::UnknownTx,
+                make_other_err!("Tx ({}) does not exist : {:?}", transaction.tx, transaction),
+            )
         })?;
     if tx_state == allowed_tx_state {
         return Ok((tx_state, amount));
     }
-    match tx_state {
-        TransactionType::Withdrawal => Err(make_other_err!(
-            "Cannot dispute a withdrawal : {:?}",
+    let err = match tx_state {
+        // Withdrawals only enter the `Dispute` state with `allow_withdrawal_disputes`, so
+        // resolves and chargebacks that reference an undisputed one get the same treatment as
+        // a dispute would without it.
+        TransactionType::Withdrawal => make_other_err!(
+            "Cannot {} a withdrawal : {:?}",
+            transaction.transaction_type.as_str(),
             transaction
-        )),
-        TransactionType::Dispute => Err(make_other_err!(
+        ),
+        TransactionType::Dispute => make_other_err!(
             "Tx ({}) is already being disputed : {:?}",
             transaction.tx,
             transaction
-        )),
-        TransactionType::Chargeback => Err(make_other_err!(
+        ),
+        TransactionType::Chargeback => make_other_err!(
             "Tx ({}) has already been chargebacked : {:?}",
             transaction.tx,
             transaction
-        )),
-        TransactionType::Resolve => {
-            unreachable!("Resolve should never be set in tx_for_transaction_state")
+        ),
+        TransactionType::Resolve | TransactionType::CloseAccount => {
+            unreachable!("Resolve and CloseAccount should never be set in tx_for_transaction_state")
         }
-        TransactionType::Deposit => Err(make_other_err!(
+        TransactionType::Deposit => make_other_err!(
             "Tx ({}) is not under dispute : {:?}",
             transaction.tx,
             transaction
-        )),
-    }
+        ),
+    };
+    Err((RejectionCategory::InvalidTxState, err))
 }
 
 impl ClientState {
-    #[cfg(test)]
-    pub(crate) fn new(
-        client: ClientId,
-        available: BigDecimal,
-        held: BigDecimal,
-        locked: bool,
-    ) -> Self {
+    /// Creates the state of a client with the given balances, eg: to seed an `AccountManager`
+    /// with `with_initial_states` or to build expected states.
+    ///
+    /// The transaction history starts empty, so disputes, resolves and chargebacks of any
+    /// transaction made before this state was created are rejected as unknown. States
+    /// deserialized from a `--dump-final-state` snapshot keep their history instead.
+    pub fn new(client: ClientId, available: BigDecimal, held: BigDecimal, locked: bool) -> Self {
         Self {
             client,
+            peak_available: available.clone(),
             available,
             held,
             locked,
-            tx_for_transaction_state: Default::default(),
+            ..Default::default()
         }
     }
 
-    fn deposit(&mut self, transaction: Transaction) -> Result<(), Error> {
-        assert!(transaction.transaction_type == TransactionType::Deposit);
-        // Not sure if we should prevent deposits if the account is locked?
-        // I assume it's ok if a client deposits funds if their account is locked.
-        if self.tx_for_transaction_state.contains_key(&transaction.tx) {
-            return Err(make_other_err!(
-                "Transaction ({}) already processed : {:?}",
+    /// Applies the per-client settings of `config` to this client.
+    pub(crate) fn configure(&mut self, config: &ProcessingConfig) {
+        self.history = config.rewind.map(|_| Vec::new());
+        self.overdraft_limit = config.overdraft_limit.clone();
+        self.max_transactions = config.max_tx_per_client;
+        self.decimal_context = config.decimal_context;
+        self.forbid_zero_tx = config.forbid_zero_tx;
+        self.lock_freezes_deposits = config.lock_freezes_deposits;
+        self.allow_withdrawal_disputes = config.allow_withdrawal_disputes;
+    }
+
+    /// Returns true if at least one deposit or withdrawal was accepted for this client.
+    pub fn has_transactions(&self) -> bool {
+        !self.tx_for_transaction_state.is_empty()
+    }
+
+    /// Returns the lowest `available` balance this client may have, or `None` if it may go
+    /// arbitrarily negative (ie: disputes are allowed to take it below zero).
+    pub fn min_available(&self) -> Option<BigDecimal> {
+        match (self.dispute_policy, &self.overdraft_limit) {
+            (DisputePolicy::AllowNegative, _) => None,
+            (DisputePolicy::Strict, Some(overdraft_limit)) => Some(-overdraft_limit.clone()),
+            (DisputePolicy::Strict, None) => Some(BigDecimal::default()),
+        }
+    }
+
+    /// Returns the tx id and amount of every transaction currently under dispute.
+    pub fn open_disputes(&self) -> impl Iterator<Item = (TxId, &BigDecimal)> {
+        self.tx_for_transaction_state
+            .iter()
+            .filter(|(_, (tx_state, _))| tx_state == &TransactionType::Dispute)
+            .map(|(tx, (_, amount))| (*tx, amount))
+    }
+
+    /// Returns the state of this client under each scenario requested in the
+    /// `ProcessingConfig`, in the same order.
+    pub fn scenarios(&self) -> &[ClientState] {
+        &self.scenarios
+    }
+
+    /// Returns the proportion of the account's funds that are held (`held / total`), or
+    /// `None` if the account has no funds.
+    pub fn held_ratio(&self) -> Option<BigDecimal> {
+        let total = &self.available + &self.held;
+        if total.is_zero() {
+            return None;
+        }
+        Some(&self.held / total)
+    }
+
+    /// Applies the given transaction to this client. If it is rejected, the error tells why
+    /// with `Error::rejection_category`.
+    pub fn process(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.process_categorized(transaction)
+            .map_err(|(category, err)| err.with_rejection_category(category))
+    }
+
+    /// Same as `process`, but on failure also returns why the transaction was rejected.
+    fn process_categorized(&mut self, mut transaction: Transaction) -> Result<(), Rejected> {
+        self.transactions_received += 1;
+        if let Some(max_transactions) = self.max_transactions {
+            if self.transactions_received > max_transactions {
+                return Err((
+                    RejectionCategory::TransactionLimitExceeded,
+                    make_other_err!(
+                        "Client ({}) transaction limit exceeded ({} max). Transaction not processed : {:?}",
+                        transaction.client,
+                        max_transactions,
+                        transaction
+                    ),
+                ));
+            }
+        }
+        if self.forbid_zero_tx && transaction.tx == 0 {
+            return Err((
+                RejectionCategory::ZeroTx,
+                make_other_err!(
+                    "Tx id 0 is reserved. Transaction not processed : {:?}",
+                    transaction
+                ),
+            ));
+        }
+        if self.closed {
+            return Err((
+                RejectionCategory::AccountClosed,
+                make_other_err!(
+                    "Account ({}) is closed. Transaction not processed : {:?}",
+                    transaction.client,
+                    transaction
+                ),
+            ));
+        }
+        if let Some(decimal_context) = &self.decimal_context {
+            transaction.amount = transaction
+                .amount
+                .map(|amount| decimal_context.apply(&amount));
+        }
+        let (transaction_type, tx) = (transaction.transaction_type, transaction.tx);
+        let was_locked = self.locked;
+        let was_disputed_withdrawal = self.disputed_withdrawals.contains(&tx);
+        match transaction.transaction_type {
+            TransactionType::Deposit => self.deposit(transaction),
+            TransactionType::Withdrawal => self.withdrawal(transaction),
+            TransactionType::Dispute => self.dispute(transaction),
+            TransactionType::Resolve => self.resolve(transaction),
+            TransactionType::Chargeback => self.chargeback(transaction),
+            TransactionType::CloseAccount => self.close_account(transaction),
+        }?;
+        if let Some(history) = &mut self.history {
+            let amount = self
+                .tx_for_transaction_state
+                .get(&tx)
+                .map(|(_, amount)| amount.clone())
+                .unwrap_or_default();
+            history.push(HistoryEntry {
+                transaction_type,
+                tx,
+                amount,
+                was_locked,
+                was_disputed_withdrawal,
+            });
+        }
+        if let Some(decimal_context) = &self.decimal_context {
+            // Amounts are already rounded, this only matters for balances loaded from a
+            // snapshot that were stored without rounding.
+            self.available = decimal_context.apply(&self.available);
+            self.held = decimal_context.apply(&self.held);
+        }
+        Ok(())
+    }
+
+    /// Undoes up to `count` of the most recently applied transactions (in reverse order),
+    /// reconstructing the state the account was in before them. Only possible if the
+    /// history was being tracked, otherwise nothing happens.
+    pub(crate) fn rewind(&mut self, count: usize) {
+        if let Some(mut history) = self.history.take() {
+            let keep = history.len().saturating_sub(count);
+            for entry in history.drain(keep..).rev() {
+                self.undo(entry);
+            }
+            self.history = Some(history);
+        }
+    }
+
+    fn undo(&mut self, entry: HistoryEntry) {
+        let amount = &entry.amount;
+        match entry.transaction_type {
+            TransactionType::Deposit => {
+                self.tx_for_transaction_state.remove(&entry.tx);
+                self.available -= amount;
+                self.deposited -= amount;
+            }
+            TransactionType::Withdrawal => {
+                self.tx_for_transaction_state.remove(&entry.tx);
+                self.available += amount;
+                self.withdrawn -= amount;
+            }
+            TransactionType::Dispute if self.disputed_withdrawals.contains(&entry.tx) => {
+                self.set_tx_state(entry.tx, TransactionType::Withdrawal);
+                self.held -= amount;
+                self.withdrawn += amount;
+            }
+            TransactionType::Dispute => {
+                self.set_tx_state(entry.tx, TransactionType::Deposit);
+                self.held -= amount;
+                self.available += amount;
+            }
+            TransactionType::Resolve if self.disputed_withdrawals.contains(&entry.tx) => {
+                self.set_tx_state(entry.tx, TransactionType::Dispute);
+                self.held += amount;
+                self.withdrawn -= amount;
+            }
+            TransactionType::Resolve => {
+                self.set_tx_state(entry.tx, TransactionType::Dispute);
+                self.available -= amount;
+                self.held += amount;
+            }
+            TransactionType::Chargeback if self.disputed_withdrawals.contains(&entry.tx) => {
+                self.set_tx_state(entry.tx, TransactionType::Dispute);
+                self.available -= amount;
+                self.held += amount;
+            }
+            TransactionType::Chargeback => {
+                self.set_tx_state(entry.tx, TransactionType::Dispute);
+                self.held += amount;
+                self.charged_back -= amount;
+            }
+            TransactionType::CloseAccount => self.closed = false,
+        }
+        self.locked = entry.was_locked;
+        if !entry.was_disputed_withdrawal {
+            self.disputed_withdrawals.remove(&entry.tx);
+        }
+    }
+
+    /// Returns the type of the transaction (deposit or withdrawal) that owns `tx`, which is
+    /// in `tx_state`.
+    fn tx_owner(&self, tx: TxId, tx_state: TransactionType) -> TransactionType {
+        match tx_state {
+            TransactionType::Withdrawal => TransactionType::Withdrawal,
+            _ if self.disputed_withdrawals.contains(&tx) => TransactionType::Withdrawal,
+            _ => TransactionType::Deposit,
+        }
+    }
+
+    fn set_tx_state(&mut self, tx: TxId, tx_state: TransactionType) {
+        if let Some((state, _)) = self.tx_for_transaction_state.get_mut(&tx) {
+            *state = tx_state;
+        }
+    }
+
+    /// Deposits and withdrawals share the same tx ids, so whichever of them uses a tx id
+    /// first owns it and any later deposit or withdrawal with that id is rejected as a
+    /// duplicate. Disputes, resolves and chargebacks always reference the owner, which means
+    /// a dispute of a tx id first used by a withdrawal is rejected even if a deposit with the
+    /// same id came later. Tx ids are only unique per client.
+    fn check_tx_unused(&self, transaction: &Transaction) -> Result<(), Rejected> {
+        let (tx_state, amount) = match self.tx_for_transaction_state.get(&transaction.tx) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let owner = self.tx_owner(transaction.tx, *tx_state);
+        let message = if transaction.transaction_type != TransactionType::Deposit {
+            format!(
+                "Transaction ({}) already processed as a {}",
                 transaction.tx,
-                transaction
+                owner.as_str()
+            )
+        } else if self.deposit_reuse(transaction) == Some(TxReuse::Redelivery) {
+            format!(
+                "Transaction ({}) already processed, redelivery of the same deposit",
+                transaction.tx
+            )
+        } else {
+            format!(
+                "Transaction ({}) collides with an earlier {} of {}",
+                transaction.tx,
+                owner.as_str(),
+                amount
+            )
+        };
+        Err((
+            RejectionCategory::DuplicateTx,
+            make_other_err!("{} : {:?}", message, transaction),
+        ))
+    }
+
+    /// Returns how a deposit reuses the tx id of an earlier deposit or withdrawal, or `None`
+    /// if it is not a deposit or its tx id is unused.
+    pub(crate) fn deposit_reuse(&self, transaction: &Transaction) -> Option<TxReuse> {
+        if transaction.transaction_type != TransactionType::Deposit {
+            return None;
+        }
+        let (tx_state, stored_amount) = self.tx_for_transaction_state.get(&transaction.tx)?;
+        // Stored amounts are rounded with `decimal_context`, so compare the rounded amount.
+        let amount = match (&self.decimal_context, &transaction.amount) {
+            (Some(decimal_context), Some(amount)) => Some(decimal_context.apply(amount)),
+            (_, amount) => amount.clone(),
+        };
+        if self.tx_owner(transaction.tx, *tx_state) == TransactionType::Deposit
+            && amount.as_ref() == Some(stored_amount)
+        {
+            Some(TxReuse::Redelivery)
+        } else {
+            Some(TxReuse::Collision)
+        }
+    }
+
+    fn deposit(&mut self, transaction: Transaction) -> Result<(), Rejected> {
+        assert!(transaction.transaction_type == TransactionType::Deposit);
+        // Deposits to locked accounts are allowed unless `lock_freezes_deposits` is set.
+        if self.locked && self.lock_freezes_deposits {
+            return Err((
+                RejectionCategory::AccountLocked,
+                make_other_err!(
+                    "Account ({}) is locked. Transaction not processed : {:?}",
+                    transaction.client,
+                    transaction
+                ),
             ));
         }
-        let amount = transaction
-            .amount
-            .ok_or_else(|| make_other_err!("Amount must be provided in Deposit"))?;
+        self.check_tx_unused(&transaction)?;
+        let amount = positive_amount(&transaction)?;
         self.available += &amount;
-        // TODO(allada) I am unsure if it is common to have zero amounts here, if it is zero
-        // we could avoid creating this transaction record, for now I'll assume it's not
-        // common.
+        self.deposited += &amount;
+        self.raise_peak_available();
         self.tx_for_transaction_state
             .insert(transaction.tx, (TransactionType::Deposit, amount));
         Ok(())
     }
 
-    fn withdrawal(&mut self, transaction: Transaction) -> Result<(), Error> {
+    fn withdrawal(&mut self, transaction: Transaction) -> Result<(), Rejected> {
         assert!(transaction.transaction_type == TransactionType::Withdrawal);
         if self.locked {
-            return Err(make_other_err!(
-                "Account ({}) is locked. Transaction not processed : {:?}",
-                transaction.client,
-                transaction
-            ));
-        }
-        if self.tx_for_transaction_state.contains_key(&transaction.tx) {
-            return Err(make_other_err!(
-                "Transaction ({}) already processed : {:?}",
-                transaction.tx,
-                transaction
+            return Err((
+                RejectionCategory::AccountLocked,
+                make_other_err!(
+                    "Account ({}) is locked. Transaction not processed : {:?}",
+                    transaction.client,
+                    transaction
+                ),
             ));
         }
-        let amount = transaction
-            .amount
-            .ok_or_else(|| make_other_err!("Amount must be provided in Deposit"))?;
-        if self.available <= amount {
-            return Err(make_other_err!(
-                "Account did not have enough available ({}) funds in Transaction",
-                self.available
+        self.check_tx_unused(&transaction)?;
+        let amount = positive_amount(&transaction)?;
+        let insufficient_funds = match &self.overdraft_limit {
+            Some(overdraft_limit) => &self.available + overdraft_limit < amount,
+            None => self.available < amount,
+        };
+        if insufficient_funds {
+            return Err((
+                RejectionCategory::InsufficientFunds,
+                make_other_err!(
+                    "Account did not have enough available ({}) funds in Transaction",
+                    self.available
+                ),
             ));
         }
         self.available -= &amount;
+        self.withdrawn += &amount;
         self.tx_for_transaction_state
             .insert(transaction.tx, (TransactionType::Withdrawal, amount));
         Ok(())
     }
 
-    fn dispute(&mut self, transaction: Transaction) -> Result<(), Error> {
+    fn dispute(&mut self, transaction: Transaction) -> Result<(), Rejected> {
         assert!(transaction.transaction_type == TransactionType::Dispute);
+        if self.allow_withdrawal_disputes
+            && matches!(
+                self.tx_for_transaction_state.get(&transaction.tx),
+                Some((TransactionType::Withdrawal, _))
+            )
+        {
+            return self.dispute_withdrawal(transaction);
+        }
         let (tx_state, amount) = get_tx_state_and_check_state(
             &mut self.tx_for_transaction_state,
             &transaction,
             &TransactionType::Deposit,
         )?;
 
-        if &self.available < amount {
-            return Err(make_other_err!(
-                "Account did not have enough available ({}) funds in Transaction : {:?}",
-                self.available,
-                transaction
+        if self.dispute_policy == DisputePolicy::Strict && &self.available < amount {
+            return Err((
+                RejectionCategory::InsufficientFunds,
+                make_other_err!(
+                    "Account did not have enough available ({}) funds in Transaction : {:?}",
+                    self.available,
+                    transaction
+                ),
             ));
         }
         *tx_state = TransactionType::Dispute;
@@ -171,8 +855,27 @@ impl ClientState {
         Ok(())
     }
 
-    fn resolve(&mut self, transaction: Transaction) -> Result<(), Error> {
+    /// Disputes a withdrawal the client says it did not make. Until the dispute is settled
+    /// the withdrawn funds are credited back to `held` (`available` is unchanged) and no
+    /// longer count as `withdrawn`. A resolve means the withdrawal stands: the funds leave
+    /// `held` and count as `withdrawn` again. A chargeback reverses the withdrawal: the funds
+    /// move from `held` to `available` and the account is locked, like for deposits.
+    fn dispute_withdrawal(&mut self, transaction: Transaction) -> Result<(), Rejected> {
+        let (tx_state, amount) = get_tx_state_and_check_state(
+            &mut self.tx_for_transaction_state,
+            &transaction,
+            &TransactionType::Withdrawal,
+        )?;
+        *tx_state = TransactionType::Dispute;
+        self.held += amount;
+        self.withdrawn -= amount;
+        self.disputed_withdrawals.insert(transaction.tx);
+        Ok(())
+    }
+
+    fn resolve(&mut self, transaction: Transaction) -> Result<(), Rejected> {
         assert!(transaction.transaction_type == TransactionType::Resolve);
+        let is_withdrawal = self.disputed_withdrawals.contains(&transaction.tx);
         let (tx_state, amount) = get_tx_state_and_check_state(
             &mut self.tx_for_transaction_state,
             &transaction,
@@ -180,79 +883,332 @@ impl ClientState {
         )?;
 
         if &self.held < amount {
-            return Err(make_other_err!(
-                "Account did not have enough held ({}) funds in Transaction : {:?}",
-                self.available,
-                transaction
+            return Err((
+                RejectionCategory::InsufficientFunds,
+                make_other_err!(
+                    "Account did not have enough held ({}) funds in Transaction : {:?}",
+                    self.held,
+                    transaction
+                ),
             ));
         }
+        if is_withdrawal {
+            *tx_state = TransactionType::Withdrawal;
+            self.held -= amount;
+            self.withdrawn += amount;
+            debug_assert!(!self.held.is_negative(), "held went negative: {:?}", self);
+            return Ok(());
+        }
         *tx_state = TransactionType::Deposit;
         self.held -= amount;
         self.available += amount;
+        self.raise_peak_available();
+        debug_assert!(!self.held.is_negative(), "held went negative: {:?}", self);
         Ok(())
     }
 
-    fn chargeback(&mut self, transaction: Transaction) -> Result<(), Error> {
+    fn raise_peak_available(&mut self) {
+        if self.available > self.peak_available {
+            self.peak_available = self.available.clone();
+        }
+    }
+
+    fn chargeback(&mut self, transaction: Transaction) -> Result<(), Rejected> {
         assert!(transaction.transaction_type == TransactionType::Chargeback);
+        let is_withdrawal = self.disputed_withdrawals.contains(&transaction.tx);
         let (tx_state, amount) = get_tx_state_and_check_state(
             &mut self.tx_for_transaction_state,
             &transaction,
             &TransactionType::Dispute,
         )?;
         if &self.held < amount {
-            return Err(make_other_err!(
-                "Account did not have enough held ({}) funds in Transaction : {:?}",
-                self.available,
-                transaction
+            return Err((
+                RejectionCategory::InsufficientFunds,
+                make_other_err!(
+                    "Account did not have enough held ({}) funds in Transaction : {:?}",
+                    self.held,
+                    transaction
+                ),
             ));
         }
+        // Chargebacks are applied even if an earlier chargeback already locked the account, so
+        // every disputed amount is clawed back. The account simply stays locked.
         *tx_state = TransactionType::Chargeback;
         self.held -= amount;
+        if is_withdrawal {
+            self.available += amount;
+            self.raise_peak_available();
+        } else {
+            self.charged_back += amount;
+        }
         self.locked = true;
+        debug_assert!(!self.held.is_negative(), "held went negative: {:?}", self);
+        Ok(())
+    }
+
+    fn close_account(&mut self, transaction: Transaction) -> Result<(), Rejected> {
+        assert!(transaction.transaction_type == TransactionType::CloseAccount);
+        if !self.available.is_zero() || !self.held.is_zero() {
+            return Err((
+                RejectionCategory::CannotClose,
+                make_other_err!(
+                    "Account ({}) still has funds (available {}, held {}) and cannot be closed : {:?}",
+                    transaction.client,
+                    self.available,
+                    self.held,
+                    transaction
+                ),
+            ));
+        }
+        let has_open_dispute = self
+            .tx_for_transaction_state
+            .values()
+            .any(|(tx_state, _)| tx_state == &TransactionType::Dispute);
+        if has_open_dispute {
+            return Err((
+                RejectionCategory::CannotClose,
+                make_other_err!(
+                    "Account ({}) has open disputes and cannot be closed : {:?}",
+                    transaction.client,
+                    transaction
+                ),
+            ));
+        }
+        self.closed = true;
         Ok(())
     }
 }
 
-/// This is designed to be run in a `tokio::spawn` and will constantly pull the rx stream
-/// and process the given transaction. When the stream is closed it will collect all the
-/// final `ClientState`s into a single vector.
-async fn process_account_transactions(
-    mut rx: mpsc::Receiver<Transaction>,
-) -> Result<Vec<ClientState>, Error> {
+/// State owned by a single worker spawn.
+struct Worker {
+    config: ProcessingConfig,
     // TODO(allada) We should use a database here instead of storing it all in memory.
-    let mut state_for_client = HashMap::<ClientId, ClientState>::new();
-    while let Some(transaction) = rx.recv().await {
-        let state = match state_for_client.get_mut(&transaction.client) {
-            Some(state) => state,
-            None => {
-                state_for_client.insert(
-                    transaction.client,
-                    ClientState {
+    state_for_client: IdMap<ClientId, ClientState>,
+    balance_mismatches: Vec<BalanceMismatch>,
+    rejections: Vec<Rejection>,
+    /// Clients that were referenced by a dispute, resolve or chargeback before they were seen.
+    dangling_clients: IdSet<ClientId>,
+    stats: WorkerStats,
+    /// Set once a transaction must fail the whole run. Every later transaction is skipped.
+    fatal_error: Option<Error>,
+}
+
+impl Worker {
+    fn new(config: ProcessingConfig, initial_states: Vec<ClientState>) -> Self {
+        let state_for_client = initial_states
+            .into_iter()
+            .map(|mut state| {
+                state.configure(&config);
+                state.dispute_policy = config.dispute_policy;
+                (state.client, state)
+            })
+            .collect();
+        Self {
+            config,
+            state_for_client,
+            balance_mismatches: Vec::new(),
+            rejections: Vec::new(),
+            dangling_clients: IdSet::default(),
+            stats: WorkerStats::default(),
+            fatal_error: None,
+        }
+    }
+
+    fn process_message(&mut self, message: TransactionMessage) {
+        if self.fatal_error.is_some() {
+            // The run fails anyway, the channel is only drained so senders are not blocked.
+            return;
+        }
+        let TransactionMessage {
+            row_number,
+            mut transaction,
+        } = message;
+        let start_time = Instant::now();
+        self.stats.transactions_processed += 1;
+        let (client, tx) = (transaction.client, transaction.tx);
+        let expected_balance = transaction.expected_balance.take();
+        let mut unknown_client_state;
+        // A single lookup both for known clients and for the first transaction of a client.
+        let state = match self.state_for_client.entry(client) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let new_state = |dispute_policy| {
+                    let mut state = ClientState {
                         client: transaction.client,
+                        dispute_policy,
                         ..Default::default()
-                    },
-                );
-                state_for_client.get_mut(&transaction.client).unwrap()
+                    };
+                    state.configure(&self.config);
+                    state
+                };
+                let mut state = new_state(self.config.dispute_policy);
+                state.scenarios = self
+                    .config
+                    .scenarios
+                    .iter()
+                    .copied()
+                    .map(new_state)
+                    .collect();
+                if transaction.transaction_type.references_tx() {
+                    // The referenced tx cannot exist for a client that was never seen. The
+                    // transaction is rejected by a throwaway state, so the client does not end
+                    // up in the output with a zero balance.
+                    self.dangling_clients.insert(client);
+                    unknown_client_state = state;
+                    &mut unknown_client_state
+                } else {
+                    entry.insert(state)
+                }
             }
         };
 
-        let result = match transaction.transaction_type {
-            TransactionType::Deposit => state.deposit(transaction),
-            TransactionType::Withdrawal => state.withdrawal(transaction),
-            TransactionType::Dispute => state.dispute(transaction),
-            TransactionType::Resolve => state.resolve(transaction),
-            TransactionType::Chargeback => state.chargeback(transaction),
-        };
-        if let Err(err) = result {
-            eprintln!("{}", err.to_string());
+        match (
+            self.config.deposit_collision_policy,
+            state.deposit_reuse(&transaction),
+        ) {
+            (DepositCollisionPolicy::Reject, _) | (_, None) => {}
+            (_, Some(TxReuse::Redelivery)) => {
+                self.stats.redeliveries_skipped += 1;
+                self.stats.busy_time += start_time.elapsed();
+                return;
+            }
+            (DepositCollisionPolicy::Error, Some(TxReuse::Collision)) => {
+                let amount = transaction
+                    .amount
+                    .as_ref()
+                    .map_or_else(|| "no amount".to_string(), ToString::to_string);
+                self.fatal_error = Some(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Deposit (tx {}) of {} for client {} collides with an earlier transaction with the same tx id",
+                        tx, amount, client
+                    ),
+                ));
+                return;
+            }
+            (DepositCollisionPolicy::Ignore, Some(TxReuse::Collision)) => {}
+        }
+
+        // Rejections are only reported for the main state, scenarios are expected to differ.
+        for scenario in &mut state.scenarios {
+            let _ = scenario.process_categorized(transaction.clone());
+        }
+
+        // The transaction is consumed when processed, so keep a copy in case it is rejected.
+        let rejection_transaction = self.config.collect_rejections.then(|| transaction.clone());
+        if let Err((category, mut err)) = state.process_categorized(transaction) {
+            if self.config.fail_on_rejection {
+                err = err.with_rejection_category(category);
+                err.messages.insert(
+                    0,
+                    format!(
+                        "Transaction {} of client {} on row {} was rejected",
+                        tx,
+                        client,
+                        row_number.map_or_else(|| "?".to_string(), |row| row.to_string())
+                    ),
+                );
+                err.kind = ErrorKind::InvalidData;
+                self.fatal_error = Some(err);
+                return;
+            }
+            if !self.config.quiet_rejections {
+                eprintln!("{}", rejection_log_line(row_number, &err));
+            }
+            if let Some(transaction) = rejection_transaction {
+                self.rejections.push(Rejection {
+                    row_number,
+                    category,
+                    message: err.messages.join(" : "),
+                    transaction: Some(transaction),
+                });
+            }
+        }
+        if let (Some(tolerance), Some(expected)) =
+            (&self.config.running_balance_tolerance, expected_balance)
+        {
+            if (&state.available - &expected).abs() > *tolerance {
+                self.balance_mismatches.push(BalanceMismatch {
+                    row_number,
+                    client,
+                    tx,
+                    expected,
+                    actual: state.available.clone(),
+                });
+            }
+        }
+        self.stats.busy_time += start_time.elapsed();
+    }
+
+    fn snapshot(&self, rounding: OutputRounding) -> Vec<AccountBalance> {
+        self.state_for_client
+            .values()
+            .map(|state| AccountBalance::with_rounding(state, rounding))
+            .collect()
+    }
+
+    fn into_results(mut self) -> WorkerResults {
+        if let Some(count) = self.config.rewind {
+            for state in self.state_for_client.values_mut() {
+                state.rewind(count);
+                for scenario in &mut state.scenarios {
+                    scenario.rewind(count);
+                }
+            }
+        }
+        self.stats.clients = self.state_for_client.len();
+        // Clients that were seen later (eg: their deposit came after the dispute) are not
+        // dangling.
+        let state_for_client = &self.state_for_client;
+        let dangling_clients = self
+            .dangling_clients
+            .into_iter()
+            .filter(|client| !state_for_client.contains_key(client))
+            .collect();
+        WorkerResults {
+            client_states: self.state_for_client.into_values().collect(),
+            balance_mismatches: self.balance_mismatches,
+            rejections: self.rejections,
+            dangling_clients,
+            stats: self.stats,
         }
     }
-    Ok(state_for_client.into_values().collect())
+}
+
+/// This is designed to be run in a `tokio::spawn` and will constantly pull the rx stream
+/// and process the given transactions. When the stream is closed it will collect all the
+/// final `ClientState`s into a single vector.
+async fn process_account_transactions(
+    mut rx: mpsc::Receiver<WorkerMessage>,
+    config: ProcessingConfig,
+    initial_states: Vec<ClientState>,
+) -> Result<WorkerResults, Error> {
+    let mut worker = Worker::new(config, initial_states);
+    while let Some(message) = rx.recv().await {
+        match message {
+            WorkerMessage::Transactions(batch) => {
+                for message in batch {
+                    worker.process_message(message);
+                }
+            }
+            // The requester going away (eg: it was cancelled) is not an error for the worker.
+            WorkerMessage::Snapshot { rounding, reply } => {
+                drop(reply.send(worker.snapshot(rounding)))
+            }
+            WorkerMessage::ClientState { client, reply } => {
+                drop(reply.send(worker.state_for_client.get(&client).cloned()))
+            }
+        }
+    }
+    if let Some(err) = worker.fatal_error.take() {
+        return Err(err);
+    }
+    Ok(worker.into_results())
 }
 
 type WorkerHandle = (
-    mpsc::Sender<Transaction>,
-    JoinHandle<Result<Vec<ClientState>, Error>>,
+    mpsc::Sender<WorkerMessage>,
+    JoinHandle<Result<WorkerResults, Error>>,
 );
 
 /// AccountManager manages the state of each account and gives APIs into sending transactions
@@ -265,14 +1221,14 @@ type WorkerHandle = (
 /// in order per account (not globally), we would be spreading the database latency over N number
 /// of workers, in theory giving us much higher throughput.
 ///
-/// In the event we might ever want to retrieve the current state of the client, an API could be
-/// exposed in this class which would ask the specific worker for the current account state.
-/// Although, this would be quite complicated to implement.
+/// The current balances can be retrieved while processing with `snapshot_balances`, which asks
+/// every worker for the state of its clients, or `get_client_state` for a single client.
 ///
 /// Processing of transactions does not require any locks, thus many immutable references to this
 /// struct can be used if many connected clients needed to stream transactions.
 pub struct AccountManager {
     workers: Vec<WorkerHandle>,
+    route_by: RouteBy,
 }
 
 impl AccountManager {
@@ -280,37 +1236,283 @@ impl AccountManager {
     ///
     /// `num_workers` represents the number of workers spawned in the background to do the
     /// processing.
+    ///
+    /// Panics if `num_workers` is out of range, see `try_new`.
     pub fn new(num_workers: usize) -> Self {
-        assert!(
-            num_workers > 0,
-            "`num_workers` must be at least 1 in AccountManager"
-        );
-        assert!(
-            num_workers < u16::MAX.into(),
-            "`num_workers` must be less than u16::MAX in AccountManager"
-        );
+        Self::with_config(num_workers, ProcessingConfig::default())
+    }
+
+    /// Same as `new`, but fails with `ErrorKind::InvalidInput` instead of panicking if
+    /// `num_workers` is 0 or not less than `u16::MAX`.
+    pub fn try_new(num_workers: usize) -> Result<Self, Error> {
+        Self::try_with_initial_states(num_workers, ProcessingConfig::default(), Vec::new())
+    }
+
+    /// Construct a new AccountManager where every worker uses the given `config`.
+    pub fn with_config(num_workers: usize, config: ProcessingConfig) -> Self {
+        Self::with_initial_states(num_workers, config, Vec::new())
+    }
+
+    /// Construct a new AccountManager where the clients start in the given states (eg: loaded
+    /// from a snapshot of a previous run) instead of empty accounts. Transactions for these
+    /// clients are applied on top of their state, so a tx id already known by the state is
+    /// rejected as a duplicate instead of being counted twice.
+    ///
+    /// Every client must appear at most once in `initial_states` and scenarios are not
+    /// supported for them.
+    ///
+    /// Panics if `num_workers` is out of range, see `try_with_initial_states`.
+    pub fn with_initial_states(
+        num_workers: usize,
+        config: ProcessingConfig,
+        initial_states: Vec<ClientState>,
+    ) -> Self {
+        Self::try_with_initial_states(num_workers, config, initial_states)
+            .unwrap_or_else(|err| panic!("{}", err.messages.join(" : ")))
+    }
+
+    /// Same as `with_initial_states`, but fails with `ErrorKind::InvalidInput` instead of
+    /// panicking if `num_workers` is 0 or not less than `u16::MAX`.
+    pub fn try_with_initial_states(
+        num_workers: usize,
+        config: ProcessingConfig,
+        initial_states: Vec<ClientState>,
+    ) -> Result<Self, Error> {
+        if num_workers == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`num_workers` must be at least 1 in AccountManager",
+            ));
+        }
+        if config.channel_buffer_size == Some(0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "`channel_buffer_size` must be at least 1 in AccountManager",
+            ));
+        }
+        if num_workers >= u16::MAX.into() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "`num_workers` must be less than u16::MAX in AccountManager, got {}",
+                    num_workers
+                ),
+            ));
+        }
+        let route_by = config.route_by;
+        let channel_buffer_size = config.channel_buffer_size.unwrap_or(CHANNEL_BUFFER_SIZE);
+        let mut states_for_worker: Vec<Vec<ClientState>> =
+            (0..num_workers).map(|_| Vec::new()).collect();
+        for state in initial_states {
+            states_for_worker[route_by.worker_index(state.client, num_workers)].push(state);
+        }
         let mut workers = Vec::with_capacity(num_workers);
-        for _ in 0..num_workers {
-            let (tx, rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
-            workers.push((tx, tokio::spawn(process_account_transactions(rx))));
+        for initial_states in states_for_worker {
+            let (tx, rx) = mpsc::channel(channel_buffer_size);
+            workers.push((
+                tx,
+                tokio::spawn(process_account_transactions(
+                    rx,
+                    config.clone(),
+                    initial_states,
+                )),
+            ));
         }
-        Self { workers }
+        Ok(Self { workers, route_by })
     }
 
     /// Sends a transaction to a worker to be processed.
     pub async fn process_transaction(&self, transaction: Transaction) -> Result<(), Error> {
-        let worker_index = (transaction.client as usize) % self.workers.len();
-        self.workers[worker_index].0.send(transaction).await?;
+        self.send_to_worker(None, transaction).await
+    }
+
+    /// Same as `process_transaction`, but remembers which row of the input the transaction
+    /// came from so it can be used in reports.
+    pub async fn process_transaction_from_row(
+        &self,
+        row_number: usize,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        self.send_to_worker(Some(row_number), transaction).await
+    }
+
+    async fn send_to_worker(
+        &self,
+        row_number: Option<usize>,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        let worker_index = self.worker_index_for(transaction.client);
+        self.send_batch(
+            worker_index,
+            smallvec![TransactionMessage {
+                row_number,
+                transaction,
+            }],
+        )
+        .await
+    }
+
+    fn worker_index_for(&self, client: ClientId) -> usize {
+        self.route_by.worker_index(client, self.workers.len())
+    }
+
+    async fn send_batch(&self, worker_index: usize, batch: TransactionBatch) -> Result<(), Error> {
+        self.workers[worker_index]
+            .0
+            .send(WorkerMessage::Transactions(batch))
+            .await?;
         Ok(())
     }
 
+    /// Returns the current balance of every client rounded according to `rounding`,
+    /// ordered by client, without stopping the workers. Only transactions sent before calling
+    /// this are reflected, so anything given to a `TransactionBatcher` must be flushed first.
+    pub async fn snapshot_balances(
+        &self,
+        rounding: OutputRounding,
+    ) -> Result<Vec<AccountBalance>, Error> {
+        let mut replies = Vec::with_capacity(self.workers.len());
+        for (sender, _) in &self.workers {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            sender
+                .send(WorkerMessage::Snapshot {
+                    rounding,
+                    reply: reply_tx,
+                })
+                .await?;
+            replies.push(reply_rx);
+        }
+        let mut balances = Vec::new();
+        for reply in replies {
+            balances.append(
+                &mut reply
+                    .await
+                    .map_err(|e| make_other_err!("Worker stopped before replying : {}", e))?,
+            );
+        }
+        balances.sort_unstable_by_key(|balance| balance.client);
+        Ok(balances)
+    }
+
+    /// Returns a copy of the current state of `client` (including its tx history), or `None`
+    /// if no transaction for it was processed yet, without stopping the workers. Only the
+    /// worker owning the client is asked. Like `snapshot_balances`, only transactions sent
+    /// before calling this are reflected.
+    pub async fn get_client_state(&self, client: ClientId) -> Result<Option<ClientState>, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.workers[self.worker_index_for(client)]
+            .0
+            .send(WorkerMessage::ClientState {
+                client,
+                reply: reply_tx,
+            })
+            .await?;
+        reply_rx
+            .await
+            .map_err(|e| make_other_err!("Worker stopped before replying : {}", e))
+    }
+
+    /// Returns a `TransactionBatcher` that groups up to `batch_size` transactions per worker
+    /// before sending them.
+    pub fn batcher(&self, batch_size: usize) -> TransactionBatcher<'_> {
+        TransactionBatcher {
+            account_manager: self,
+            batch_size: batch_size.max(1),
+            batches: (0..self.workers.len()).map(|_| SmallVec::new()).collect(),
+        }
+    }
+
     /// Closes all the workers and returns a VecDeque of all client states.
     pub async fn collect_account_states(self) -> Result<VecDeque<ClientState>, Error> {
-        let mut client_states = VecDeque::new();
+        Ok(self.collect_results().await?.client_states)
+    }
+
+    /// Closes all the workers and returns all client states along with anything else the
+    /// workers recorded while processing.
+    pub async fn collect_results(self) -> Result<ProcessingResults, Error> {
+        let mut all_worker_results = Vec::with_capacity(self.workers.len());
         for (sender, join_handle) in self.workers {
             drop(sender); // Close our channel.
-            client_states.append(&mut VecDeque::from(join_handle.await??));
+            all_worker_results.push(join_handle.await??);
+        }
+        // Every worker is done, so the number of clients is known and the states can be
+        // gathered without growing (and copying) the collection once per worker.
+        let total_clients = all_worker_results
+            .iter()
+            .map(|worker_results| worker_results.client_states.len())
+            .sum();
+        let mut results = ProcessingResults {
+            client_states: VecDeque::with_capacity(total_clients),
+            ..Default::default()
+        };
+        for (worker_index, worker_results) in all_worker_results.into_iter().enumerate() {
+            results.add_worker_results(worker_index, worker_results);
+        }
+        results.dangling_clients.sort_unstable();
+        Ok(results)
+    }
+
+    /// Closes all the workers and yields the results of each worker as soon as it has
+    /// finished, in the order they finish. Each client belongs to a single worker, so the
+    /// client states of a worker are final even while other workers are still processing.
+    /// Every item only has the `worker_stats` of its own worker, see
+    /// `ProcessingResults::append` to combine them.
+    pub fn finished_workers(self) -> impl Stream<Item = Result<ProcessingResults, Error>> {
+        let running_workers = FuturesUnordered::new();
+        for (worker_index, (sender, join_handle)) in self.workers.into_iter().enumerate() {
+            drop(sender); // Close our channel.
+            running_workers.push(async move { (worker_index, join_handle.await) });
         }
-        Ok(client_states)
+        running_workers.map(|(worker_index, worker_results)| {
+            let mut results = ProcessingResults::default();
+            results.add_worker_results(worker_index, worker_results??);
+            results.dangling_clients.sort_unstable();
+            Ok(results)
+        })
+    }
+}
+
+/// Accumulates transactions into a batch per worker and only sends a batch once it is full,
+/// reducing the number of channel operations under high throughput. Transactions of the same
+/// client always go to the same batch in order, so per-client ordering is preserved.
+///
+/// `flush` must be called once all transactions have been given to the batcher, otherwise
+/// any partially filled batches are never processed.
+pub struct TransactionBatcher<'a> {
+    account_manager: &'a AccountManager,
+    batch_size: usize,
+    batches: Vec<TransactionBatch>,
+}
+
+impl<'a> TransactionBatcher<'a> {
+    /// Adds a transaction to the batch of the worker that owns the client, sending the batch
+    /// if it is full.
+    pub async fn process_transaction_from_row(
+        &mut self,
+        row_number: usize,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        let worker_index = self.account_manager.worker_index_for(transaction.client);
+        let batch = &mut self.batches[worker_index];
+        batch.push(TransactionMessage {
+            row_number: Some(row_number),
+            transaction,
+        });
+        if batch.len() >= self.batch_size {
+            let batch = std::mem::take(batch);
+            self.account_manager.send_batch(worker_index, batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends all partially filled batches.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        for worker_index in 0..self.batches.len() {
+            if !self.batches[worker_index].is_empty() {
+                let batch = std::mem::take(&mut self.batches[worker_index]);
+                self.account_manager.send_batch(worker_index, batch).await?;
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,65 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+use crate::{Error, ErrorKind};
+
+/// A file that is written under a temporary name next to its destination and only renamed
+/// into place by `commit`, so readers never observe a partially written file.
+pub struct AtomicFile {
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    file: fs::File,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file for `final_path`. Nothing exists at `final_path` until
+    /// `commit` is called.
+    pub async fn create(final_path: impl AsRef<Path>) -> Result<Self, Error> {
+        let final_path = final_path.as_ref().to_path_buf();
+        let file_name = final_path.file_name().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Output path '{}' is not a file", final_path.display()),
+            )
+        })?;
+        let temp_path = final_path.with_file_name(format!(
+            ".{}.tmp-{}",
+            file_name.to_string_lossy(),
+            std::process::id()
+        ));
+        let file = fs::File::create(&temp_path).await?;
+        Ok(Self {
+            temp_path,
+            final_path,
+            file,
+        })
+    }
+
+    /// The temporary file to write the content to.
+    pub fn file_mut(&mut self) -> &mut fs::File {
+        &mut self.file
+    }
+
+    /// Syncs the temporary file to disk and renames it to the final path.
+    pub async fn commit(self) -> Result<(), Error> {
+        self.file.sync_all().await?;
+        drop(self.file);
+        fs::rename(&self.temp_path, &self.final_path).await?;
+        Ok(())
+    }
+
+    /// Removes the temporary file, leaving whatever was at the final path untouched.
+    pub async fn abort(self) {
+        drop(self.file);
+        if let Err(err) = fs::remove_file(&self.temp_path).await {
+            eprintln!(
+                "Could not remove temporary output '{}' : {}",
+                self.temp_path.display(),
+                err
+            );
+        }
+    }
+}
@@ -1,13 +1,30 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
 use bigdecimal::BigDecimal;
-use serde::{Deserialize, Serialize};
+use clap::ArgEnum;
+use csv_async::StringRecord;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{make_other_err, Error, ErrorKind};
 
 pub type ClientId = u16;
 pub type TxId = u32;
 
-/// The type of a given transaction.
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// Number of columns a row may have when there is no header to map them by name. They are
+/// mapped in order to: type, client, tx, amount and expected_balance.
+const POSITIONAL_COLUMNS: RangeInclusive<usize> = 3..=5;
+
+/// Names of the columns holding amounts, and their position when there are no headers.
+const AMOUNT_COLUMNS: [(&str, usize); 2] = [("amount", 3), ("expected_balance", 4)];
+
+/// The type of a given transaction. Deserialized with `TransactionType::from_name`, so any
+/// case and a few common abbreviations are accepted.
+#[derive(ArgEnum, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
 pub enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -19,14 +36,194 @@ pub enum TransactionType {
     Resolve,
     #[serde(rename = "chargeback")]
     Chargeback,
+    /// Closes an account that has no funds and no open disputes. All transactions for a
+    /// closed account are rejected.
+    #[serde(rename = "close")]
+    #[clap(name = "close")]
+    CloseAccount,
+}
+
+impl TransactionType {
+    /// Name of the transaction type as it appears in the input.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::CloseAccount => "close",
+        }
+    }
+
+    /// Parses the name of a transaction type ignoring its case. Besides the names returned by
+    /// `as_str`, the abbreviations some upstreams emit are accepted: `dep`, `withdraw`, `wd`
+    /// and `cb`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        const NAMES: [(&str, TransactionType); 10] = [
+            ("deposit", TransactionType::Deposit),
+            ("dep", TransactionType::Deposit),
+            ("withdrawal", TransactionType::Withdrawal),
+            ("withdraw", TransactionType::Withdrawal),
+            ("wd", TransactionType::Withdrawal),
+            ("dispute", TransactionType::Dispute),
+            ("resolve", TransactionType::Resolve),
+            ("chargeback", TransactionType::Chargeback),
+            ("cb", TransactionType::Chargeback),
+            ("close", TransactionType::CloseAccount),
+        ];
+        NAMES
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|(_, transaction_type)| *transaction_type)
+    }
+
+    /// Whether the transaction refers to an earlier deposit or withdrawal by its tx id.
+    pub fn references_tx(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TransactionTypeVisitor;
+
+        impl<'de> Visitor<'de> for TransactionTypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<TransactionType, E> {
+                TransactionType::from_name(value).ok_or_else(|| {
+                    E::unknown_variant(
+                        value,
+                        &[
+                            "deposit",
+                            "withdrawal",
+                            "dispute",
+                            "resolve",
+                            "chargeback",
+                            "close",
+                        ],
+                    )
+                })
+            }
+        }
+
+        // Visiting a str (rather than deserializing a String) avoids an allocation per row.
+        deserializer.deserialize_str(TransactionTypeVisitor)
+    }
 }
 
 /// Holds a raw transaction (usually from a csv).
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
     pub client: ClientId,
     pub tx: TxId,
     pub amount: Option<BigDecimal>,
+    /// Available balance the producer expects the client to have once this transaction has
+    /// been applied. Only used when running balance checks are enabled.
+    #[serde(default)]
+    pub expected_balance: Option<BigDecimal>,
+}
+
+impl Transaction {
+    /// Parses a single csv record. Columns are mapped by name using `headers` or by position
+    /// if there are no headers.
+    ///
+    /// Amounts with more than `max_decimal_digits` digits are rejected before they are
+    /// parsed, since huge numbers make every later operation on them very slow. Amounts with
+    /// more than `max_scale` decimal places (if given) are rejected too, since every amount
+    /// added to a balance can grow the scale of that balance. Amounts are normalized with
+    /// `normalize_amount` first.
+    pub fn from_record(
+        record: &StringRecord,
+        headers: Option<&StringRecord>,
+        max_decimal_digits: usize,
+        max_scale: Option<i64>,
+    ) -> Result<Self, Error> {
+        if headers.is_none() && !POSITIONAL_COLUMNS.contains(&record.len()) {
+            return Err(make_other_err!(
+                "Expected between {} and {} columns but found {}",
+                POSITIONAL_COLUMNS.start(),
+                POSITIONAL_COLUMNS.end(),
+                record.len()
+            ));
+        }
+        let mut needs_normalizing = false;
+        let mut amount_indexes = [None; AMOUNT_COLUMNS.len()];
+        for (i, (name, position)) in AMOUNT_COLUMNS.into_iter().enumerate() {
+            let index = match headers {
+                Some(headers) => headers.iter().position(|header| header == name),
+                None => Some(position),
+            };
+            let field = index.and_then(|index| record.get(index)).unwrap_or("");
+            let digits = field.chars().filter(char::is_ascii_digit).count();
+            if digits > max_decimal_digits {
+                return Err(make_other_err!(
+                    "Column {} has {} digits, more than the maximum of {}",
+                    name,
+                    digits,
+                    max_decimal_digits
+                ));
+            }
+            if let Some(max_scale) = max_scale {
+                // Malformed amounts are left for the deserializer to report.
+                let scale = BigDecimal::from_str(normalize_amount(field))
+                    .map_or(0, |amount| amount.as_bigint_and_exponent().1);
+                if scale > max_scale {
+                    return Err(make_other_err!(
+                        "Column {} has {} decimal places, more than the maximum of {}",
+                        name,
+                        scale,
+                        max_scale
+                    ));
+                }
+            }
+            needs_normalizing |= normalize_amount(field).len() != field.len();
+            amount_indexes[i] = index;
+        }
+        if !needs_normalizing {
+            return record
+                .deserialize(headers)
+                .map_err(|e| make_other_err!("{}", e));
+        }
+        // Only rebuild the record in the uncommon case an amount had to be normalized.
+        let mut normalized: StringRecord = record
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                if amount_indexes.contains(&Some(index)) {
+                    normalize_amount(field)
+                } else {
+                    field
+                }
+            })
+            .collect();
+        normalized.set_position(record.position().cloned());
+        normalized
+            .deserialize(headers)
+            .map_err(|e| make_other_err!("{}", e))
+    }
+}
+
+/// Strips a leading `+` and any redundant leading zeros from an amount, so feeds writing
+/// `+1.50` or `001.50` parse the same as `1.50`. Anything else is left as is, so malformed
+/// values (eg: `++1.50`) are still rejected when parsed.
+pub fn normalize_amount(field: &str) -> &str {
+    let mut amount = match field.strip_prefix('+') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit() || c == '.') => rest,
+        _ => field,
+    };
+    while amount.starts_with('0') && amount[1..].starts_with(|c: char| c.is_ascii_digit()) {
+        amount = &amount[1..];
+    }
+    amount
 }
@@ -1,7 +1,11 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
+use std::sync::Arc;
+
 pub use std::io::ErrorKind;
 
+use crate::rejections::RejectionCategory;
+
 #[macro_export]
 macro_rules! make_other_err {
     ($($arg:tt)+) => {{
@@ -12,10 +16,14 @@ macro_rules! make_other_err {
     }};
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Clone)]
 pub struct Error {
     pub kind: ErrorKind,
     pub messages: Vec<String>,
+    /// The error this one was converted from, if any. Returned by `source()`.
+    source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+    /// Why a transaction was rejected, if this error is about a rejected transaction.
+    rejection_category: Option<RejectionCategory>,
 }
 
 impl Error {
@@ -28,8 +36,50 @@ impl Error {
         Error {
             kind,
             messages: msgs,
+            source: None,
+            rejection_category: None,
         }
     }
+
+    /// Marks this error as the rejection of a transaction for the given reason.
+    pub fn with_rejection_category(mut self, category: RejectionCategory) -> Self {
+        self.rejection_category = Some(category);
+        self
+    }
+
+    /// Why a transaction was rejected, so callers can tell eg: insufficient funds from a
+    /// duplicate tx without parsing the messages. `None` for errors that are not about a
+    /// rejected transaction.
+    pub fn rejection_category(&self) -> Option<RejectionCategory> {
+        self.rejection_category
+    }
+}
+
+// The source is only kept for `source()`, errors are compared by what they report.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.messages == other.messages
+            && self.rejection_category == other.rejection_category
+    }
+}
+
+impl Eq for Error {}
+
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = f.debug_struct("Error");
+        builder
+            .field("kind", &self.kind)
+            .field("messages", &self.messages);
+        if let Some(category) = &self.rejection_category {
+            builder.field("rejection_category", category);
+        }
+        if let Some(source) = &self.source {
+            builder.field("source", source);
+        }
+        builder.finish()
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -47,18 +97,33 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error {
             kind: err.kind(),
             messages: vec![err.to_string()],
+            source: Some(Arc::new(err)),
+            rejection_category: None,
         }
     }
 }
 
 impl From<tokio::task::JoinError> for Error {
     fn from(err: tokio::task::JoinError) -> Self {
-        Error::new(ErrorKind::Other, err.to_string())
+        Error {
+            kind: ErrorKind::Other,
+            messages: vec![err.to_string()],
+            source: Some(Arc::new(err)),
+            rejection_category: None,
+        }
     }
 }
 
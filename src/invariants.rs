@@ -0,0 +1,116 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use bigdecimal::{BigDecimal, Signed};
+
+use crate::account_manager::ClientState;
+use crate::{make_other_err, Error, ErrorKind};
+
+/// Verifies the global conservation invariant: the funds held in all accounts
+/// (`available + held`) must equal all accepted deposits, minus all accepted withdrawals,
+/// minus everything that was charged back. Any difference means a transaction moved funds
+/// it should not have, so an error describing the discrepancy is returned.
+pub fn verify_conservation<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+) -> Result<(), Error> {
+    let mut funds = BigDecimal::default();
+    let mut expected_funds = BigDecimal::default();
+    for client_state in client_states {
+        funds += &client_state.available;
+        funds += &client_state.held;
+        expected_funds += &client_state.deposited;
+        expected_funds -= &client_state.withdrawn;
+        expected_funds -= &client_state.charged_back;
+    }
+    if funds != expected_funds {
+        return Err(make_other_err!(
+            "Conservation violated: accounts hold {} but deposits - withdrawals - chargebacks is {} (discrepancy {})",
+            funds,
+            expected_funds,
+            &funds - &expected_funds
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the invariants every single account must hold once processing finished: `held` is
+/// not negative, `available` is not below what the client is allowed (see
+/// `ClientState::min_available`) and `available + held` is exactly the client's deposits
+/// minus withdrawals minus chargebacks. Returns a description of every violation, sorted by
+/// client.
+pub fn account_violations<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut client_states: Vec<&ClientState> = client_states.into_iter().collect();
+    client_states.sort_unstable_by_key(|state| state.client);
+    for state in client_states {
+        if state.held.is_negative() {
+            violations.push(format!(
+                "Client {} holds a negative amount ({})",
+                state.client, state.held
+            ));
+        }
+        if let Some(min_available) = state.min_available() {
+            if state.available < min_available {
+                violations.push(format!(
+                    "Client {} has {} available, less than the minimum of {}",
+                    state.client, state.available, min_available
+                ));
+            }
+        }
+        let total = &state.available + &state.held;
+        let expected_total = &state.deposited - &state.withdrawn - &state.charged_back;
+        if total != expected_total {
+            violations.push(format!(
+                "Client {} has a total of {} but deposits - withdrawals - chargebacks is {}",
+                state.client, total, expected_total
+            ));
+        }
+    }
+    violations
+}
+
+/// Input side sums of every account along with the sum of the totals in the output, as
+/// compared by `--reconcile`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Reconciliation {
+    pub deposited: BigDecimal,
+    pub withdrawn: BigDecimal,
+    pub charged_back: BigDecimal,
+    /// Sum of the `total` column of every account.
+    pub output_total: BigDecimal,
+}
+
+impl Reconciliation {
+    /// What the output totals should add up to: deposits - withdrawals - chargebacks.
+    pub fn expected_total(&self) -> BigDecimal {
+        &self.deposited - &self.withdrawn - &self.charged_back
+    }
+
+    /// How far the output totals are from `expected_total`, zero if they reconcile.
+    pub fn discrepancy(&self) -> BigDecimal {
+        &self.output_total - self.expected_total()
+    }
+}
+
+/// Sums the input side counters of every account and the totals `output_total` gives for
+/// them. Unlike `verify_conservation` the totals are the ones written to the output, so
+/// rounding them also shows up as a discrepancy.
+pub fn reconcile<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+    output_total: impl Fn(&ClientState) -> BigDecimal,
+) -> Reconciliation {
+    let mut reconciliation = Reconciliation {
+        deposited: BigDecimal::default(),
+        withdrawn: BigDecimal::default(),
+        charged_back: BigDecimal::default(),
+        output_total: BigDecimal::default(),
+    };
+    for client_state in client_states {
+        reconciliation.deposited += &client_state.deposited;
+        reconciliation.withdrawn += &client_state.withdrawn;
+        reconciliation.charged_back += &client_state.charged_back;
+        reconciliation.output_total += output_total(client_state);
+    }
+    reconciliation
+}
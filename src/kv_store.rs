@@ -0,0 +1,33 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use crate::account_manager::ClientState;
+use crate::common::ClientId;
+use crate::output::{AccountBalance, OutputRounding};
+use crate::{make_other_err, Error, ErrorKind};
+
+/// Converts a client id into the key used in the store. Big endian so keys sort by client.
+pub fn client_key(client: ClientId) -> [u8; 2] {
+    client.to_be_bytes()
+}
+
+/// Writes the balance of every client into the embedded key-value store at `path`, keyed by
+/// client id (see `client_key`) with the JSON encoded `AccountBalance` (rounded according to
+/// `rounding`, like the output) as the value. This allows looking up a single client after a
+/// run without re-parsing the csv output.
+pub fn write_kv_store<'a>(
+    path: &str,
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+    rounding: OutputRounding,
+) -> Result<(), Error> {
+    let db = sled::open(path)
+        .map_err(|e| make_other_err!("Could not open kv store '{}' : {}", path, e))?;
+    for client_state in client_states {
+        let value = serde_json::to_vec(&AccountBalance::with_rounding(client_state, rounding))
+            .map_err(|e| make_other_err!("Could not serialize balance : {}", e))?;
+        db.insert(client_key(client_state.client), value)
+            .map_err(|e| make_other_err!("Could not write to kv store '{}' : {}", path, e))?;
+    }
+    db.flush()
+        .map_err(|e| make_other_err!("Could not flush kv store '{}' : {}", path, e))?;
+    Ok(())
+}
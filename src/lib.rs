@@ -1,108 +1,1814 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
+use std::ffi::OsString;
 
-use clap::Parser;
-use csv_async::AsyncReaderBuilder as CsvAsyncReaderBuilder;
-use futures::StreamExt;
+use bigdecimal::{BigDecimal, Signed};
+use clap::{Parser, Subcommand};
+use csv_async::{AsyncReaderBuilder as CsvAsyncReaderBuilder, Terminator, Trim};
+use futures::future::try_join_all;
+use futures::{pin_mut, Stream, StreamExt};
 use num_cpus::get as get_num_cpus;
+use serde::Serialize;
 use tokio::fs;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
+mod atomic_file;
+use atomic_file::AtomicFile;
 mod error;
 #[cfg(test)]
 mod tests; // Failing to do this results in zero unit tests being run.
-use error::{Error, ErrorKind};
-mod common;
-use common::Transaction;
-mod account_manager;
-use account_manager::AccountManager;
+pub use error::{Error, ErrorKind};
+pub mod account_manager;
+pub mod common;
+use account_manager::{
+    AccountManager, ClientState, DecimalContext, DepositCollisionPolicy, DisputePolicy,
+    ProcessingConfig, ProcessingResults, RouteBy, CHANNEL_BUFFER_SIZE,
+};
+use common::{ClientId, Transaction, TransactionType, TxId};
+mod merge;
+use merge::merge_outputs;
+mod self_test;
+use self_test::run_self_test;
+pub mod output;
+use output::{
+    format_amount, Column, ColumnType, OutputFormat, OutputRounding, OutputSchema, RoundingMode,
+    MAX_OUTPUT_PRECISION, OUTPUT_FORMAT_VERSION,
+};
+mod invariants;
+mod lint;
+#[cfg(feature = "ofx")]
+mod ofx;
+use lint::lint_transactions;
+mod reader;
+mod snapshot;
+use reader::{spawn_transaction_reader, ParsedRow, ReaderOptions};
+use snapshot::load_snapshot;
+mod metadata;
+use invariants::{account_violations, reconcile, verify_conservation};
+use metadata::{read_first_line, FileMetadata};
+#[cfg(feature = "kv")]
+pub mod kv_store;
+pub mod rejections;
+mod replay;
+mod reports;
+#[cfg(feature = "http")]
+mod sse;
+use rejections::{format_rejections, Rejection, RejectionCategory, RejectionsFormat};
+use replay::replay_rejections;
+use reports::{balance_quantiles, net_outflows, open_disputes};
+pub mod sink;
+use sink::{sink_for_format, Cell, OutputSink, RowFormatter};
+mod verify_output;
+use verify_output::VerifyingWriter;
+mod manifest;
+use manifest::{
+    unix_time_ms, FingerprintWriter, InputManifest, RowCounts, RunManifest, RunSummary,
+};
+
+/// Number of rows formatted together by `--parallel-format`.
+const PARALLEL_FORMAT_CHUNK_ROWS: usize = 4096;
 
 /// Command line arguments holder.
-#[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[derive(Parser, Serialize)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// CSV file of all transactions.
-    transactions_file: String,
+    #[clap(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
+    /// CSV files of all transactions, or `-` to read them from stdin. Several files are read
+    /// one after the other in the given order, as if they were a single file.
+    #[clap(required_unless_present = "input-dir")]
+    transactions_file: Vec<String>,
+
+    /// Read every `.csv` file in this directory instead of a single transactions file. The
+    /// files are read concurrently, so this requires `--disjoint-clients`.
+    #[clap(
+        long,
+        conflicts_with_all = &["transactions-file", "serve-sse"],
+        requires = "disjoint-clients"
+    )]
+    input_dir: Option<String>,
+
+    /// Promise that no client appears in more than one input file, which allows reading them
+    /// concurrently. Fails if a client is found in more than one file.
+    #[clap(long, requires = "input-dir")]
+    disjoint_clients: bool,
+
+    /// Compare each client's available balance against the `expected_balance` column after
+    /// every transaction and report any mismatches to stderr.
+    #[clap(long)]
+    check_running_balance: bool,
+
+    /// Largest difference allowed between `expected_balance` and the computed balance when
+    /// `--check-running-balance` is used.
+    #[clap(long, default_value = "0.0001")]
+    running_balance_tolerance: BigDecimal,
+
+    /// Print the number of clients, transactions and busy time of each worker to stderr.
+    #[clap(long)]
+    worker_stats: bool,
+
+    /// Upper bound on the number of workers spawned, regardless of the detected cpu count or
+    /// `ACCOUNT_WORKER_SPAWNS`.
+    #[clap(long, default_value = "256")]
+    max_workers: usize,
+
+    /// Number of workers to spawn. Takes precedence over `ACCOUNT_WORKER_SPAWNS`, the cpu
+    /// count, the input size and `--max-workers`.
+    #[clap(long)]
+    workers: Option<usize>,
+
+    /// Number of batches of transactions (see `--batch-size`) each worker can have queued
+    /// before reading the input waits for it.
+    #[clap(long, default_value_t = CHANNEL_BUFFER_SIZE)]
+    channel_buffer_size: usize,
+
+    /// Add `deposited`, `withdrawn` and `net_flow` columns to the output. These only count
+    /// accepted deposits and withdrawals and are not affected by disputes, except that
+    /// disputed and charged back withdrawals are not counted (see
+    /// `--allow-withdrawal-disputes`).
+    #[clap(long)]
+    with_netflow: bool,
+
+    /// Add a `peak_available` column with the highest `available` balance each client reached
+    /// while processing, which can be far above its final balance.
+    #[clap(long)]
+    with_peak_balance: bool,
+
+    /// Add a `held_ratio_flagged` column marking accounts where `held / total` is above the
+    /// given ratio (eg: 0.5). This is purely informational.
+    #[clap(long)]
+    flag_held_ratio: Option<BigDecimal>,
+
+    /// Write the complete internal state of every client (including the state of each of
+    /// its transactions) as JSON to this path once processing is done. Useful for debugging,
+    /// or as a snapshot for `--resume` to continue from in a later run.
+    #[clap(long, alias = "snapshot-out")]
+    dump_final_state: Option<String>,
+
+    /// Print the number of rows read so far to stderr while reading the inputs, and the
+    /// number of rows and clients once they are all processed.
+    #[clap(long)]
+    progress: bool,
+
+    /// Number of rows between two `--progress` lines of an input. Rows are counted as they
+    /// are parsed in chunks, so lines may be further apart than this for small values.
+    #[clap(long, default_value = "1000000", requires = "progress")]
+    progress_every: usize,
+
+    /// Number of transactions grouped together for each worker before they are sent to it.
+    /// Larger batches mean fewer (relatively expensive) channel operations.
+    #[clap(long, default_value = "16")]
+    batch_size: usize,
+
+    /// Treat the first row as data instead of a header. Columns are then mapped by position:
+    /// type, client, tx, amount (and optionally expected_balance).
+    #[clap(long)]
+    no_header: bool,
+
+    /// Omit accounts whose total is below this amount from the output (locked accounts are
+    /// always kept). The comparison uses the exact total before any rounding, so an account
+    /// with a total of 0.99999 is omitted with a threshold of 1 even though it would be
+    /// printed as 1.0000.
+    #[clap(long)]
+    dust_threshold: Option<BigDecimal>,
+
+    /// Layout of the output.
+    #[clap(long, arg_enum, default_value = "csv")]
+    format: OutputFormat,
+
+    /// Verify that the funds in all accounts equal accepted deposits minus withdrawals minus
+    /// chargebacks before writing the output, failing if they do not.
+    #[clap(long)]
+    verify_conservation: bool,
+
+    /// Check every account before writing the output: `held` must not be negative,
+    /// `available` must not be negative (beyond `--overdraft`) and the total must be the
+    /// client's deposits minus withdrawals minus chargebacks. Every violation is printed and
+    /// the run fails if there is any.
+    #[clap(long)]
+    verify: bool,
+
+    /// Also write the final balances into an embedded key-value store at this path, keyed
+    /// by client id. Requires the `kv` feature.
+    #[clap(long)]
+    kv_out: Option<String>,
+
+    /// Instead of writing the output, listen on this address (eg: 127.0.0.1:8080) and stream
+    /// every rejected row and every account as Server-Sent Events to the first client that
+    /// connects. Processing only starts once the client is connected. The accounts of each
+    /// worker are sent as soon as that worker has finished, so they are never sorted.
+    /// Requires the `http` feature.
+    #[clap(long, conflicts_with = "output")]
+    serve_sse: Option<String>,
+
+    /// Print a warning listing clients that were only referenced by disputes, resolves or
+    /// chargebacks of unknown transactions. Such clients are left out of the output.
+    #[clap(long)]
+    warn_dangling_clients: bool,
+
+    /// Warn about every tx id used by the deposits or withdrawals of more than one client.
+    /// Reuse within a client is always rejected, but tx ids are only checked across clients
+    /// with this flag: it keeps the client of every deposit and withdrawal in memory (roughly
+    /// 16 bytes per transaction, plus the hash map overhead), on top of what the workers keep.
+    #[clap(long)]
+    global_tx_uniqueness: bool,
+
+    /// After processing, undo the last N applied transactions of every client to see what
+    /// the balances were before them. Requires keeping every applied transaction in memory.
+    #[clap(long)]
+    rewind: Option<usize>,
+
+    /// Write a csv of every transaction still under dispute (client, tx and held amount) to
+    /// this path.
+    #[clap(long)]
+    open_disputes: Option<String>,
+
+    /// Write a csv of every client that withdrew more than it deposited (client and net
+    /// outflow) to this path. Clients that withdrew exactly what they deposited are omitted.
+    #[clap(long)]
+    net_outflow: Option<String>,
+
+    /// Print the total deposited, withdrawn and charged back to stderr and fail if
+    /// deposited - withdrawn - charged back differs from the sum of the `total` column of
+    /// every account (including accounts hidden by `--dust-threshold`) by more than rounding
+    /// the `available` and `held` columns of every account can explain.
+    #[clap(long)]
+    reconcile: bool,
+
+    /// Silently skip lines that are empty or only contain whitespace.
+    #[clap(long, parse(try_from_str), default_value = "true")]
+    skip_blank_lines: bool,
+
+    /// Silently skip lines starting with this (ASCII) character, eg: '#'.
+    #[clap(long)]
+    comment_char: Option<char>,
+
+    /// Character separating the columns of the input, eg: ';' or a tab. Must be ASCII.
+    #[clap(long, default_value = ",")]
+    delimiter: char,
+
+    /// How clients are assigned to workers. `hash` spreads clustered client ids (eg: all
+    /// multiples of the worker count) more evenly than `modulus`, which is only kept for
+    /// reproducing the placement of earlier runs.
+    #[clap(long, arg_enum, default_value = "hash")]
+    route_by: RouteBy,
+
+    /// Write every rejected row (unparsable or not applied) to this file, ordered by row.
+    #[clap(long)]
+    rejections_file: Option<String>,
+
+    /// Layout of the file written by `--rejections-file`.
+    #[clap(long, arg_enum, default_value = "text")]
+    rejections_format: RejectionsFormat,
+
+    /// Allow withdrawals to take the available funds of every client down to minus this
+    /// amount instead of zero.
+    #[clap(long)]
+    overdraft: Option<BigDecimal>,
+
+    /// Reject every transaction of a client past the first N, to limit how much work a single
+    /// client can cause.
+    #[clap(long)]
+    max_tx_per_client: Option<usize>,
+
+    /// Reject every transaction with a tx id of 0, for feeds that use 0 to mean "no id".
+    #[clap(long)]
+    forbid_zero_tx: bool,
+
+    /// Reject deposits to locked (charged back) accounts as well as withdrawals, instead of
+    /// only withdrawals.
+    #[clap(long)]
+    lock_freezes_deposits: bool,
+
+    /// Allow disputing withdrawals too. While disputed the withdrawn amount is credited back
+    /// to `held`, a resolve takes it out again and a chargeback releases it to `available`
+    /// (and locks the account).
+    #[clap(long)]
+    allow_withdrawal_disputes: bool,
+
+    /// How to handle a dispute of a deposit whose funds are no longer available (eg: they
+    /// were withdrawn since). `strict` rejects the dispute, `allow-negative` always holds the
+    /// disputed amount and lets `available` go below zero.
+    #[clap(long, arg_enum, default_value = "strict")]
+    dispute_policy: DisputePolicy,
+
+    /// How a deposit reusing the tx id of an earlier deposit or withdrawal is handled. A
+    /// deposit with the same amount as the earlier deposit is a redelivery, anything else is a
+    /// collision. `ignore` skips redeliveries silently, `reject` rejects both and `error` skips
+    /// redeliveries but fails the run on a collision.
+    #[clap(long, arg_enum, default_value = "reject")]
+    on_deposit_collision: DepositCollisionPolicy,
+
+    /// Comma separated transaction types to process, eg: `deposit,withdrawal` for gross
+    /// balances. Transactions of any other type are skipped and counted on stderr. Every type
+    /// is processed if not set.
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    types: Vec<TransactionType>,
+
+    /// Fail before processing anything if a transactions file is larger than this many bytes.
+    /// Not checked for stdin and named pipes, whose size is not known up front. For gzip
+    /// compressed files this is their compressed size.
+    #[clap(long)]
+    max_file_bytes: Option<u64>,
+
+    /// Decompress the transactions file(s) with gzip. Implied for files ending in `.gz`.
+    /// Requires the `gzip` feature.
+    #[clap(long)]
+    gzip: bool,
+
+    /// Fail without writing any output on the first row that cannot be parsed or is
+    /// rejected, instead of only reporting it on stderr. Rows are parsed ahead of being
+    /// applied, so an unparsable row can be reported instead of an earlier rejected one.
+    #[clap(long)]
+    strict: bool,
+
+    /// Print the min, p50, p90, p99 and max of the clients' total balances to stderr.
+    #[clap(long)]
+    balance_quantiles: bool,
+
+    /// Require the file to start with a `# precision=N currency=CODE` line matching
+    /// `--precision` and `--currency`, otherwise nothing is processed.
+    #[clap(long)]
+    require_metadata: bool,
+
+    /// Number of decimal places amounts are rounded to in the output (at most 28). Also the
+    /// number of decimal places the amounts of the file must be declared with when
+    /// `--require-metadata` is used, and balances are stored with when `--round-on-store` is
+    /// used.
+    #[clap(long, default_value = "4")]
+    precision: u32,
+
+    /// Round every amount to `--precision` decimal places before applying it and keep
+    /// `available` and `held` at exactly that scale, instead of only rounding the output. A
+    /// disputed deposit holds its rounded amount.
+    #[clap(long)]
+    round_on_store: bool,
+
+    /// How amounts are rounded with `--round-on-store`.
+    #[clap(long, arg_enum, default_value = "half-up", requires = "round-on-store")]
+    store_rounding: RoundingMode,
+
+    /// Currency the file must be declared with when `--require-metadata` is used. Any
+    /// currency is accepted if not set.
+    #[clap(long)]
+    currency: Option<String>,
+
+    /// Write a JSON description of the output columns, their types and the format version to
+    /// this file.
+    #[clap(long)]
+    emit_schema: Option<String>,
+
+    /// Start from the client states in this file (written by `--dump-final-state` of a
+    /// previous run) and apply the transactions on top of them. Transactions of the previous
+    /// runs can still be disputed.
+    #[clap(long, alias = "snapshot-in", conflicts_with = "scenarios")]
+    resume: Option<String>,
+
+    /// Rows with an amount of more than this many digits are rejected without being parsed.
+    #[clap(long, default_value = "64")]
+    max_decimal_digits: usize,
+
+    /// Rows with an amount of more than this many decimal places are rejected. Without it
+    /// amounts of any scale are accepted.
+    #[clap(long)]
+    max_scale: Option<u32>,
+
+    /// How `available` is rounded in the output. `total` is the sum of the rounded
+    /// `available` and `held`.
+    #[clap(long, arg_enum, default_value = "half-up")]
+    rounding_available: RoundingMode,
+
+    /// How `held` is rounded in the output (eg: `down` to be conservative).
+    #[clap(long, arg_enum, default_value = "half-up")]
+    rounding_held: RoundingMode,
+
+    /// Write the output to this file instead of stdout.
+    #[clap(long)]
+    output: Option<String>,
+
+    /// Write `--output` under a temporary name and only rename it into place once
+    /// everything succeeded, so no partial output is ever visible. Rows are sorted by client.
+    #[clap(long, requires = "output")]
+    atomic_output: bool,
+
+    /// Write the rows sorted by client, so the same input always gives the same output.
+    /// Otherwise the order of the rows depends on the workers.
+    #[clap(long)]
+    sort: bool,
+
+    /// Parse the csv output back as it is written and fail if the `total` of any row is not
+    /// exactly its `available` plus `held`. With `--atomic-output` nothing is written then.
+    #[clap(long)]
+    verify_output: bool,
+
+    /// Write a JSON manifest of the run to this path once the output is written: the inputs
+    /// and their sizes, the number of rows read, accepted and rejected (by category), the
+    /// number of workers, every option, the start and end times, a fingerprint of the output
+    /// and the tx ids found by `--global-tx-uniqueness`.
+    #[clap(long, conflicts_with = "serve-sse")]
+    manifest_out: Option<String>,
+
+    /// Comma separated dispute policies to also compute balances under, in the same pass.
+    /// Adds available, held, total and locked columns suffixed by the policy name for each.
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    scenarios: Vec<DisputePolicy>,
+
+    /// Comma separated tx ids. Right after the first row with each of these tx ids has been
+    /// applied, the balance of every client is written to `--checkpoints-file`.
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        requires = "checkpoints-file",
+        conflicts_with_all = &["input-dir", "serve-sse"]
+    )]
+    checkpoint_at: Vec<TxId>,
+
+    /// Csv file the `--checkpoint-at` snapshots are written to. Each row is labeled with the
+    /// tx id of its checkpoint in the `checkpoint` column.
+    #[clap(long, requires = "checkpoint-at")]
+    checkpoints_file: Option<String>,
+
+    /// Format the output rows on several threads while earlier rows are being written. Rows
+    /// are written in the same order as without it. Only the csv format supports this, it is
+    /// ignored otherwise.
+    #[clap(long)]
+    parallel_format: bool,
+}
+
+impl Args {
+    /// Number of decimal places amounts are rounded to in the output.
+    pub(crate) fn output_precision(&self) -> i64 {
+        self.precision.into()
+    }
+
+    /// Returns how the `available` and `held` columns are rounded in the output.
+    pub(crate) fn output_rounding(&self) -> OutputRounding {
+        OutputRounding {
+            precision: self.output_precision(),
+            available: self.rounding_available,
+            held: self.rounding_held,
+        }
+    }
+
+    /// Builds the configuration of the workers from the options. Fails if they are invalid.
+    fn processing_config(&self) -> Result<ProcessingConfig, Error> {
+        if self
+            .overdraft
+            .as_ref()
+            .is_some_and(|limit| limit.is_negative())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Overdraft limit must not be negative",
+            ));
+        }
+        Ok(ProcessingConfig {
+            running_balance_tolerance: self
+                .check_running_balance
+                .then(|| self.running_balance_tolerance.clone()),
+            rewind: self.rewind,
+            route_by: self.route_by,
+            collect_rejections: self.collects_rejections(),
+            scenarios: self.scenarios.clone(),
+            overdraft_limit: self.overdraft.clone(),
+            max_tx_per_client: self.max_tx_per_client,
+            channel_buffer_size: Some(self.channel_buffer_size),
+            decimal_context: self.round_on_store.then(|| DecimalContext {
+                scale: i64::from(self.precision),
+                rounding: self.store_rounding,
+            }),
+            forbid_zero_tx: self.forbid_zero_tx,
+            lock_freezes_deposits: self.lock_freezes_deposits,
+            allow_withdrawal_disputes: self.allow_withdrawal_disputes,
+            dispute_policy: self.dispute_policy,
+            deposit_collision_policy: self.on_deposit_collision,
+            fail_on_rejection: self.strict,
+            quiet_rejections: false,
+        })
+    }
+
+    /// Whether every rejected row is recorded, because some output needs them.
+    fn collects_rejections(&self) -> bool {
+        self.rejections_file.is_some() || self.serve_sse.is_some() || self.manifest_out.is_some()
+    }
+}
+
+/// Commands other than the default of processing a transactions file.
+#[derive(Subcommand)]
+enum Command {
+    /// Combine several output files (eg: one per shard of clients) into a single output.
+    /// Fails if the same client appears in more than one file.
+    Merge {
+        /// Output files to merge.
+        #[clap(required = true)]
+        output_files: Vec<String>,
+    },
+    /// Run the bundled sample files and verify the output matches the known good results.
+    Selftest,
+    /// Scan a transactions file for statistical anomalies (eg: unusually many disputes, tx id
+    /// reuse) and write them as one JSON object per line. Nothing is processed.
+    Lint {
+        /// CSV file of all transactions.
+        transactions_file: String,
+        /// Clients that disputed more than this proportion of their deposits are reported.
+        #[clap(long, default_value = "0.5")]
+        max_dispute_rate: f64,
+    },
+    /// Re-apply the transactions of a rejections file (written with `--rejections-format
+    /// json`) on top of a snapshot (written with `--dump-final-state`) and write which of them
+    /// are applied now. Eg: a dispute that arrived before its deposit. Options that decide
+    /// whether a transaction is applied (eg: `--overdraft` or `--allow-withdrawal-disputes`)
+    /// are not stored in the snapshot, give them again before `replay` to replay with them.
+    Replay {
+        /// Rejections file of an earlier run.
+        rejections_file: String,
+        /// Snapshot of the clients to replay the transactions on.
+        #[clap(long)]
+        snapshot: String,
+        /// Write the state of every client after the replay as JSON to this path, in the
+        /// same format as the snapshot.
+        #[clap(long)]
+        dump_final_state: Option<String>,
+    },
+}
+
+/// Figures out how many workers to spawn. `env_override` is the value of the
+/// `ACCOUNT_WORKER_SPAWNS` env var (if set), otherwise the number of cpus is used. The result
+/// is always clamped to `1..=max_workers` since the detected value can be surprising in
+/// constrained containers (eg: cgroup limits). Fails if `env_override` is not a number.
+pub(crate) fn resolve_worker_count(
+    env_override: Option<OsString>,
+    max_workers: usize,
+) -> Result<usize, Error> {
+    // TODO(allada): Use std::thread::available_parallelism() instead of num_cpus::get() when
+    // it is on stable long enough.
+    let detected = match env_override {
+        Some(value) => value
+            .to_str()
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "ACCOUNT_WORKER_SPAWNS must be a number of workers, got {:?}",
+                        value
+                    ),
+                )
+            })?,
+        None => get_num_cpus(),
+    };
+    let worker_count = detected.clamp(1, max_workers.max(1));
+    if worker_count != detected {
+        eprintln!(
+            "Detected worker count {} is out of range, using {} workers instead",
+            detected, worker_count
+        );
+    }
+    Ok(worker_count)
+}
+
+/// Fails unless `workers` (given with `--workers`) is a number of workers `AccountManager`
+/// can spawn.
+fn check_worker_count(workers: usize) -> Result<usize, Error> {
+    if workers == 0 || workers >= usize::from(u16::MAX) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "--workers must be at least 1 and less than {}, got {}",
+                u16::MAX,
+                workers
+            ),
+        ));
+    }
+    Ok(workers)
+}
+
+/// Inputs of at most this many bytes are processed by a single worker when the worker count
+/// is not given explicitly. See the "Workers" section of the README for how this was picked.
+const SINGLE_WORKER_MAX_INPUT_BYTES: u64 = 256 * 1024;
+
+/// Picks the number of workers for inputs adding up to `size_hint` bytes (if known). For small
+/// inputs spawning and feeding many workers costs more than it saves, so they get one.
+pub(crate) fn workers_for_input_size(worker_count: usize, size_hint: Option<u64>) -> usize {
+    match size_hint {
+        Some(size) if size <= SINGLE_WORKER_MAX_INPUT_BYTES => 1,
+        _ => worker_count,
+    }
+}
+
+/// Value of `transactions_file` that reads the transactions from stdin.
+const STDIN_PATH: &str = "-";
+
+/// Opens the transactions file for reading.
+///
+/// Named pipes (FIFOs) are supported. Opening one blocks until a writer connects, reads may
+/// return partial rows while the writer is slow (the csv reader simply waits for the rest) and
+/// the stream only ends once every writer has closed its end of the pipe.
+async fn open_transactions_file(path: &str) -> Result<fs::File, Error> {
+    if let Ok(metadata) = fs::metadata(path).await {
+        // Opening a directory succeeds on some platforms, only for the first read to fail
+        // with a confusing error.
+        if metadata.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is a directory, expected a file", path),
+            ));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if metadata.file_type().is_fifo() {
+                eprintln!(
+                    "'{}' is a named pipe, waiting for a writer to connect",
+                    path
+                );
+            }
+        }
+    }
+    fs::File::open(path).await.map_err(|e| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Error, could not open file: '{}', error '{}'", path, e),
+        )
+    })
 }
 
 /// For the given args will parse the csv file, stream the data to the AccountManager and
 /// finally write the output csv to the provided `writer`.
 /// Note: This is effectively a main() function, but in order to make unit testing easier
 /// it is separated.
-pub async fn run_with_args(args: Args, mut writer: impl AsyncWrite + Unpin) -> Result<(), Error> {
-    let (account_manager, mut reader) = {
-        // Setup and configure our classes and utilities.
-        let file = match fs::File::open(&args.transactions_file).await {
-            Ok(file) => file,
-            Err(e) => {
+pub async fn run_with_args(args: Args, writer: impl AsyncWrite + Unpin) -> Result<(), Error> {
+    run_with_args_and_stdin(args, tokio::io::stdin(), writer).await
+}
+
+/// Same as `run_with_args`, but a `transactions_file` of `-` reads from `stdin` instead of the
+/// process' stdin.
+pub(crate) async fn run_with_args_and_stdin(
+    args: Args,
+    stdin: impl AsyncRead + Unpin + Send + 'static,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    if args.precision > MAX_OUTPUT_PRECISION {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Precision {} is too large, at most {} decimal places are supported",
+                args.precision, MAX_OUTPUT_PRECISION
+            ),
+        ));
+    }
+    if args.verify_output && args.format != OutputFormat::Csv {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--verify-output only supports the csv format",
+        ));
+    }
+    if let (None, Some(input_dir)) = (&args.command, &args.input_dir) {
+        let mut inputs = Vec::new();
+        for path in list_csv_files(input_dir).await? {
+            let input = Input::open(&path).await?;
+            input.check_size(args.max_file_bytes)?;
+            inputs.push(input.decompressed(args.gzip)?);
+        }
+        return run_with_readers(&args, inputs, writer).await;
+    }
+    let transactions_files = match &args.command {
+        Some(Command::Merge { output_files }) => {
+            return merge_outputs(output_files, args.output_precision(), writer).await
+        }
+        Some(Command::Selftest) => return run_self_test(writer).await,
+        Some(Command::Lint {
+            transactions_file,
+            max_dispute_rate,
+        }) => return lint_transactions(transactions_file, *max_dispute_rate, writer).await,
+        Some(Command::Replay {
+            rejections_file,
+            snapshot,
+            dump_final_state,
+        }) => {
+            return replay_rejections(
+                rejections_file,
+                snapshot,
+                dump_final_state.as_deref(),
+                args.processing_config()?,
+                writer,
+            )
+            .await
+        }
+        None => &args.transactions_file,
+    };
+    let mut stdin = Some(stdin);
+    let mut inputs = Vec::with_capacity(transactions_files.len());
+    for transactions_file in transactions_files {
+        let input = if transactions_file == STDIN_PATH {
+            let stdin = stdin.take().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "stdin can only be read once")
+            })?;
+            Input {
+                name: "<stdin>".to_string(),
+                reader: Box::new(stdin),
+                size_hint: None,
+            }
+        } else {
+            Input::open(transactions_file).await?
+        };
+        input.check_size(args.max_file_bytes)?;
+        inputs.push(input.decompressed(args.gzip)?);
+    }
+    if inputs.len() == 1 {
+        return run_with_input(&args, inputs.remove(0), writer).await;
+    }
+    if args.serve_sse.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--serve-sse only supports a single transactions file",
+        ));
+    }
+    run_with_readers(&args, inputs, writer).await
+}
+
+/// Returns the (sorted) paths of every `.csv` file in `dir`.
+async fn list_csv_files(dir: &str) -> Result<Vec<String>, Error> {
+    let mut entries = fs::read_dir(dir).await.map_err(|e| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Error, could not read directory: '{}', error '{}'", dir, e),
+        )
+    })?;
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "csv") {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    if paths.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No .csv files found in '{}'", dir),
+        ));
+    }
+    paths.sort_unstable();
+    Ok(paths)
+}
+
+/// A transactions input along with the name it is reported by (usually its path).
+struct Input {
+    name: String,
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    /// Size in bytes, if known before reading it (eg: regular files, but not pipes).
+    size_hint: Option<u64>,
+}
+
+impl Input {
+    /// Opens the transactions file at `path`.
+    async fn open(path: &str) -> Result<Self, Error> {
+        let file = open_transactions_file(path).await?;
+        let size_hint = match file.metadata().await {
+            Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+            _ => None,
+        };
+        Ok(Self {
+            name: path.to_string(),
+            reader: Box::new(file),
+            size_hint,
+        })
+    }
+
+    /// Fails if the input is known to be larger than `max_bytes`. Inputs of unknown size (eg:
+    /// stdin or pipes) always pass.
+    fn check_size(&self, max_bytes: Option<u64>) -> Result<(), Error> {
+        match (self.size_hint, max_bytes) {
+            (Some(size), Some(max_bytes)) if size > max_bytes => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "'{}' is {} bytes, more than the maximum of {} bytes",
+                    self.name, size, max_bytes
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wraps the input in a gzip decoder if `gzip` is set or its name ends in `.gz`. The size
+    /// of the decompressed data is not known, so `size_hint` is cleared.
+    fn decompressed(self, gzip: bool) -> Result<Self, Error> {
+        if !gzip && !self.name.ends_with(".gz") {
+            return Ok(self);
+        }
+        #[cfg(feature = "gzip")]
+        return Ok(Self {
+            reader: Box::new(async_compression::tokio::bufread::GzipDecoder::new(
+                BufReader::new(self.reader),
+            )),
+            name: self.name,
+            size_hint: None,
+        });
+        #[cfg(not(feature = "gzip"))]
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Cannot decompress '{}', built without the `gzip` feature",
+                self.name
+            ),
+        ));
+    }
+
+    /// Names `reader` after the first `transactions_file` in `args`.
+    fn from_reader(args: &Args, reader: impl AsyncRead + Unpin + Send + 'static) -> Self {
+        let name = args
+            .transactions_file
+            .first()
+            .cloned()
+            .unwrap_or_else(|| "<input>".to_string());
+        Self {
+            name,
+            reader: Box::new(reader),
+            size_hint: None,
+        }
+    }
+}
+
+/// Same as `run_with_args`, but the transactions are read from `input` instead of the
+/// `transactions_file` in `args`.
+pub(crate) async fn run_with_reader(
+    args: &Args,
+    input: impl AsyncRead + Unpin + Send + 'static,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    run_with_input(args, Input::from_reader(args, input), writer).await
+}
+
+/// Same as `run_with_reader`, but for an already opened `Input`.
+async fn run_with_input(
+    args: &Args,
+    input: Input,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    if let Some(address) = &args.serve_sse {
+        #[cfg(feature = "http")]
+        return sse::serve_sse(
+            args,
+            input.reader,
+            tokio::net::TcpListener::bind(address).await?,
+        )
+        .await;
+        #[cfg(not(feature = "http"))]
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Cannot serve on '{}', built without the `http` feature",
+                address
+            ),
+        ));
+    }
+    run_with_readers(args, vec![input], writer).await
+}
+
+/// Same as `run_with_reader`, but reads every input. See `run_with_inputs`.
+async fn run_with_readers(
+    args: &Args,
+    inputs: Vec<Input>,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    match &args.output {
+        Some(output_path) if args.atomic_output => {
+            let mut output_file = AtomicFile::create(output_path).await?;
+            let result = run_with_writer(args, inputs, output_file.file_mut()).await;
+            match result {
+                Ok(()) => output_file.commit().await,
+                Err(err) => {
+                    output_file.abort().await;
+                    Err(err)
+                }
+            }
+        }
+        Some(output_path) => {
+            run_with_writer(args, inputs, fs::File::create(output_path).await?).await
+        }
+        None => run_with_writer(args, inputs, writer).await,
+    }
+}
+
+/// Same as `run_with_readers`, but always writes the output to `writer`. With
+/// `--verify-output` the output is checked as it is written.
+async fn run_with_writer(
+    args: &Args,
+    inputs: Vec<Input>,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    if !args.verify_output {
+        return run_with_manifest(args, inputs, writer).await;
+    }
+    let mut verifying_writer = VerifyingWriter::new(writer);
+    run_with_manifest(args, inputs, &mut verifying_writer).await?;
+    verifying_writer.finish()
+}
+
+/// Same as `run_with_writer`, but without `--verify-output`. With `--manifest-out` the
+/// output is fingerprinted as it is written and the manifest is written once it is done.
+async fn run_with_manifest(
+    args: &Args,
+    inputs: Vec<Input>,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let manifest_path = match &args.manifest_out {
+        Some(manifest_path) => manifest_path,
+        None => {
+            let mut sink = sink_for_format(args.format, args.output_precision(), writer)?;
+            return run_with_inputs(args, inputs, sink.as_mut())
+                .await
+                .map(|_| ());
+        }
+    };
+    let started_at_ms = unix_time_ms();
+    let mut fingerprint_writer = FingerprintWriter::new(writer);
+    let summary = {
+        let mut sink = sink_for_format(
+            args.format,
+            args.output_precision(),
+            &mut fingerprint_writer,
+        )?;
+        run_with_inputs(args, inputs, sink.as_mut()).await?
+    };
+    let manifest = RunManifest {
+        started_at_ms,
+        finished_at_ms: unix_time_ms(),
+        inputs: summary.inputs,
+        workers: summary.workers,
+        rows: summary.rows,
+        reused_tx_ids: summary.reused_tx_ids,
+        options: args,
+        output_fingerprint: fingerprint_writer.fingerprint(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| make_other_err!("Could not serialize manifest : {}", e))?;
+    fs::write(manifest_path, manifest).await?;
+    Ok(())
+}
+
+/// Returns the columns of the output for the given `args`.
+fn output_columns(args: &Args) -> Vec<Column> {
+    let mut columns = vec![
+        Column::new("client", ColumnType::Integer),
+        Column::new("available", ColumnType::Decimal),
+        Column::new("held", ColumnType::Decimal),
+        Column::new("total", ColumnType::Decimal),
+        Column::new("locked", ColumnType::Boolean),
+    ];
+    if args.with_netflow {
+        for name in ["deposited", "withdrawn", "net_flow"] {
+            columns.push(Column::new(name, ColumnType::Decimal));
+        }
+    }
+    if args.with_peak_balance {
+        columns.push(Column::new("peak_available", ColumnType::Decimal));
+    }
+    if args.flag_held_ratio.is_some() {
+        columns.push(Column::new("held_ratio_flagged", ColumnType::Boolean));
+    }
+    for scenario in &args.scenarios {
+        for name in ["available", "held", "total"] {
+            columns.push(Column::new(
+                format!("{}_{}", name, scenario.as_str()),
+                ColumnType::Decimal,
+            ));
+        }
+        columns.push(Column::new(
+            format!("locked_{}", scenario.as_str()),
+            ColumnType::Boolean,
+        ));
+    }
+    columns
+}
+
+/// Processes the transactions read from `input` and gives the final balances to `sink`
+/// instead of writing them in the format given in `args`.
+pub async fn run_with_sink(
+    args: &Args,
+    input: impl AsyncRead + Unpin + Send + 'static,
+    sink: &mut dyn OutputSink,
+) -> Result<(), Error> {
+    run_with_inputs(args, vec![Input::from_reader(args, input)], sink)
+        .await
+        .map(|_| ())
+}
+
+/// Number of transactions `process_stream` groups together for each worker.
+const STREAM_BATCH_SIZE: usize = 16;
+
+/// Processes every transaction of `stream` with `num_workers` workers and returns the final
+/// state of every client, in no particular order. Nothing is parsed or written, so this is
+/// meant for embedding the engine with transactions that were already deserialized. Rejected
+/// transactions are only reported on stderr, see `process_stream_with_config` to collect them.
+pub async fn process_stream<S: Stream<Item = Transaction>>(
+    stream: S,
+    num_workers: usize,
+) -> Result<Vec<ClientState>, Error> {
+    let results =
+        process_stream_with_config(stream, num_workers, ProcessingConfig::default()).await?;
+    Ok(results.client_states.into())
+}
+
+/// Same as `process_stream`, but every worker uses `config` and everything the workers
+/// recorded is returned. With `collect_rejections` set, every rejected transaction is in the
+/// results along with its position in `stream` (starting at 1) as its row number.
+pub async fn process_stream_with_config<S: Stream<Item = Transaction>>(
+    stream: S,
+    num_workers: usize,
+    config: ProcessingConfig,
+) -> Result<ProcessingResults, Error> {
+    let account_manager = AccountManager::with_config(num_workers, config);
+    let mut batcher = account_manager.batcher(STREAM_BATCH_SIZE);
+    let transactions = stream.enumerate();
+    pin_mut!(transactions);
+    while let Some((index, transaction)) = transactions.next().await {
+        batcher
+            .process_transaction_from_row(index + 1, transaction)
+            .await?;
+    }
+    batcher.flush().await?;
+    drop(batcher);
+    account_manager.collect_results().await
+}
+
+/// Rows of a single input that could not be parsed and the clients it referenced.
+#[derive(Default)]
+struct InputSummary {
+    name: String,
+    /// Size of the input in bytes, if it was known.
+    bytes: Option<u64>,
+    /// Every row read, including the ones that could not be parsed.
+    rows_read: usize,
+    parse_rejections: Vec<Rejection>,
+    /// Only recorded with `--disjoint-clients`.
+    clients: HashSet<ClientId>,
+    /// Transactions skipped because their type is not in `--types`.
+    skipped_by_type: usize,
+    /// Only recorded with `--global-tx-uniqueness`. Client of the first deposit or withdrawal
+    /// with each tx id.
+    tx_clients: HashMap<TxId, ClientId>,
+    /// Only recorded with `--global-tx-uniqueness`. Every later deposit or withdrawal reusing
+    /// the tx id of one from another client.
+    reused_tx_ids: Vec<(TxId, ClientId)>,
+}
+
+/// Bytes given to the csv reader of every input, validated from `args`.
+#[derive(Clone, Copy)]
+struct CsvDialect {
+    delimiter: u8,
+    comment: Option<u8>,
+}
+
+impl CsvDialect {
+    /// Fails if `--delimiter` or `--comment-char` is not a single ASCII character.
+    fn from_args(args: &Args) -> Result<Self, Error> {
+        if !args.delimiter.is_ascii() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Delimiter '{}' must be a single ASCII character",
+                    args.delimiter
+                ),
+            ));
+        }
+        let comment = match args.comment_char {
+            Some(c) if !c.is_ascii() => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Comment character '{}' must be a single ASCII character", c),
+                ));
+            }
+            Some(c) => Some(c as u8),
+            None => None,
+        };
+        Ok(Self {
+            delimiter: args.delimiter as u8,
+            comment,
+        })
+    }
+}
+
+/// Parses `input` and sends every transaction in it to `account_manager`.
+async fn read_input(
+    args: &Args,
+    dialect: CsvDialect,
+    input: Input,
+    account_manager: &AccountManager,
+    checkpoints: Option<&Mutex<Checkpoints>>,
+) -> Result<InputSummary, Error> {
+    let (name, bytes) = (input.name, input.size_hint);
+    let mut input = BufReader::new(input.reader);
+    if args.require_metadata {
+        FileMetadata::parse(&read_first_line(&mut input).await?)?
+            .check(args.precision, args.currency.as_deref())?;
+    }
+    let mut reader = CsvAsyncReaderBuilder::new()
+        .flexible(true)
+        .has_headers(!args.no_header)
+        .delimiter(dialect.delimiter)
+        .comment(dialect.comment)
+        // Exports often pad fields after the delimiter, eg: `deposit, 1, 1, 1.0`.
+        .trim(Trim::All)
+        // Treats `\r`, `\n` and `\r\n` as line endings, so files exported with old Mac
+        // (`\r` only) line endings are not read as a single giant row.
+        .terminator(Terminator::CRLF)
+        // Sadly, tokio's AsyncRead and Future's AsyncRead are not compatible, so we use
+        // tokio_util::compat library to build our compatibility layer.
+        .create_reader(input.compat());
+    let headers = if args.no_header {
+        None
+    } else {
+        Some(
+            reader
+                .headers()
+                .await
+                .map_err(|e| make_other_err!("Could not read header : {}", e))?
+                .clone(),
+        )
+    };
+    let (mut rows, reader_handle) = spawn_transaction_reader(
+        reader,
+        ReaderOptions {
+            headers,
+            // The metadata line was consumed before the csv reader saw the input.
+            skipped_lines: usize::from(args.require_metadata),
+            skip_blank_lines: args.skip_blank_lines,
+            max_decimal_digits: args.max_decimal_digits,
+            max_scale: args.max_scale.map(i64::from),
+        },
+    );
+    let mut batcher = account_manager.batcher(args.batch_size);
+    let mut summary = InputSummary {
+        name,
+        bytes,
+        rows_read: 0,
+        parse_rejections: Vec::new(),
+        clients: HashSet::new(),
+        skipped_by_type: 0,
+        tx_clients: HashMap::new(),
+        reused_tx_ids: Vec::new(),
+    };
+    let progress_every = args.progress.then(|| args.progress_every.max(1));
+    while let Some(chunk) = rows.recv().await {
+        let rows_before = summary.rows_read;
+        summary.rows_read += chunk.len();
+        if let Some(every) = progress_every {
+            if summary.rows_read / every != rows_before / every {
+                eprintln!("Read {} rows of '{}'", summary.rows_read, summary.name);
+            }
+        }
+        for row in chunk {
+            match row {
+                ParsedRow::Transaction {
+                    row_number,
+                    transaction,
+                } => {
+                    if !args.types.is_empty() && !args.types.contains(&transaction.transaction_type)
+                    {
+                        summary.skipped_by_type += 1;
+                        continue;
+                    }
+                    if args.disjoint_clients {
+                        summary.clients.insert(transaction.client);
+                    }
+                    if args.global_tx_uniqueness
+                        && matches!(
+                            transaction.transaction_type,
+                            TransactionType::Deposit | TransactionType::Withdrawal
+                        )
+                    {
+                        match summary.tx_clients.entry(transaction.tx) {
+                            Entry::Vacant(entry) => {
+                                entry.insert(transaction.client);
+                            }
+                            Entry::Occupied(entry) if *entry.get() != transaction.client => {
+                                summary
+                                    .reused_tx_ids
+                                    .push((transaction.tx, transaction.client));
+                            }
+                            Entry::Occupied(_) => {}
+                        }
+                    }
+                    let tx = transaction.tx;
+                    batcher
+                        .process_transaction_from_row(row_number, transaction)
+                        .await?;
+                    if let Some(checkpoints) = checkpoints {
+                        let mut checkpoints = checkpoints.lock().await;
+                        if checkpoints.pending.remove(&tx) {
+                            batcher.flush().await?;
+                            write_checkpoint(
+                                &mut checkpoints.file,
+                                tx,
+                                account_manager,
+                                args.output_rounding(),
+                            )
+                            .await?;
+                        }
+                    }
+                }
+                ParsedRow::Invalid {
+                    row_number,
+                    mut error,
+                } if args.strict => {
+                    error
+                        .messages
+                        .insert(0, format!("Could not parse line {}", row_number));
+                    error.kind = ErrorKind::InvalidData;
+                    return Err(error.with_rejection_category(RejectionCategory::Parse));
+                }
+                ParsedRow::Invalid { row_number, error } => {
+                    eprintln!(
+                        "Could not parse line {} due to error {:?}",
+                        row_number, error
+                    );
+                    if args.collects_rejections() {
+                        summary.parse_rejections.push(Rejection {
+                            row_number: Some(row_number),
+                            category: RejectionCategory::Parse,
+                            message: error.messages.join(" : "),
+                            transaction: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    reader_handle.await?;
+    batcher.flush().await?;
+    Ok(summary)
+}
+
+/// The `--checkpoint-at` tx ids that did not get their checkpoint yet and the file checkpoints
+/// are written to. Shared by every input, so a tx id only gets a checkpoint at its first row
+/// across all of them.
+struct Checkpoints {
+    pending: HashSet<TxId>,
+    file: fs::File,
+}
+
+impl Checkpoints {
+    /// Creates `--checkpoints-file` with its header, if it was given.
+    async fn from_args(args: &Args) -> Result<Option<Mutex<Self>>, Error> {
+        let path = match &args.checkpoints_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let mut file = fs::File::create(path).await?;
+        file.write_all(b"checkpoint,client,available,held,total,locked\n")
+            .await?;
+        Ok(Some(Mutex::new(Self {
+            pending: args.checkpoint_at.iter().copied().collect(),
+            file,
+        })))
+    }
+}
+
+/// Appends the current balance of every client to `file`, labeled with `tx`, rounded the
+/// same way as the output.
+async fn write_checkpoint(
+    file: &mut fs::File,
+    tx: TxId,
+    account_manager: &AccountManager,
+    rounding: OutputRounding,
+) -> Result<(), Error> {
+    let precision = rounding.precision;
+    let mut rows = String::new();
+    for balance in account_manager.snapshot_balances(rounding).await? {
+        rows.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            tx,
+            balance.client,
+            format_amount(&balance.available, precision, OutputFormat::Csv),
+            format_amount(&balance.held, precision, OutputFormat::Csv),
+            format_amount(&balance.total, precision, OutputFormat::Csv),
+            balance.locked
+        ));
+    }
+    file.write_all(rows.as_bytes()).await?;
+    Ok(())
+}
+
+/// Fails if a client appears in more than one input, which breaks the promise made with
+/// `--disjoint-clients`.
+fn check_disjoint_clients(summaries: &[InputSummary]) -> Result<(), Error> {
+    let mut input_for_client = HashMap::<ClientId, &str>::new();
+    for summary in summaries {
+        let mut clients: Vec<ClientId> = summary.clients.iter().copied().collect();
+        clients.sort_unstable();
+        for client in clients {
+            if let Some(other) = input_for_client.insert(client, &summary.name) {
                 return Err(Error::new(
-                    ErrorKind::NotFound,
+                    ErrorKind::InvalidData,
                     format!(
-                        "Error, could not open file: '{}', error '{}'",
-                        args.transactions_file, e
+                        "Client {} appears in both '{}' and '{}', but clients must be disjoint",
+                        client, other, summary.name
                     ),
                 ));
             }
+        }
+    }
+    Ok(())
+}
+
+/// Returns every tx id used by the deposits or withdrawals of more than one client, with all
+/// of those clients. The result does not depend on the order the inputs were read in.
+fn reused_tx_ids(summaries: &mut [InputSummary]) -> BTreeMap<TxId, BTreeSet<ClientId>> {
+    let mut reused = BTreeMap::<TxId, BTreeSet<ClientId>>::new();
+    let mut record =
+        |tx_clients: &mut HashMap<TxId, ClientId>, tx, client| match tx_clients.entry(tx) {
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+            }
+            Entry::Occupied(entry) if *entry.get() != client => {
+                reused
+                    .entry(tx)
+                    .or_insert_with(|| BTreeSet::from([*entry.get()]))
+                    .insert(client);
+            }
+            Entry::Occupied(_) => {}
         };
+    let mut tx_clients = HashMap::new();
+    for (index, summary) in summaries.iter_mut().enumerate() {
+        let input_tx_clients = std::mem::take(&mut summary.tx_clients);
+        if index == 0 {
+            // Start from the map of the first input rather than copying it.
+            tx_clients = input_tx_clients;
+        } else {
+            for (tx, client) in input_tx_clients {
+                record(&mut tx_clients, tx, client);
+            }
+        }
+        for (tx, client) in summary.reused_tx_ids.drain(..) {
+            record(&mut tx_clients, tx, client);
+        }
+    }
+    reused
+}
 
-        // TODO(allada): Use std::thread::available_parallelism() instead of num_cpus::get() when
-        // it is on stable long enough.
-        let worker_threads = env::var_os("ACCOUNT_WORKER_SPAWNS").map_or(get_num_cpus(), |v| {
-            v.into_string()
-                .expect("Could not convert OsString to String. Probably UTF8 error.")
-                .parse::<usize>()
-                .expect("Could not convert ACCOUNT_WORKER_SPAWNS env to usize")
-        });
-        let reader = CsvAsyncReaderBuilder::new()
-            .flexible(true)
-            // Sadly, tokio's AsyncRead and Future's AsyncRead are not compatible, so we use
-            // tokio_util::compat library to build our compatibility layer.
-            .create_deserializer(file.compat());
-        (AccountManager::new(worker_threads), reader)
+/// Same as `run_with_sink`, but every input is read into the same workers. Inputs are read one
+/// after the other in order, unless `--disjoint-clients` promises that no client appears in
+/// more than one of them, in which case they are read concurrently.
+async fn run_with_inputs(
+    args: &Args,
+    inputs: Vec<Input>,
+    sink: &mut dyn OutputSink,
+) -> Result<RunSummary, Error> {
+    let (account_manager, dialect, workers) = {
+        // Setup and configure our classes and utilities.
+
+        let worker_threads = match (args.workers, env::var_os("ACCOUNT_WORKER_SPAWNS")) {
+            (Some(workers), _) => check_worker_count(workers)?,
+            // An explicit worker count always wins over the input size heuristic.
+            (None, Some(workers)) => resolve_worker_count(Some(workers), args.max_workers)?,
+            (None, None) => workers_for_input_size(
+                resolve_worker_count(None, args.max_workers)?,
+                inputs.iter().map(|input| input.size_hint).sum(),
+            ),
+        };
+        eprintln!("Using {} workers", worker_threads);
+        let dialect = CsvDialect::from_args(args)?;
+        let config = args.processing_config()?;
+        let initial_states = match &args.resume {
+            Some(snapshot_path) => load_snapshot(snapshot_path).await?,
+            None => Vec::new(),
+        };
+        (
+            AccountManager::try_with_initial_states(worker_threads, config, initial_states)?,
+            dialect,
+            worker_threads,
+        )
     };
 
-    let account_states = {
+    let checkpoints = Checkpoints::from_args(args).await?;
+    let (mut account_states, summary) = {
         // Process our csv data.
-        let mut transaction_stream = reader.deserialize::<Transaction>();
-        let mut row_number = 1; // Start at 1 because header was in input, but not in transaction_stream.
-        while let Some(transaction_result) = transaction_stream.next().await {
-            let transaction = match transaction_result {
-                Ok(v) => v,
-                Err(err) => {
-                    eprintln!("Could not parse line {} due to error {:?}", row_number, err);
-                    continue;
-                }
-            };
-            account_manager.process_transaction(transaction).await?;
-            row_number += 1;
+        let mut summaries = if args.disjoint_clients {
+            let summaries = try_join_all(inputs.into_iter().map(|input| {
+                read_input(args, dialect, input, &account_manager, checkpoints.as_ref())
+            }))
+            .await?;
+            check_disjoint_clients(&summaries)?;
+            summaries
+        } else {
+            let mut summaries = Vec::with_capacity(inputs.len());
+            for input in inputs {
+                summaries.push(
+                    read_input(args, dialect, input, &account_manager, checkpoints.as_ref())
+                        .await?,
+                );
+            }
+            summaries
+        };
+        if let Some(checkpoints) = &checkpoints {
+            checkpoints.lock().await.file.flush().await?;
+        }
+        let reused_tx_ids = if args.global_tx_uniqueness {
+            reused_tx_ids(&mut summaries)
+        } else {
+            BTreeMap::new()
+        };
+        for (tx, clients) in &reused_tx_ids {
+            eprintln!(
+                "Warning: tx {} is used by the deposits or withdrawals of clients {:?}",
+                tx, clients
+            );
+        }
+        let skipped_by_type: usize = summaries
+            .iter()
+            .map(|summary| summary.skipped_by_type)
+            .sum();
+        if skipped_by_type > 0 {
+            eprintln!(
+                "Skipped {} transactions with a type not in --types",
+                skipped_by_type
+            );
+        }
+        let mut input_manifests = Vec::with_capacity(summaries.len());
+        let mut parse_rejections = Vec::new();
+        for summary in summaries {
+            input_manifests.push(InputManifest {
+                name: summary.name,
+                bytes: summary.bytes,
+                rows: summary.rows_read,
+            });
+            parse_rejections.extend(summary.parse_rejections);
+        }
+        let mut results = if sink.streams_workers() {
+            write_accounts_per_worker(args, account_manager, &parse_rejections, sink).await?
+        } else {
+            account_manager.collect_results().await?
+        };
+        for mismatch in &results.balance_mismatches {
+            eprintln!(
+                "Running balance mismatch on line {} for client {} (tx {}): expected {} but found {}",
+                mismatch
+                    .row_number
+                    .map_or_else(|| "?".to_string(), |v| v.to_string()),
+                mismatch.client,
+                mismatch.tx,
+                mismatch.expected,
+                mismatch.actual,
+            );
+        }
+        if args.worker_stats {
+            for stats in &results.worker_stats {
+                eprintln!(
+                    "Worker {}: clients={} transactions={} busy_time={:?}",
+                    stats.worker_index,
+                    stats.clients,
+                    stats.transactions_processed,
+                    stats.busy_time,
+                );
+            }
+        }
+        let parse_rejections_count = parse_rejections.len();
+        if args.warn_dangling_clients && !results.dangling_clients.is_empty() {
+            eprintln!(
+                "Warning: clients {:?} were only referenced by disputes, resolves or chargebacks of unknown transactions, they are not in the output",
+                results.dangling_clients
+            );
+        }
+        let mut rejections = parse_rejections;
+        rejections.append(&mut results.rejections);
+        rejections.sort_by_key(|rejection| rejection.row_number);
+        if let Some(rejections_path) = &args.rejections_file {
+            let report = format_rejections(&rejections, args.rejections_format)?;
+            fs::write(rejections_path, report).await?;
+        }
+        if !sink.streams_workers() {
+            sink.write_rejections(&rejections).await?;
+        }
+        let mut rows = RowCounts {
+            read: input_manifests.iter().map(|input| input.rows).sum(),
+            skipped_by_type,
+            ..Default::default()
+        };
+        for rejection in &rejections {
+            *rows
+                .rejected
+                .entry(rejection.category.as_str())
+                .or_default() += 1;
+        }
+        let (processed, redeliveries_skipped) =
+            results
+                .worker_stats
+                .iter()
+                .fold((0, 0), |(processed, skipped), stats| {
+                    (
+                        processed + stats.transactions_processed as usize,
+                        skipped + stats.redeliveries_skipped as usize,
+                    )
+                });
+        let worker_rejections = rejections.len() - parse_rejections_count;
+        rows.redeliveries_skipped = redeliveries_skipped;
+        rows.accepted = processed - worker_rejections - redeliveries_skipped;
+        let summary = RunSummary {
+            inputs: input_manifests,
+            workers,
+            rows,
+            reused_tx_ids,
+        };
+        if args.progress {
+            eprintln!(
+                "Processed {} rows of {} inputs, {} clients",
+                summary.rows.read,
+                summary.inputs.len(),
+                results.client_states.len()
+            );
         }
-        account_manager.collect_account_states().await?
+        (results.client_states, summary)
     };
+    if args.sort || args.atomic_output {
+        account_states
+            .make_contiguous()
+            .sort_unstable_by_key(|state| state.client);
+    }
+
+    if args.balance_quantiles {
+        match balance_quantiles(&account_states) {
+            Some(quantiles) => eprintln!(
+                "Balance quantiles over {} clients: min={} p50={} p90={} p99={} max={}",
+                quantiles.clients,
+                format_amount(&quantiles.min, args.output_precision(), OutputFormat::Csv),
+                format_amount(&quantiles.p50, args.output_precision(), OutputFormat::Csv),
+                format_amount(&quantiles.p90, args.output_precision(), OutputFormat::Csv),
+                format_amount(&quantiles.p99, args.output_precision(), OutputFormat::Csv),
+                format_amount(&quantiles.max, args.output_precision(), OutputFormat::Csv),
+            ),
+            None => eprintln!("Balance quantiles: no clients"),
+        }
+    }
+
+    if let Some(open_disputes_path) = &args.open_disputes {
+        let mut report = "client,tx,held\n".to_string();
+        for dispute in open_disputes(&account_states) {
+            report.push_str(&format!(
+                "{},{},{}\n",
+                dispute.client,
+                dispute.tx,
+                format_amount(&dispute.amount, args.output_precision(), OutputFormat::Csv)
+            ));
+        }
+        fs::write(open_disputes_path, report).await?;
+    }
+
+    if let Some(net_outflow_path) = &args.net_outflow {
+        let mut report = "client,net_outflow\n".to_string();
+        for outflow in net_outflows(&account_states) {
+            report.push_str(&format!(
+                "{},{}\n",
+                outflow.client,
+                format_amount(&outflow.amount, args.output_precision(), OutputFormat::Csv)
+            ));
+        }
+        fs::write(net_outflow_path, report).await?;
+    }
+
+    if args.verify_conservation {
+        verify_conservation(&account_states)?;
+    }
+
+    if args.verify {
+        let violations = account_violations(&account_states);
+        for violation in &violations {
+            eprintln!("{}", violation);
+        }
+        if !violations.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} account invariants were violated, first: {}",
+                    violations.len(),
+                    violations[0]
+                ),
+            ));
+        }
+    }
+
+    if args.reconcile {
+        let reconciliation = reconcile(&account_states, |state| {
+            let (available, held) = args.output_rounding().balances(state);
+            available + held
+        });
+        eprintln!(
+            "Reconciliation: deposited={} withdrawn={} charged_back={} expected_total={} output_total={}",
+            reconciliation.deposited,
+            reconciliation.withdrawn,
+            reconciliation.charged_back,
+            reconciliation.expected_total(),
+            reconciliation.output_total,
+        );
+        let discrepancy = reconciliation.discrepancy();
+        // Each of the two rounded columns of an account is off by less than one unit of the
+        // last decimal place.
+        let rounding_tolerance = BigDecimal::new(2.into(), args.output_precision())
+            * BigDecimal::from(account_states.len() as u64);
+        if discrepancy.abs() > rounding_tolerance {
+            return Err(make_other_err!(
+                "Reconciliation failed: output totals differ from deposited - withdrawn - charged back by {}",
+                discrepancy
+            ));
+        }
+    }
+
+    if let Some(kv_path) = &args.kv_out {
+        #[cfg(feature = "kv")]
+        kv_store::write_kv_store(kv_path, &account_states, args.output_rounding())?;
+        #[cfg(not(feature = "kv"))]
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Cannot write to '{}', built without the `kv` feature",
+                kv_path
+            ),
+        ));
+    }
+
+    if let Some(dump_path) = &args.dump_final_state {
+        let dump = serde_json::to_vec_pretty(&account_states)
+            .map_err(|e| make_other_err!("Could not serialize final state : {}", e))?;
+        fs::write(dump_path, dump).await?;
+    }
 
     {
         // Print out final output.
-        writer
-            .write_all("client,available,held,total,locked\n".as_bytes())
-            .await?;
-        writer.flush().await?; // Be very mindful to flush on very write.
-        for account_state in account_states {
-            writer
-                .write_all(
-                    format!(
-                        "{},{},{},{},{}\n",
-                        &account_state.client,
-                        account_state.available.round(4),
-                        account_state.held.round(4),
-                        (account_state.available + account_state.held).round(4),
-                        &account_state.locked
-                    )
-                    .as_bytes(),
-                )
-                .await?;
-            writer.flush().await?; // Be very mindful to flush on very write.
+        let columns = output_columns(args);
+        if let Some(schema_path) = &args.emit_schema {
+            let schema = OutputSchema {
+                version: OUTPUT_FORMAT_VERSION,
+                columns: columns.clone(),
+            };
+            let schema = serde_json::to_vec_pretty(&schema)
+                .map_err(|e| make_other_err!("Could not serialize schema : {}", e))?;
+            fs::write(schema_path, schema).await?;
         }
+        let result = if sink.streams_workers() {
+            sink.finish().await
+        } else {
+            let header: Vec<String> = columns.into_iter().map(|column| column.name).collect();
+            write_accounts(args, &header, account_states, sink).await
+        };
+        match result {
+            // Whoever reads the output went away (eg: piped into `head`). Like other Unix tools
+            // this is a normal early exit, not a failure.
+            Err(err) if err.kind == ErrorKind::BrokenPipe => Ok(summary),
+            result => result.map(|()| summary),
+        }
+    }
+}
+
+/// Returns the cells of the output row of `account_state`, or `None` if it is left out of the
+/// output.
+fn account_row(args: &Args, account_state: &ClientState) -> Option<Vec<Cell>> {
+    if let Some(dust_threshold) = &args.dust_threshold {
+        let total = &account_state.available + &account_state.held;
+        if !account_state.locked && &total < dust_threshold {
+            return None;
+        }
+    }
+    let (available, held) = args.output_rounding().balances(account_state);
+    let mut cells = vec![
+        Cell::Client(account_state.client),
+        Cell::Amount(available.clone()),
+        Cell::Amount(held.clone()),
+        Cell::Amount(available + held),
+        Cell::Flag(account_state.locked),
+    ];
+    if args.with_netflow {
+        cells.extend([
+            Cell::Amount(account_state.deposited.clone()),
+            Cell::Amount(account_state.withdrawn.clone()),
+            Cell::Amount(&account_state.deposited - &account_state.withdrawn),
+        ]);
+    }
+    if args.with_peak_balance {
+        cells.push(Cell::Amount(account_state.peak_available.clone()));
+    }
+    if let Some(max_ratio) = &args.flag_held_ratio {
+        let flagged = account_state
+            .held_ratio()
+            .is_some_and(|ratio| &ratio > max_ratio);
+        cells.push(Cell::Flag(flagged));
+    }
+    for scenario in account_state.scenarios() {
+        cells.extend([
+            Cell::Amount(scenario.available.clone()),
+            Cell::Amount(scenario.held.clone()),
+            Cell::Amount(&scenario.available + &scenario.held),
+            Cell::Flag(scenario.locked),
+        ]);
+    }
+    Some(cells)
+}
+
+/// Formats chunks of rows on blocking threads while the chunks formatted before them are
+/// written, so formatting overlaps with writing. Chunks are written in order, so the output
+/// is the same as giving every row to `write_row`.
+async fn write_rows_in_parallel(
+    args: &Args,
+    account_states: VecDeque<ClientState>,
+    formatter: RowFormatter,
+    sink: &mut dyn OutputSink,
+) -> Result<(), Error> {
+    let max_in_flight = get_num_cpus().max(1) * 2;
+    let mut in_flight = VecDeque::with_capacity(max_in_flight);
+    let mut account_states = account_states.into_iter().peekable();
+    while account_states.peek().is_some() {
+        let rows: Vec<Vec<Cell>> = account_states
+            .by_ref()
+            .take(PARALLEL_FORMAT_CHUNK_ROWS)
+            .filter_map(|account_state| account_row(args, &account_state))
+            .collect();
+        let formatter = formatter.clone();
+        in_flight.push_back(tokio::task::spawn_blocking(move || {
+            let mut formatted = String::new();
+            for row in &rows {
+                formatter(row, &mut formatted);
+            }
+            formatted
+        }));
+        if in_flight.len() >= max_in_flight {
+            let formatted = in_flight.pop_front().unwrap().await?;
+            sink.write_formatted(&formatted).await?;
+        }
+    }
+    for chunk in in_flight {
+        sink.write_formatted(&chunk.await?).await?;
     }
     Ok(())
 }
+
+/// Gives the rows of each worker to `sink` as soon as that worker has finished, right after
+/// its rejections, and returns the combined results of every worker. The `parse_rejections`
+/// and the header are given first.
+async fn write_accounts_per_worker(
+    args: &Args,
+    account_manager: AccountManager,
+    parse_rejections: &[Rejection],
+    sink: &mut dyn OutputSink,
+) -> Result<ProcessingResults, Error> {
+    sink.write_rejections(parse_rejections).await?;
+    let header: Vec<String> = output_columns(args)
+        .into_iter()
+        .map(|column| column.name)
+        .collect();
+    sink.write_header(&header).await?;
+    let mut results = ProcessingResults::default();
+    let finished_workers = account_manager.finished_workers();
+    pin_mut!(finished_workers);
+    while let Some(worker_results) = finished_workers.next().await {
+        let mut worker_results = worker_results?;
+        worker_results
+            .rejections
+            .sort_by_key(|rejection| rejection.row_number);
+        sink.write_rejections(&worker_results.rejections).await?;
+        for account_state in &worker_results.client_states {
+            if let Some(cells) = account_row(args, account_state) {
+                sink.write_row(&cells).await?;
+            }
+        }
+        results.append(worker_results);
+    }
+    Ok(results)
+}
+
+/// Gives the header and a row per account to `sink`, formatted according to `args`.
+async fn write_accounts(
+    args: &Args,
+    header: &[String],
+    account_states: VecDeque<ClientState>,
+    sink: &mut dyn OutputSink,
+) -> Result<(), Error> {
+    let result = async {
+        sink.write_header(header).await?;
+        match sink.row_formatter() {
+            Some(formatter) if args.parallel_format => {
+                write_rows_in_parallel(args, account_states, formatter, sink).await
+            }
+            _ => {
+                for account_state in account_states {
+                    if let Some(cells) = account_row(args, &account_state) {
+                        sink.write_row(&cells).await?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+    .await;
+    if let Err(err) = result {
+        // Best effort, the original error is the one worth reporting.
+        let _ = sink.flush().await;
+        return Err(err);
+    }
+    sink.finish().await
+}
@@ -0,0 +1,218 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::collections::{BTreeSet, HashMap};
+
+use bigdecimal::{BigDecimal, Zero};
+use csv_async::{AsyncReaderBuilder as CsvAsyncReaderBuilder, Terminator, Trim};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::common::{ClientId, Transaction, TransactionType, TxId};
+use crate::reader::{spawn_transaction_reader, ParsedRow, ReaderOptions};
+use crate::{make_other_err, open_transactions_file, Error, ErrorKind};
+
+/// Deposits of a multiple of this amount are considered suspiciously round.
+const ROUND_AMOUNT: u32 = 1000;
+
+/// A client depositing the exact same amount at least this many times is reported.
+const DUPLICATE_AMOUNT_MIN_COUNT: usize = 3;
+
+/// Digit limit used while parsing, the same default as when processing.
+const MAX_DECIMAL_DIGITS: usize = 64;
+
+/// Kind of statistical oddity found by `lint`.
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// A client disputed a larger proportion of its deposits than allowed.
+    HighDisputeRate,
+    /// A deposit of a multiple of `ROUND_AMOUNT`.
+    RoundAmount,
+    /// A client deposited the exact same amount many times.
+    DuplicateAmount,
+    /// A deposit or withdrawal reused the tx id of an earlier one.
+    TxIdReuse,
+    /// Deposit and withdrawal tx ids skip over a range of ids.
+    TxIdGap,
+    /// A withdrawal of exactly the amount the client deposited right before it.
+    WithdrawalAfterMatchingDeposit,
+}
+
+/// A single finding of `lint`.
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// Row of the input the anomaly was found at, if it relates to a single row.
+    pub row_number: Option<usize>,
+    pub client: Option<ClientId>,
+    pub detail: String,
+}
+
+/// Running statistics of a single client.
+#[derive(Default)]
+struct ClientStats {
+    deposits: usize,
+    disputes: usize,
+    deposits_of_amount: HashMap<BigDecimal, usize>,
+    /// Amount of the last deposit, if the last deposit or withdrawal was a deposit.
+    last_deposit: Option<BigDecimal>,
+}
+
+/// Scans every transaction of `transactions_file` and writes each anomaly found as a JSON
+/// object per line to `writer`. These are heuristics meant to pre-screen a feed for fraud or
+/// problems with how it was produced, nothing is processed.
+pub async fn lint_transactions(
+    transactions_file: &str,
+    max_dispute_rate: f64,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let file = open_transactions_file(transactions_file).await?;
+    let mut reader = CsvAsyncReaderBuilder::new()
+        .flexible(true)
+        .trim(Trim::All)
+        .terminator(Terminator::CRLF)
+        .create_reader(file.compat());
+    let headers = reader
+        .headers()
+        .await
+        .map_err(|e| make_other_err!("Could not read header : {}", e))?
+        .clone();
+    let (mut rows, reader_handle) = spawn_transaction_reader(
+        reader,
+        ReaderOptions {
+            headers: Some(headers),
+            skipped_lines: 0,
+            skip_blank_lines: true,
+            max_decimal_digits: MAX_DECIMAL_DIGITS,
+            max_scale: None,
+        },
+    );
+
+    let mut anomalies = Vec::new();
+    let mut stats_for_client = HashMap::<ClientId, ClientStats>::new();
+    let mut row_for_tx = HashMap::<TxId, usize>::new();
+    let round_amount = BigDecimal::from(ROUND_AMOUNT);
+    while let Some(chunk) = rows.recv().await {
+        for row in chunk {
+            let (row_number, transaction) = match row {
+                ParsedRow::Transaction {
+                    row_number,
+                    transaction,
+                } => (row_number, transaction),
+                // Rows that cannot be parsed are already reported when processing.
+                ParsedRow::Invalid { .. } => continue,
+            };
+            let Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount,
+                ..
+            } = transaction;
+            let stats = stats_for_client.entry(client).or_default();
+            let mut anomaly = |kind, detail| {
+                anomalies.push(Anomaly {
+                    kind,
+                    row_number: Some(row_number),
+                    client: Some(client),
+                    detail,
+                })
+            };
+            match transaction_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    if let Some(first_row) = row_for_tx.insert(tx, row_number) {
+                        anomaly(
+                            AnomalyKind::TxIdReuse,
+                            format!("Tx {} was already used on line {}", tx, first_row),
+                        );
+                    }
+                }
+                TransactionType::Dispute => stats.disputes += 1,
+                _ => {}
+            }
+            let amount = match amount {
+                Some(amount) => amount,
+                None => continue,
+            };
+            match transaction_type {
+                TransactionType::Deposit => {
+                    stats.deposits += 1;
+                    if !amount.is_zero() && (&amount / &round_amount).is_integer() {
+                        anomaly(
+                            AnomalyKind::RoundAmount,
+                            format!("Deposit of a round amount {}", amount.normalized()),
+                        );
+                    }
+                    let count = stats.deposits_of_amount.entry(amount.clone()).or_default();
+                    *count += 1;
+                    if *count == DUPLICATE_AMOUNT_MIN_COUNT {
+                        anomaly(
+                            AnomalyKind::DuplicateAmount,
+                            format!(
+                                "Deposited {} {} times",
+                                amount.normalized(),
+                                DUPLICATE_AMOUNT_MIN_COUNT
+                            ),
+                        );
+                    }
+                    stats.last_deposit = Some(amount);
+                }
+                TransactionType::Withdrawal => {
+                    let last_deposit = stats.last_deposit.take();
+                    if last_deposit.as_ref() == Some(&amount) {
+                        anomaly(
+                            AnomalyKind::WithdrawalAfterMatchingDeposit,
+                            format!(
+                                "Withdrawal of {} right after a deposit of the same amount",
+                                amount.normalized()
+                            ),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    reader_handle.await?;
+
+    let mut clients: Vec<(&ClientId, &ClientStats)> = stats_for_client.iter().collect();
+    clients.sort_unstable_by_key(|(client, _)| **client);
+    for (client, stats) in clients {
+        let dispute_rate = stats.disputes as f64 / stats.deposits.max(1) as f64;
+        if stats.disputes > 0 && dispute_rate > max_dispute_rate {
+            anomalies.push(Anomaly {
+                kind: AnomalyKind::HighDisputeRate,
+                row_number: None,
+                client: Some(*client),
+                detail: format!(
+                    "{} disputes for {} deposits",
+                    stats.disputes, stats.deposits
+                ),
+            });
+        }
+    }
+
+    let tx_ids: BTreeSet<TxId> = row_for_tx.keys().copied().collect();
+    let mut tx_ids = tx_ids.into_iter();
+    if let Some(mut previous) = tx_ids.next() {
+        for tx in tx_ids {
+            if tx - previous > 1 {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::TxIdGap,
+                    row_number: None,
+                    client: None,
+                    detail: format!("No tx ids between {} and {}", previous, tx),
+                });
+            }
+            previous = tx;
+        }
+    }
+
+    for anomaly in anomalies {
+        let line = serde_json::to_string(&anomaly).map_err(|e| make_other_err!("{}", e))?;
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
@@ -8,5 +8,8 @@ use account_balance_calculator::{run_with_args, Args};
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    run_with_args(args, stdout()).await.unwrap();
+    if let Err(err) = run_with_args(args, stdout()).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,123 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+
+use crate::common::{ClientId, TxId};
+use crate::Args;
+
+/// Offset basis and prime of the 64 bit FNV-1a hash.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Summary of a run written with `--manifest-out`.
+#[derive(Serialize)]
+pub struct RunManifest<'a> {
+    /// Milliseconds since the unix epoch when the run started.
+    pub started_at_ms: u128,
+    /// Milliseconds since the unix epoch when the output was fully written.
+    pub finished_at_ms: u128,
+    pub inputs: Vec<InputManifest>,
+    pub workers: usize,
+    pub rows: RowCounts,
+    /// Tx ids used by the deposits or withdrawals of more than one client, with those
+    /// clients. Only filled with `--global-tx-uniqueness`.
+    pub reused_tx_ids: BTreeMap<TxId, BTreeSet<ClientId>>,
+    /// Every command line option, including the ones left to their default.
+    pub options: &'a Args,
+    /// FNV-1a hash of the bytes of the output, as 16 hex digits. It is meant to tell whether
+    /// two runs wrote the same output, not to protect against tampering.
+    pub output_fingerprint: String,
+}
+
+/// An input of the run.
+#[derive(Serialize)]
+pub struct InputManifest {
+    pub name: String,
+    /// Size of the input in bytes, `None` if it was not known (eg: stdin or compressed inputs).
+    pub bytes: Option<u64>,
+    /// Rows read from the input, including the ones that could not be parsed.
+    pub rows: usize,
+}
+
+/// What happened to the rows read from every input.
+#[derive(Serialize, Default)]
+pub struct RowCounts {
+    pub read: usize,
+    /// Transactions skipped because their type is not in `--types`.
+    pub skipped_by_type: usize,
+    /// Deposits skipped as redeliveries of an earlier deposit (see `--on-deposit-collision`).
+    pub redeliveries_skipped: usize,
+    /// Transactions the workers processed without rejecting them.
+    pub accepted: usize,
+    /// Number of rejected rows for every rejection category that happened at least once.
+    pub rejected: BTreeMap<&'static str, usize>,
+}
+
+/// Everything `run_with_inputs` knows about a run that ends up in the manifest.
+pub struct RunSummary {
+    pub inputs: Vec<InputManifest>,
+    pub workers: usize,
+    pub rows: RowCounts,
+    pub reused_tx_ids: BTreeMap<TxId, BTreeSet<ClientId>>,
+}
+
+/// Returns the milliseconds elapsed since the unix epoch.
+pub fn unix_time_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis())
+}
+
+/// Passes everything written to it through to `inner` while hashing it.
+pub struct FingerprintWriter<W> {
+    inner: W,
+    hash: u64,
+}
+
+impl<W> FingerprintWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hash: FNV_OFFSET_BASIS,
+        }
+    }
+
+    /// Returns the hash of everything written so far as 16 hex digits.
+    pub fn fingerprint(&self) -> String {
+        format!("{:016x}", self.hash)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FingerprintWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            for byte in &buf[..written] {
+                this.hash = (this.hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::collections::HashMap;
+
+use bigdecimal::BigDecimal;
+use csv_async::AsyncReaderBuilder as CsvAsyncReaderBuilder;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::common::ClientId;
+use crate::output::{format_amount, OutputFormat};
+use crate::{Error, ErrorKind};
+
+/// A single row of a previously written output file.
+#[derive(Deserialize, Debug)]
+struct OutputRow {
+    client: ClientId,
+    available: BigDecimal,
+    held: BigDecimal,
+    locked: bool,
+}
+
+/// Reads several output files (usually produced by different machines each processing a
+/// shard of the clients) and writes a single consolidated output to `writer`.
+///
+/// Shards are expected to be partitioned by client, so a client appearing in more than one
+/// file is an error. Amounts are rounded to `precision` decimal places, which should be the
+/// `--precision` the shards were written with.
+pub async fn merge_outputs(
+    output_files: &[String],
+    precision: i64,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let mut file_for_client = HashMap::<ClientId, &str>::new();
+    let mut rows = Vec::new();
+    for output_file in output_files {
+        let file = fs::File::open(output_file).await.map_err(|e| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Error, could not open file: '{}', error '{}'",
+                    output_file, e
+                ),
+            )
+        })?;
+        let mut reader = CsvAsyncReaderBuilder::new()
+            .flexible(true)
+            .create_deserializer(file.compat());
+        let mut row_stream = reader.deserialize::<OutputRow>();
+        while let Some(row_result) = row_stream.next().await {
+            let row = row_result.map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Could not parse row in '{}' : {}", output_file, e),
+                )
+            })?;
+            if let Some(other_file) = file_for_client.insert(row.client, output_file) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Client {} appears in both '{}' and '{}', shards must not overlap",
+                        row.client, other_file, output_file
+                    ),
+                ));
+            }
+            rows.push(row);
+        }
+    }
+
+    writer
+        .write_all("client,available,held,total,locked\n".as_bytes())
+        .await?;
+    for row in rows {
+        writer
+            .write_all(
+                format!(
+                    "{},{},{},{},{}\n",
+                    row.client,
+                    format_amount(&row.available, precision, OutputFormat::Csv),
+                    format_amount(&row.held, precision, OutputFormat::Csv),
+                    format_amount(&(&row.available + &row.held), precision, OutputFormat::Csv),
+                    row.locked
+                )
+                .as_bytes(),
+            )
+            .await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::{Error, ErrorKind};
+
+/// Assumptions a file was produced under, declared in a leading comment line such as
+/// `# precision=4 currency=USD`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct FileMetadata {
+    /// Number of decimal places of the amounts.
+    pub precision: u32,
+    pub currency: String,
+}
+
+impl FileMetadata {
+    /// Parses a metadata line. Unknown keys are ignored, but `precision` and `currency` are
+    /// required.
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let fields = line
+            .strip_prefix('#')
+            .ok_or_else(|| invalid(format!("Expected a metadata line but found '{}'", line)))?;
+        let mut precision = None;
+        let mut currency = None;
+        for field in fields.split_whitespace() {
+            let (key, value) = field.split_once('=').ok_or_else(|| {
+                invalid(format!(
+                    "Metadata field '{}' is not a key=value pair",
+                    field
+                ))
+            })?;
+            match key {
+                "precision" => {
+                    precision = Some(value.parse::<u32>().map_err(|e| {
+                        invalid(format!("Metadata precision '{}' is invalid : {}", value, e))
+                    })?);
+                }
+                "currency" => currency = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            precision: precision
+                .ok_or_else(|| invalid("Metadata line is missing precision".to_string()))?,
+            currency: currency
+                .ok_or_else(|| invalid("Metadata line is missing currency".to_string()))?,
+        })
+    }
+
+    /// Ensures the file was produced with the given `precision` and (if set) `currency`.
+    pub fn check(&self, precision: u32, currency: Option<&str>) -> Result<(), Error> {
+        if self.precision != precision {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "File declares precision {} but precision {} is expected",
+                    self.precision, precision
+                ),
+            ));
+        }
+        if let Some(currency) = currency {
+            if self.currency != currency {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "File declares currency {} but currency {} is expected",
+                        self.currency, currency
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the first line of `reader`, which may end with `\n`, `\r\n` or a lone `\r`.
+pub async fn read_first_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<String, Error> {
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0; 1];
+        if reader.read(&mut byte).await? == 0 {
+            break; // EOF.
+        }
+        match byte[0] {
+            b'\n' => break,
+            b'\r' => {
+                if reader.fill_buf().await?.first() == Some(&b'\n') {
+                    reader.consume(1);
+                }
+                break;
+            }
+            byte => line.push(byte),
+        }
+    }
+    String::from_utf8(line).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Metadata line is not valid utf8 : {}", e),
+        )
+    })
+}
@@ -0,0 +1,137 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::output::OutputFormat;
+use crate::sink::{Cell, OutputSink};
+use crate::{make_other_err, Error, ErrorKind};
+
+/// ISO 4217 code for "no currency", our amounts are not tied to any.
+const CURRENCY: &str = "XXX";
+
+/// Writes a minimal OFX 2 document holding one bank statement per account. Each statement
+/// only has the account id (the client), the ledger balance (`total`) and the available
+/// balance (`available`), every other column is dropped since OFX has no place for it.
+pub struct OfxSink<W> {
+    writer: W,
+    /// Position of the `client`, `available` and `total` columns in every row.
+    columns: Option<(usize, usize, usize)>,
+    /// Time the balances are reported as of, in OFX's `YYYYMMDDHHMMSS` format.
+    as_of: String,
+    /// Number of decimal places amounts are rounded to.
+    precision: i64,
+}
+
+impl<W> OfxSink<W> {
+    pub fn new(writer: W, precision: i64) -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        Self {
+            writer,
+            columns: None,
+            as_of: format_ofx_datetime(seconds),
+            precision,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: AsyncWrite + Unpin> OutputSink for OfxSink<W> {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        let position = |name: &str| {
+            header
+                .iter()
+                .position(|column| column == name)
+                .ok_or_else(|| make_other_err!("OFX output needs a '{}' column", name))
+        };
+        self.columns = Some((
+            position("client")?,
+            position("available")?,
+            position("total")?,
+        ));
+        let document_start = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n",
+                "<?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" ",
+                "OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n",
+                "<OFX>\n",
+                "<SIGNONMSGSRSV1><SONRS>",
+                "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>",
+                "<DTSERVER>{}</DTSERVER><LANGUAGE>ENG</LANGUAGE>",
+                "</SONRS></SIGNONMSGSRSV1>\n",
+                "<BANKMSGSRSV1>\n",
+            ),
+            self.as_of
+        );
+        self.writer.write_all(document_start.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        let (client, available, total) = self
+            .columns
+            .ok_or_else(|| make_other_err!("OFX header must be written before any row"))?;
+        let format = |index: usize| {
+            row.get(index)
+                .map(|cell| cell.format(OutputFormat::Ofx, self.precision))
+                .ok_or_else(|| make_other_err!("OFX row is missing column {}", index))
+        };
+        let client = format(client)?;
+        let statement = format!(
+            concat!(
+                "<STMTTRNRS><TRNUID>{client}</TRNUID>",
+                "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>",
+                "<STMTRS><CURDEF>{currency}</CURDEF>",
+                "<BANKACCTFROM><BANKID>0</BANKID><ACCTID>{client}</ACCTID>",
+                "<ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>",
+                "<LEDGERBAL><BALAMT>{total}</BALAMT><DTASOF>{as_of}</DTASOF></LEDGERBAL>",
+                "<AVAILBAL><BALAMT>{available}</BALAMT><DTASOF>{as_of}</DTASOF></AVAILBAL>",
+                "</STMTRS></STMTTRNRS>\n",
+            ),
+            client = client,
+            currency = CURRENCY,
+            total = format(total)?,
+            available = format(available)?,
+            as_of = self.as_of,
+        );
+        self.writer.write_all(statement.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        self.writer.write_all(b"</BANKMSGSRSV1>\n</OFX>\n").await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Formats seconds since the unix epoch (UTC) as `YYYYMMDDHHMMSS`.
+fn format_ofx_datetime(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let seconds_of_day = seconds % 86_400;
+    // Converts days since the epoch to a proleptic Gregorian date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
@@ -0,0 +1,216 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use bigdecimal::{BigDecimal, Signed};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::account_manager::ClientState;
+use crate::common::ClientId;
+
+/// Number of decimal places amounts are rounded to in the output, unless another precision is
+/// given (eg: with `--precision`).
+pub const OUTPUT_PRECISION: i64 = 4;
+
+/// Largest number of decimal places amounts can be rounded to in the output.
+pub const MAX_OUTPUT_PRECISION: u32 = 28;
+
+/// Version of the output layout described by `OutputSchema`. Must be bumped whenever columns
+/// are added, removed, renamed or change type.
+pub const OUTPUT_FORMAT_VERSION: u32 = 1;
+
+/// Layout of the final output.
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Comma separated values.
+    Csv,
+    /// Human readable table with aligned columns. The whole output is buffered in memory to
+    /// compute the column widths, so this is not suitable for a very large number of clients.
+    Table,
+    /// Array with an object per account, keyed by column name. Amounts are strings so no
+    /// precision is lost by consumers parsing them as floats.
+    Json,
+    /// Minimal OFX 2 statement per account, for importing into accounting software. Only
+    /// available when built with the `ofx` feature.
+    Ofx,
+}
+
+/// How an amount is rounded to the output precision.
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// To the nearest value, ties away from zero.
+    #[default]
+    HalfUp,
+    /// Towards zero (truncate).
+    Down,
+    /// Away from zero.
+    Up,
+}
+
+/// Rounds `amount` to `precision` decimal places using `mode`. Amounts that already have at
+/// most `precision` decimal places are returned unchanged.
+pub fn round_amount(amount: &BigDecimal, precision: i64, mode: RoundingMode) -> BigDecimal {
+    let (_, scale) = amount.as_bigint_and_exponent();
+    if scale <= precision {
+        return amount.clone();
+    }
+    match mode {
+        RoundingMode::HalfUp => amount.round(precision),
+        // `with_scale` drops the extra digits, which truncates towards zero.
+        RoundingMode::Down => amount.with_scale(precision),
+        RoundingMode::Up => {
+            let truncated = amount.with_scale(precision);
+            if &truncated == amount {
+                truncated
+            } else if amount.is_negative() {
+                truncated - BigDecimal::new(1.into(), precision)
+            } else {
+                truncated + BigDecimal::new(1.into(), precision)
+            }
+        }
+    }
+}
+
+/// How the `available` and `held` amounts are rounded in the output (eg: with `--precision`,
+/// `--rounding-available` and `--rounding-held`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputRounding {
+    pub precision: i64,
+    pub available: RoundingMode,
+    pub held: RoundingMode,
+}
+
+impl Default for OutputRounding {
+    fn default() -> Self {
+        Self {
+            precision: OUTPUT_PRECISION,
+            available: RoundingMode::default(),
+            held: RoundingMode::default(),
+        }
+    }
+}
+
+impl OutputRounding {
+    /// Returns the `available` and `held` balances of `state` rounded the way they are
+    /// written.
+    pub fn balances(&self, state: &ClientState) -> (BigDecimal, BigDecimal) {
+        (
+            round_amount(&state.available, self.precision, self.available),
+            round_amount(&state.held, self.precision, self.held),
+        )
+    }
+}
+
+/// Type of the values of an output column.
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    /// Unsigned integer (the client id).
+    Integer,
+    /// Decimal number with up to `--precision` decimal places.
+    Decimal,
+    /// `true` or `false`.
+    Boolean,
+}
+
+/// A named output column.
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, column_type: ColumnType) -> Self {
+        Self {
+            name: name.into(),
+            column_type,
+        }
+    }
+}
+
+/// Machine readable description of the output, so consumers can validate the layout they
+/// are given.
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub struct OutputSchema {
+    pub version: u32,
+    pub columns: Vec<Column>,
+}
+
+/// Serializable view of the final balance of an account, rounded the same way as the csv
+/// output.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct AccountBalance {
+    pub client: ClientId,
+    pub available: BigDecimal,
+    pub held: BigDecimal,
+    pub total: BigDecimal,
+    pub locked: bool,
+}
+
+impl AccountBalance {
+    /// Balance of `state` with amounts rounded according to `rounding`. Like in the csv
+    /// output, `total` is the sum of the rounded `available` and `held`.
+    pub fn with_rounding(state: &ClientState, rounding: OutputRounding) -> Self {
+        let (available, held) = rounding.balances(state);
+        Self {
+            client: state.client,
+            total: &available + &held,
+            available,
+            held,
+            locked: state.locked,
+        }
+    }
+}
+
+impl From<&ClientState> for AccountBalance {
+    fn from(state: &ClientState) -> Self {
+        Self::with_rounding(state, OutputRounding::default())
+    }
+}
+
+/// Formats an amount rounded to `precision` decimal places for the given output format.
+/// Tables always show every decimal place so the values line up on the decimal point.
+pub fn format_amount(amount: &BigDecimal, precision: i64, format: OutputFormat) -> String {
+    let rounded = amount.round(precision);
+    match format {
+        OutputFormat::Csv | OutputFormat::Json | OutputFormat::Ofx => rounded.to_string(),
+        OutputFormat::Table => rounded.with_scale(precision).to_string(),
+    }
+}
+
+/// Formats a flag (eg: `locked`) for the given output format.
+pub fn format_bool(value: bool, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Csv | OutputFormat::Json | OutputFormat::Ofx => value.to_string(),
+        OutputFormat::Table => if value { "yes" } else { "no" }.to_string(),
+    }
+}
+
+/// Renders the rows (the first of which is the header) as a table where every column is
+/// right-justified to the width of its widest cell.
+pub fn render_table(rows: &[Vec<String>]) -> String {
+    let mut widths = Vec::<usize>::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            if i < widths.len() {
+                widths[i] = widths[i].max(cell.len());
+            } else {
+                widths.push(cell.len());
+            }
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        table.push_str(&cells.join("  "));
+        table.push('\n');
+    }
+    table
+}
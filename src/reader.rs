@@ -0,0 +1,105 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use csv_async::{AsyncReader, StringRecord};
+use futures::io::AsyncRead;
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::common::Transaction;
+use crate::{make_other_err, Error, ErrorKind};
+
+/// Number of parsed rows sent to the dispatch loop at once.
+const PREFETCH_CHUNK_SIZE: usize = 256;
+
+/// Number of chunks the reader may parse ahead of the dispatch loop before it has to wait.
+const PREFETCH_CHUNKS: usize = 4;
+
+/// A row of the input as produced by the reader task.
+pub enum ParsedRow {
+    Transaction {
+        row_number: usize,
+        transaction: Transaction,
+    },
+    Invalid {
+        row_number: usize,
+        error: Error,
+    },
+}
+
+/// How the reader task turns records into `ParsedRow`s.
+pub struct ReaderOptions {
+    /// Header used to map columns by name, `None` to map them by position.
+    pub headers: Option<StringRecord>,
+    /// Lines consumed from the input before it was given to the csv reader.
+    pub skipped_lines: usize,
+    pub skip_blank_lines: bool,
+    /// Amounts with more digits are rejected.
+    pub max_decimal_digits: usize,
+    /// Amounts with more decimal places are rejected, if given.
+    pub max_scale: Option<i64>,
+}
+
+/// Spawns a task that reads and deserializes every record of `reader`, so that disk reads and
+/// parsing overlap with dispatching the transactions to the workers. Rows are received in the
+/// same order as they appear in the input.
+pub fn spawn_transaction_reader<R>(
+    reader: AsyncReader<R>,
+    options: ReaderOptions,
+) -> (mpsc::Receiver<Vec<ParsedRow>>, JoinHandle<()>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(PREFETCH_CHUNKS);
+    let handle = tokio::spawn(async move {
+        let mut record_stream = reader.into_records();
+        // Start at 1 because header was in input, but not in record_stream.
+        let mut row_number = options.skipped_lines + usize::from(options.headers.is_some());
+        let mut chunk = Vec::with_capacity(PREFETCH_CHUNK_SIZE);
+        while let Some(record_result) = record_stream.next().await {
+            row_number += 1;
+            let record = match record_result {
+                Ok(record) => record,
+                Err(err) => {
+                    chunk.push(ParsedRow::Invalid {
+                        row_number,
+                        error: make_other_err!("{}", err),
+                    });
+                    continue;
+                }
+            };
+            // Comment lines are not returned as records, so prefer the reader's line number.
+            if let Some(position) = record.position() {
+                row_number = options.skipped_lines + position.line() as usize;
+            }
+            if options.skip_blank_lines && record.iter().all(|field| field.trim().is_empty()) {
+                continue;
+            }
+            chunk.push(
+                match Transaction::from_record(
+                    &record,
+                    options.headers.as_ref(),
+                    options.max_decimal_digits,
+                    options.max_scale,
+                ) {
+                    Ok(transaction) => ParsedRow::Transaction {
+                        row_number,
+                        transaction,
+                    },
+                    Err(error) => ParsedRow::Invalid { row_number, error },
+                },
+            );
+            if chunk.len() >= PREFETCH_CHUNK_SIZE {
+                let full_chunk =
+                    std::mem::replace(&mut chunk, Vec::with_capacity(PREFETCH_CHUNK_SIZE));
+                if tx.send(full_chunk).await.is_err() {
+                    return; // Nobody is listening anymore.
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = tx.send(chunk).await;
+        }
+    });
+    (rx, handle)
+}
@@ -0,0 +1,108 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use clap::ArgEnum;
+use serde::Serialize;
+
+use crate::common::Transaction;
+use crate::{make_other_err, Error, ErrorKind};
+
+/// Broad reason a row of the input did not change any account.
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionCategory {
+    /// The row could not be parsed into a transaction.
+    Parse,
+    /// The account was closed by an earlier `close` transaction.
+    AccountClosed,
+    /// The account was locked by a chargeback.
+    AccountLocked,
+    /// A deposit or withdrawal reused the tx id of an earlier one.
+    DuplicateTx,
+    /// A deposit or withdrawal did not have an amount.
+    MissingAmount,
+    /// A deposit or withdrawal had an amount of zero or less.
+    InvalidAmount,
+    /// Not enough available (or held) funds to apply the transaction.
+    InsufficientFunds,
+    /// A dispute, resolve or chargeback referenced a tx that does not exist for the client.
+    UnknownTx,
+    /// A dispute, resolve or chargeback referenced a tx that is not in the right state.
+    InvalidTxState,
+    /// A `close` transaction for an account that still has funds or open disputes.
+    CannotClose,
+    /// The client already sent the maximum number of transactions allowed.
+    TransactionLimitExceeded,
+    /// The transaction used tx id 0, which is reserved with `--forbid-zero-tx`.
+    ZeroTx,
+}
+
+impl RejectionCategory {
+    /// Name of the category as it appears in the rejections file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionCategory::Parse => "parse",
+            RejectionCategory::AccountClosed => "account_closed",
+            RejectionCategory::AccountLocked => "account_locked",
+            RejectionCategory::DuplicateTx => "duplicate_tx",
+            RejectionCategory::MissingAmount => "missing_amount",
+            RejectionCategory::InvalidAmount => "invalid_amount",
+            RejectionCategory::InsufficientFunds => "insufficient_funds",
+            RejectionCategory::UnknownTx => "unknown_tx",
+            RejectionCategory::InvalidTxState => "invalid_tx_state",
+            RejectionCategory::CannotClose => "cannot_close",
+            RejectionCategory::TransactionLimitExceeded => "transaction_limit_exceeded",
+            RejectionCategory::ZeroTx => "zero_tx",
+        }
+    }
+}
+
+/// A row of the input that was rejected.
+#[derive(Serialize, Debug)]
+pub struct Rejection {
+    /// Row of the input the rejection came from (if known).
+    pub row_number: Option<usize>,
+    pub category: RejectionCategory,
+    pub message: String,
+    /// The parsed transaction, `None` if the row could not be parsed.
+    pub transaction: Option<Transaction>,
+}
+
+/// Layout of the rejections written with `--rejections-file`.
+#[derive(ArgEnum, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RejectionsFormat {
+    /// One human readable line per rejection.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Renders `rejections` in the given format, one rejection per line.
+pub fn format_rejections(
+    rejections: &[Rejection],
+    format: RejectionsFormat,
+) -> Result<String, Error> {
+    let mut report = String::new();
+    for rejection in rejections {
+        match format {
+            RejectionsFormat::Text => {
+                let row_number = rejection
+                    .row_number
+                    .map_or_else(|| "?".to_string(), |v| v.to_string());
+                report.push_str(&format!(
+                    "line {} [{}] {}",
+                    row_number,
+                    rejection.category.as_str(),
+                    rejection.message
+                ));
+            }
+            RejectionsFormat::Json => {
+                report.push_str(
+                    &serde_json::to_string(rejection).map_err(|e| make_other_err!("{}", e))?,
+                );
+            }
+        }
+        report.push('\n');
+    }
+    Ok(report)
+}
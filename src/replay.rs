@@ -0,0 +1,139 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::account_manager::{AccountManager, ProcessingConfig};
+use crate::common::Transaction;
+use crate::snapshot::load_snapshot;
+use crate::{make_other_err, Error, ErrorKind};
+
+/// Fields of a line of a `--rejections-format json` file that are needed to replay it.
+#[derive(Deserialize)]
+struct RejectedRow {
+    row_number: Option<usize>,
+    transaction: Option<Transaction>,
+}
+
+/// Re-applies every transaction of `rejections_file` (written with `--rejections-format
+/// json`) on top of the clients of `snapshot_path` (written with `--dump-final-state`), in
+/// the order they were rejected. Writes a csv line per replayed transaction to `writer` with
+/// `applied` or the category it was rejected with this time. Rows that could not be parsed
+/// have no transaction to replay and are skipped.
+///
+/// Transactions are judged with the per-client options of `config` (eg: the overdraft limit),
+/// which should be the ones of the original run.
+///
+/// If `dump_path` is given the states after the replay are written to it, in the same
+/// format as the snapshot, so later runs can resume from them.
+pub async fn replay_rejections(
+    rejections_file: &str,
+    snapshot_path: &str,
+    dump_path: Option<&str>,
+    config: ProcessingConfig,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<(), Error> {
+    let data = fs::read_to_string(rejections_file).await.map_err(|e| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Could not read rejections file '{}' : {}",
+                rejections_file, e
+            ),
+        )
+    })?;
+    let mut replayed = Vec::new();
+    let mut skipped = 0;
+    for (index, line) in data.lines().enumerate() {
+        let rejected_row: RejectedRow = serde_json::from_str(line).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Could not parse line {} of rejections file '{}', it must be written with --rejections-format json : {}",
+                    index + 1,
+                    rejections_file,
+                    e
+                ),
+            )
+        })?;
+        match rejected_row.transaction {
+            Some(transaction) => replayed.push((rejected_row.row_number, transaction)),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        eprintln!(
+            "Skipped {} rejected rows that could not be parsed, they have nothing to replay",
+            skipped
+        );
+    }
+
+    let account_manager = AccountManager::with_initial_states(
+        1,
+        ProcessingConfig {
+            collect_rejections: true,
+            // Rejections are printed below with their row in the original input.
+            quiet_rejections: true,
+            fail_on_rejection: false,
+            running_balance_tolerance: None,
+            rewind: None,
+            scenarios: Vec::new(),
+            ..config
+        },
+        load_snapshot(snapshot_path).await?,
+    );
+    // Rows are numbered by their position in `replayed`, so every rejection can be matched to
+    // its transaction even if the original row numbers repeat (eg: with `--input-dir`).
+    for (index, (_, transaction)) in replayed.iter().enumerate() {
+        account_manager
+            .process_transaction_from_row(index, transaction.clone())
+            .await?;
+    }
+    let results = account_manager.collect_results().await?;
+    for rejection in &results.rejections {
+        let row_number = rejection
+            .row_number
+            .and_then(|index| replayed.get(index))
+            .and_then(|(row_number, _)| *row_number);
+        match row_number {
+            Some(row_number) => eprintln!("line {}: {}", row_number, rejection.message),
+            None => eprintln!("{}", rejection.message),
+        }
+    }
+    let categories: HashMap<usize, &str> = results
+        .rejections
+        .iter()
+        .filter_map(|rejection| Some((rejection.row_number?, rejection.category.as_str())))
+        .collect();
+
+    let mut report = "row,type,client,tx,result\n".to_string();
+    for (index, (row_number, transaction)) in replayed.iter().enumerate() {
+        report.push_str(&format!(
+            "{},{},{},{},{}\n",
+            row_number.map_or_else(String::new, |row_number| row_number.to_string()),
+            transaction.transaction_type.as_str(),
+            transaction.client,
+            transaction.tx,
+            categories.get(&index).copied().unwrap_or("applied")
+        ));
+    }
+    eprintln!(
+        "Replayed {} transactions, {} were applied",
+        replayed.len(),
+        replayed.len() - categories.len()
+    );
+    writer.write_all(report.as_bytes()).await?;
+    writer.flush().await?;
+
+    if let Some(dump_path) = dump_path {
+        let mut client_states = Vec::from(results.client_states);
+        client_states.sort_unstable_by_key(|state| state.client);
+        let dump = serde_json::to_vec_pretty(&client_states)
+            .map_err(|e| make_other_err!("Could not serialize final state : {}", e))?;
+        fs::write(dump_path, dump).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use bigdecimal::BigDecimal;
+
+use crate::account_manager::ClientState;
+use crate::common::{ClientId, TxId};
+
+/// A transaction that is still under dispute once processing finished.
+#[derive(Debug, Eq, PartialEq)]
+pub struct OpenDispute {
+    pub client: ClientId,
+    pub tx: TxId,
+    /// Amount held because of the dispute.
+    pub amount: BigDecimal,
+}
+
+/// Returns every transaction still under dispute across all clients, sorted by client and tx.
+pub fn open_disputes<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+) -> Vec<OpenDispute> {
+    let mut disputes: Vec<OpenDispute> = client_states
+        .into_iter()
+        .flat_map(|state| {
+            state.open_disputes().map(|(tx, amount)| OpenDispute {
+                client: state.client,
+                tx,
+                amount: amount.clone(),
+            })
+        })
+        .collect();
+    disputes.sort_unstable_by_key(|dispute| (dispute.client, dispute.tx));
+    disputes
+}
+
+/// A client whose accepted withdrawals exceeded its accepted deposits.
+#[derive(Debug, Eq, PartialEq)]
+pub struct NetOutflow {
+    pub client: ClientId,
+    /// How much more was withdrawn than deposited, always positive.
+    pub amount: BigDecimal,
+}
+
+/// Returns every client that withdrew more than it deposited, sorted by client. Clients whose
+/// withdrawals exactly match their deposits did not drain any funds and are not included.
+/// Like the `net_flow` column, disputes and chargebacks are not taken into account.
+pub fn net_outflows<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+) -> Vec<NetOutflow> {
+    let mut outflows: Vec<NetOutflow> = client_states
+        .into_iter()
+        .filter(|state| state.withdrawn > state.deposited)
+        .map(|state| NetOutflow {
+            client: state.client,
+            amount: &state.withdrawn - &state.deposited,
+        })
+        .collect();
+    outflows.sort_unstable_by_key(|outflow| outflow.client);
+    outflows
+}
+
+/// Distribution of the clients' total (available + held) balances.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BalanceQuantiles {
+    pub clients: usize,
+    pub min: BigDecimal,
+    pub p50: BigDecimal,
+    pub p90: BigDecimal,
+    pub p99: BigDecimal,
+    pub max: BigDecimal,
+}
+
+/// Computes the quantiles of every client's total balance, or `None` if there are no clients.
+///
+/// The quantiles are exact (nearest-rank) because every total is collected and sorted. A
+/// streaming estimator would use less memory, but there are at most `ClientId::MAX + 1`
+/// clients, so keeping every total is cheap and avoids any approximation error.
+pub fn balance_quantiles<'a>(
+    client_states: impl IntoIterator<Item = &'a ClientState>,
+) -> Option<BalanceQuantiles> {
+    let mut totals: Vec<BigDecimal> = client_states
+        .into_iter()
+        .map(|state| &state.available + &state.held)
+        .collect();
+    if totals.is_empty() {
+        return None;
+    }
+    totals.sort_unstable();
+    // Nearest-rank method: the smallest total that at least `percent`% of totals are <= to.
+    let quantile = |percent: usize| {
+        let rank = (percent * totals.len()).div_ceil(100);
+        totals[rank.max(1) - 1].clone()
+    };
+    Some(BalanceQuantiles {
+        clients: totals.len(),
+        min: totals[0].clone(),
+        p50: quantile(50),
+        p90: quantile(90),
+        p99: quantile(99),
+        max: totals[totals.len() - 1].clone(),
+    })
+}
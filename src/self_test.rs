@@ -0,0 +1,80 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use clap::Parser;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{make_other_err, run_with_reader, Args, Error, ErrorKind};
+
+/// A bundled input file along with the output it is known to produce.
+struct Fixture {
+    name: &'static str,
+    input: &'static str,
+    /// Expected output lines, excluding the header and sorted (since ordering is undefined).
+    expected_rows: &'static [&'static str],
+}
+
+const OUTPUT_HEADER: &str = "client,available,held,total,locked";
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "provided_sample_input.csv",
+        input: include_str!("tests/data/provided_sample_input.csv"),
+        expected_rows: &["1,1.5000,0,1.5000,false", "2,2.0000,0,2.0000,false"],
+    },
+    Fixture {
+        name: "generated_sample_input.csv",
+        input: include_str!("tests/data/generated_sample_input.csv"),
+        expected_rows: &[
+            "1,2.4900,0,2.4900,false",
+            "2,0.0012,0,0.0012,false",
+            "3,10.0000,0.0000,10.0000,true",
+            "4,2.2222,3.3333,5.5555,false",
+        ],
+    },
+];
+
+/// Runs every bundled fixture through the normal processing path (in memory) and compares
+/// the output against the known good results. A pass/fail line is written to `writer` for
+/// each fixture followed by a summary. Returns an error if any fixture failed.
+pub async fn run_self_test(mut writer: impl AsyncWrite + Unpin) -> Result<(), Error> {
+    let mut num_passed = 0;
+    for fixture in FIXTURES {
+        let args = Args::parse_from(["account_balance_calculator", fixture.name]);
+        let mut output = Vec::new();
+        let result = run_with_reader(&args, fixture.input.as_bytes(), &mut output).await;
+        let status = match result {
+            Ok(()) => {
+                let output = String::from_utf8_lossy(&output);
+                let mut lines: Vec<&str> = output.lines().collect();
+                if lines.first() == Some(&OUTPUT_HEADER) {
+                    lines[1..].sort_unstable();
+                    if lines[1..] == *fixture.expected_rows {
+                        "PASS".to_string()
+                    } else {
+                        format!("FAIL (unexpected output: {:?})", lines)
+                    }
+                } else {
+                    format!("FAIL (unexpected header: {:?})", lines.first())
+                }
+            }
+            Err(err) => format!("FAIL ({})", err),
+        };
+        if status == "PASS" {
+            num_passed += 1;
+        }
+        writer
+            .write_all(format!("{} {}\n", status, fixture.name).as_bytes())
+            .await?;
+    }
+    writer
+        .write_all(format!("{}/{} self tests passed\n", num_passed, FIXTURES.len()).as_bytes())
+        .await?;
+    writer.flush().await?;
+    if num_passed != FIXTURES.len() {
+        return Err(make_other_err!(
+            "{} self tests failed",
+            FIXTURES.len() - num_passed
+        ));
+    }
+    Ok(())
+}
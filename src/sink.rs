@@ -0,0 +1,278 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bigdecimal::BigDecimal;
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::common::ClientId;
+use crate::output::{format_amount, format_bool, render_table, OutputFormat};
+use crate::rejections::Rejection;
+use crate::{Error, ErrorKind};
+
+/// A single value of an output row. Values are kept typed so every sink can format them the
+/// way its destination expects.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Cell {
+    Client(ClientId),
+    Amount(BigDecimal),
+    Flag(bool),
+}
+
+impl Cell {
+    /// Formats the value the same way as the given output format, with amounts rounded to
+    /// `precision` decimal places.
+    pub fn format(&self, format: OutputFormat, precision: i64) -> String {
+        match self {
+            Cell::Client(client) => client.to_string(),
+            Cell::Amount(amount) => format_amount(amount, precision, format),
+            Cell::Flag(value) => format_bool(*value, format),
+        }
+    }
+}
+
+/// Appends a row to the buffer, formatted exactly like the sink it came from would write it.
+pub type RowFormatter = Arc<dyn Fn(&[Cell], &mut String) + Send + Sync>;
+
+/// Destination of the final account balances. `write_header` is called once before any
+/// rows, `write_row` once per account and `finish` once all accounts were written. If
+/// writing the rows fails `flush` is called instead of `finish`.
+#[async_trait(?Send)]
+pub trait OutputSink {
+    /// Writes the names of the columns of every row.
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error>;
+
+    /// Writes the values of a single account, in the same order as the header.
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error>;
+
+    /// Called once every row was written. Sinks that buffer must write everything here.
+    async fn finish(&mut self) -> Result<(), Error>;
+
+    /// Writes whatever is buffered, without ending the output. Used to keep the rows that
+    /// were already given when writing the rest of them failed.
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called with every rejected row, ordered by row, before the header is written. Only
+    /// sinks that report back to whoever submitted the transactions need to write them.
+    /// With `streams_workers` it is instead called with the rows that could not be parsed
+    /// before the header, then with the rejections of each worker right before its rows.
+    async fn write_rejections(&mut self, _rejections: &[Rejection]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the rows of each worker are given as soon as that worker has finished, rather
+    /// than once every worker has. The rows are then never sorted.
+    fn streams_workers(&self) -> bool {
+        false
+    }
+
+    /// Returns how this sink formats a row, if rows can be formatted elsewhere (eg: on other
+    /// threads) and then given to `write_formatted` instead of `write_row`.
+    fn row_formatter(&self) -> Option<RowFormatter> {
+        None
+    }
+
+    /// Writes rows formatted by the `row_formatter` of this sink, in the order given.
+    async fn write_formatted(&mut self, _rows: &str) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This sink cannot write pre-formatted rows",
+        ))
+    }
+}
+
+/// Returns the sink that writes the given format to `writer`, with amounts rounded to
+/// `precision` decimal places. Fails if the format needs a feature this was built without.
+pub fn sink_for_format<'a, W: AsyncWrite + Unpin + 'a>(
+    format: OutputFormat,
+    precision: i64,
+    writer: W,
+) -> Result<Box<dyn OutputSink + 'a>, Error> {
+    Ok(match format {
+        OutputFormat::Csv => Box::new(CsvSink {
+            writer: BufWriter::new(writer),
+            precision,
+        }),
+        OutputFormat::Table => Box::new(TableSink {
+            writer,
+            precision,
+            rows: Vec::new(),
+        }),
+        OutputFormat::Json => Box::new(JsonSink {
+            writer: BufWriter::new(writer),
+            precision,
+            header: Vec::new(),
+            rows_written: 0,
+        }),
+        #[cfg(feature = "ofx")]
+        OutputFormat::Ofx => Box::new(crate::ofx::OfxSink::new(writer, precision)),
+        #[cfg(not(feature = "ofx"))]
+        OutputFormat::Ofx => {
+            return Err(Error::new(
+                crate::ErrorKind::Unsupported,
+                "Cannot write OFX, built without the `ofx` feature",
+            ))
+        }
+    })
+}
+
+/// Writes every row as a line of comma separated values. Rows are buffered and only
+/// flushed by `finish`.
+pub struct CsvSink<W: AsyncWrite> {
+    writer: BufWriter<W>,
+    precision: i64,
+}
+
+impl<W: AsyncWrite + Unpin> CsvSink<W> {
+    async fn write_str(&mut self, data: &str) -> Result<(), Error> {
+        self.writer.write_all(data.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Appends `row` to `line` as comma separated values followed by a newline.
+fn format_csv_row(row: &[Cell], precision: i64, line: &mut String) {
+    for (i, cell) in row.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push_str(&cell.format(OutputFormat::Csv, precision));
+    }
+    line.push('\n');
+}
+
+#[async_trait(?Send)]
+impl<W: AsyncWrite + Unpin> OutputSink for CsvSink<W> {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        self.write_str(&format!("{}\n", header.join(","))).await
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        let mut line = String::new();
+        format_csv_row(row, self.precision, &mut line);
+        self.write_str(&line).await
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    fn row_formatter(&self) -> Option<RowFormatter> {
+        let precision = self.precision;
+        Some(Arc::new(move |row, line| {
+            format_csv_row(row, precision, line)
+        }))
+    }
+
+    async fn write_formatted(&mut self, rows: &str) -> Result<(), Error> {
+        self.write_str(rows).await
+    }
+}
+
+/// Writes a human readable table. Tables need every row to compute the column widths, so
+/// nothing is written until `finish`.
+pub struct TableSink<W> {
+    writer: W,
+    precision: i64,
+    rows: Vec<Vec<String>>,
+}
+
+#[async_trait(?Send)]
+impl<W: AsyncWrite + Unpin> OutputSink for TableSink<W> {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        self.rows.push(header.to_vec());
+        Ok(())
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        self.rows.push(
+            row.iter()
+                .map(|cell| cell.format(OutputFormat::Table, self.precision))
+                .collect(),
+        );
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        self.writer
+            .write_all(render_table(&self.rows).as_bytes())
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Formats `row` as a JSON object keyed by `header`. Built by hand instead of with a `Map`,
+/// which would sort the keys, so the fields are in the same order as the columns of the
+/// other formats.
+pub(crate) fn format_json_object(header: &[String], row: &[Cell], precision: i64) -> String {
+    let mut fields = Vec::with_capacity(row.len());
+    for (name, cell) in header.iter().zip(row) {
+        let value = match cell {
+            Cell::Client(client) => Value::from(*client),
+            Cell::Flag(value) => Value::from(*value),
+            // Amounts are strings so they keep every decimal place.
+            Cell::Amount(_) => Value::from(cell.format(OutputFormat::Json, precision)),
+        };
+        fields.push(format!("{}:{}", Value::from(name.as_str()), value));
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Writes a JSON array with an object per row, keyed by the header. Objects are buffered
+/// and written out as the buffer fills, so memory does not grow with the number of rows.
+pub struct JsonSink<W: AsyncWrite> {
+    writer: BufWriter<W>,
+    precision: i64,
+    header: Vec<String>,
+    rows_written: usize,
+}
+
+#[async_trait(?Send)]
+impl<W: AsyncWrite + Unpin> OutputSink for JsonSink<W> {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        self.header = header.to_vec();
+        self.writer.write_all(b"[").await?;
+        Ok(())
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        let separator = if self.rows_written == 0 { "\n" } else { ",\n" };
+        self.rows_written += 1;
+        self.writer
+            .write_all(
+                format!(
+                    "{}{}",
+                    separator,
+                    format_json_object(&self.header, row, self.precision)
+                )
+                .as_bytes(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        let end = if self.rows_written == 0 {
+            "]\n"
+        } else {
+            "\n]\n"
+        };
+        self.writer.write_all(end.as_bytes()).await?;
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
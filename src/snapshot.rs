@@ -0,0 +1,43 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::collections::HashSet;
+
+use tokio::fs;
+
+use crate::account_manager::ClientState;
+use crate::{Error, ErrorKind};
+
+/// Loads the client states written by `--dump-final-state` so a later run can resume from
+/// them.
+///
+/// Unlike the shards given to `merge`, the clients of a snapshot are expected to also appear
+/// in the new transactions and are combined by applying those transactions on top of the
+/// snapshot. A client appearing twice in the snapshot itself is still an error, since there
+/// is no way to know which of the states is the right one.
+pub async fn load_snapshot(path: &str) -> Result<Vec<ClientState>, Error> {
+    let data = fs::read(path).await.map_err(|e| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Could not read snapshot '{}' : {}", path, e),
+        )
+    })?;
+    let states: Vec<ClientState> = serde_json::from_slice(&data).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Could not parse snapshot '{}' : {}", path, e),
+        )
+    })?;
+    let mut seen_clients = HashSet::with_capacity(states.len());
+    for state in &states {
+        if !seen_clients.insert(state.client) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Client {} appears more than once in snapshot '{}'",
+                    state.client, path
+                ),
+            ));
+        }
+    }
+    Ok(states)
+}
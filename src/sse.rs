@@ -0,0 +1,114 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::rejections::Rejection;
+use crate::sink::{format_json_object, Cell, OutputSink};
+use crate::{make_other_err, run_with_sink, Args, Error, ErrorKind};
+
+/// Waits for a single HTTP client to `GET` any path on `listener`, then processes the
+/// transactions from `input` and streams every account to the client as a Server-Sent
+/// Event named `account` whose data is a JSON object of the output columns, in column order.
+/// Every rejected row is sent as a `rejection` event, with the same JSON as
+/// `--rejections-format json` (eg: a `category` of `account_closed` or `account_locked`). A
+/// final `done` event is sent once every account was written, then the connection is closed.
+///
+/// Each worker owns its own clients, so the accounts of a worker are sent as soon as it has
+/// finished, right after its rejections. Rows that could not be parsed are sent first.
+pub async fn serve_sse(
+    args: &Args,
+    input: impl AsyncRead + Unpin + Send + 'static,
+    listener: TcpListener,
+) -> Result<(), Error> {
+    eprintln!(
+        "Waiting for a client to connect on {}",
+        listener.local_addr()?
+    );
+    let (mut stream, _) = listener.accept().await?;
+    let request_line = read_request(&mut stream).await?;
+    if !request_line.starts_with("GET ") {
+        stream
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected a GET request but got '{}'", request_line),
+        ));
+    }
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    let mut sink = SseSink {
+        stream,
+        header: Vec::new(),
+        precision: args.output_precision(),
+    };
+    run_with_sink(args, input, &mut sink).await
+}
+
+/// Reads the request head (request line and headers) and returns the request line. Any body
+/// is ignored.
+async fn read_request(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line.trim_end().to_string())
+}
+
+/// Writes every row as an SSE event as soon as it is given.
+struct SseSink {
+    stream: TcpStream,
+    header: Vec<String>,
+    precision: i64,
+}
+
+impl SseSink {
+    async fn write_event(&mut self, event: &str, data: &str) -> Result<(), Error> {
+        self.stream
+            .write_all(format!("event: {}\ndata: {}\n\n", event, data).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl OutputSink for SseSink {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        self.header = header.to_vec();
+        Ok(())
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        let data = format_json_object(&self.header, row, self.precision);
+        self.write_event("account", &data).await
+    }
+
+    async fn write_rejections(&mut self, rejections: &[Rejection]) -> Result<(), Error> {
+        for rejection in rejections {
+            let data = serde_json::to_string(rejection).map_err(|e| make_other_err!("{}", e))?;
+            self.write_event("rejection", &data).await?;
+        }
+        Ok(())
+    }
+
+    fn streams_workers(&self) -> bool {
+        true
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        self.write_event("done", "{}").await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
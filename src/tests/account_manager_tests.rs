@@ -1,9 +1,21 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
-use crate::account_manager::ClientState;
+// The tests sort accounts with `sort_unstable_by` comparing clients, which newer clippy
+// versions flag in favor of `sort_unstable_by_key`.
+#![allow(clippy::unnecessary_sort_by)]
+
+use crate::account_manager::{
+    rejection_log_line, BalanceMismatch, ClientState, DecimalContext, DepositCollisionPolicy,
+    DisputePolicy, ProcessingConfig, ProcessingResults, RouteBy,
+};
 use crate::common::{Transaction, TransactionType};
-use crate::{AccountManager, Error};
+use crate::invariants::{account_violations, reconcile, verify_conservation, Reconciliation};
+use crate::output::{AccountBalance, OutputRounding, RoundingMode};
+use crate::rejections::RejectionCategory;
+use crate::reports::{balance_quantiles, open_disputes, BalanceQuantiles, OpenDispute};
+use crate::{AccountManager, Error, ErrorKind};
 
+use bigdecimal::BigDecimal;
 // Gives easier to read output for assert errors.
 use pretty_assertions::assert_eq;
 
@@ -18,6 +30,7 @@ async fn simple_deposit_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -52,6 +65,7 @@ async fn simple_withdrawal_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((2).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -60,6 +74,7 @@ async fn simple_withdrawal_test() -> Result<(), Error> {
             client: 1,
             tx: 2,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -94,6 +109,7 @@ async fn simple_dispute_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -102,6 +118,7 @@ async fn simple_dispute_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -136,6 +153,7 @@ async fn dispute_with_resolve_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -144,6 +162,7 @@ async fn dispute_with_resolve_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -152,6 +171,7 @@ async fn dispute_with_resolve_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -186,6 +206,7 @@ async fn dispute_with_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -194,6 +215,7 @@ async fn dispute_with_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -202,6 +224,7 @@ async fn dispute_with_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -236,6 +259,7 @@ async fn deposit_duplicate_tx_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -244,6 +268,7 @@ async fn deposit_duplicate_tx_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -278,6 +303,7 @@ async fn chargeback_prevents_withdrawals_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -286,6 +312,7 @@ async fn chargeback_prevents_withdrawals_test() -> Result<(), Error> {
             client: 1,
             tx: 2,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -294,6 +321,7 @@ async fn chargeback_prevents_withdrawals_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -302,6 +330,7 @@ async fn chargeback_prevents_withdrawals_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -311,6 +340,7 @@ async fn chargeback_prevents_withdrawals_test() -> Result<(), Error> {
             client: 1,
             tx: 3,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -345,6 +375,7 @@ async fn allow_deposits_if_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -353,6 +384,7 @@ async fn allow_deposits_if_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 2,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -361,6 +393,7 @@ async fn allow_deposits_if_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -369,6 +402,7 @@ async fn allow_deposits_if_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -378,6 +412,7 @@ async fn allow_deposits_if_chargeback_test() -> Result<(), Error> {
             client: 1,
             tx: 3,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -412,6 +447,7 @@ async fn not_enough_funds_for_withdrawal_test() -> Result<(), Error> {
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -420,6 +456,7 @@ async fn not_enough_funds_for_withdrawal_test() -> Result<(), Error> {
             client: 1,
             tx: 2,
             amount: Some((2).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -454,6 +491,7 @@ async fn dispute_with_resolve_can_be_disputed_again_test() -> Result<(), Error>
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -462,6 +500,7 @@ async fn dispute_with_resolve_can_be_disputed_again_test() -> Result<(), Error>
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -470,6 +509,7 @@ async fn dispute_with_resolve_can_be_disputed_again_test() -> Result<(), Error>
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -478,6 +518,7 @@ async fn dispute_with_resolve_can_be_disputed_again_test() -> Result<(), Error>
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -512,6 +553,7 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -520,6 +562,7 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
             client: 1,
             tx: 2,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -528,6 +571,7 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
     account_manager
@@ -536,6 +580,7 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
             client: 1,
             tx: 1,
             amount: None,
+            expected_balance: None,
         })
         .await?;
 
@@ -545,6 +590,7 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
             client: 1,
             tx: 1,
             amount: Some((1).into()),
+            expected_balance: None,
         })
         .await?;
 
@@ -567,3 +613,1973 @@ async fn chargeback_does_not_allow_dispute_on_same_transaction_test() -> Result<
     );
     Ok(())
 }
+
+#[tokio::test]
+async fn running_balance_mismatch_is_reported_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            running_balance_tolerance: Some("0.0001".parse().unwrap()),
+            ..Default::default()
+        },
+    );
+
+    account_manager
+        .process_transaction_from_row(
+            2,
+            Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some((2).into()),
+                expected_balance: Some((2).into()),
+            },
+        )
+        .await?;
+    account_manager
+        .process_transaction_from_row(
+            3,
+            Transaction {
+                transaction_type: TransactionType::Withdrawal,
+                client: 1,
+                tx: 2,
+                amount: Some((1).into()),
+                expected_balance: Some("1.5".parse().unwrap()),
+            },
+        )
+        .await?;
+    account_manager
+        .process_transaction_from_row(
+            4,
+            Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                tx: 3,
+                amount: Some((1).into()),
+                // Within tolerance of the real balance (2).
+                expected_balance: Some("2.00001".parse().unwrap()),
+            },
+        )
+        .await?;
+
+    let results = account_manager.collect_results().await?;
+
+    assert_eq!(
+        results.balance_mismatches,
+        vec![BalanceMismatch {
+            row_number: Some(3),
+            client: 1,
+            tx: 2,
+            expected: "1.5".parse().unwrap(),
+            actual: (1).into(),
+        }]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn worker_stats_count_transactions_per_worker_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 2;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            route_by: RouteBy::Modulus,
+            ..Default::default()
+        },
+    );
+
+    // Clients 1 and 3 land on worker 1, client 2 lands on worker 0.
+    for (tx, client) in [(1, 1), (2, 2), (3, 3), (4, 1), (5, 3)] {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx,
+                amount: Some((1).into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let results = account_manager.collect_results().await?;
+
+    let counts: Vec<(usize, usize, u64)> = results
+        .worker_stats
+        .iter()
+        .map(|stats| {
+            (
+                stats.worker_index,
+                stats.clients,
+                stats.transactions_processed,
+            )
+        })
+        .collect();
+    assert_eq!(counts, vec![(0, 1, 1), (1, 2, 4)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn netflow_tracks_deposits_and_withdrawals_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 5),
+        (TransactionType::Deposit, 2, 3),
+        (TransactionType::Withdrawal, 3, 2),
+        (TransactionType::Withdrawal, 4, 1),
+        // Rejected, not enough funds so it must not count towards `withdrawn`.
+        (TransactionType::Withdrawal, 5, 100),
+    ];
+    for (transaction_type, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some((amount).into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+    account_manager
+        .process_transaction(Transaction {
+            transaction_type: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: None,
+            expected_balance: None,
+        })
+        .await?;
+
+    let account_states = account_manager.collect_account_states().await?;
+
+    assert_eq!(account_states[0].deposited, (8).into());
+    assert_eq!(account_states[0].withdrawn, (3).into());
+    // Disputes do not change the net flow, only the available balance.
+    assert_eq!(account_states[0].available, (0).into());
+    assert_eq!(account_states[0].held, (5).into());
+    Ok(())
+}
+
+#[test]
+fn round_on_store_keeps_balances_at_precision_test() {
+    const PRECISION: i64 = 4;
+    let mut state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    state.configure(&ProcessingConfig {
+        decimal_context: Some(DecimalContext {
+            scale: PRECISION,
+            rounding: RoundingMode::HalfUp,
+        }),
+        ..Default::default()
+    });
+    let make_transaction = |transaction_type, amount: Option<&str>| Transaction {
+        transaction_type,
+        client: 1,
+        tx: 1,
+        amount: amount.map(|amount| amount.parse().unwrap()),
+        expected_balance: None,
+    };
+    let scales = |state: &ClientState| {
+        (
+            state.available.as_bigint_and_exponent().1,
+            state.held.as_bigint_and_exponent().1,
+        )
+    };
+
+    state
+        .process(make_transaction(
+            TransactionType::Deposit,
+            Some("1.23456789"),
+        ))
+        .unwrap();
+    assert_eq!(scales(&state), (PRECISION, PRECISION));
+    assert_eq!(state.available, "1.2346".parse().unwrap());
+
+    state
+        .process(make_transaction(TransactionType::Dispute, None))
+        .unwrap();
+    assert_eq!(scales(&state), (PRECISION, PRECISION));
+    // The rounded amount is held, not the one given in the deposit.
+    assert_eq!(state.held, "1.2346".parse().unwrap());
+    assert_eq!(state.available, (0).into());
+
+    state
+        .process(make_transaction(TransactionType::Resolve, None))
+        .unwrap();
+    assert_eq!(scales(&state), (PRECISION, PRECISION));
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,                         /* client */
+            "1.2346".parse().unwrap(), /* available */
+            (0).into(),                /* held */
+            false,                     /* locked */
+        )
+    );
+}
+
+#[test]
+fn resolve_and_chargeback_on_withdrawal_are_rejected_test() {
+    let mut state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    let make_transaction = |transaction_type, tx, amount: Option<u32>| Transaction {
+        transaction_type,
+        client: 1,
+        tx,
+        amount: amount.map(Into::into),
+        expected_balance: None,
+    };
+    state
+        .process(make_transaction(TransactionType::Deposit, 1, Some(5)))
+        .unwrap();
+    state
+        .process(make_transaction(TransactionType::Withdrawal, 2, Some(2)))
+        .unwrap();
+
+    let dispute_err = state
+        .process(make_transaction(TransactionType::Dispute, 2, None))
+        .unwrap_err();
+    let resolve_err = state
+        .process(make_transaction(TransactionType::Resolve, 2, None))
+        .unwrap_err();
+    let chargeback_err = state
+        .process(make_transaction(TransactionType::Chargeback, 2, None))
+        .unwrap_err();
+
+    assert!(dispute_err.messages[0].starts_with("Cannot dispute a withdrawal"));
+    assert!(resolve_err.messages[0].starts_with("Cannot resolve a withdrawal"));
+    assert!(chargeback_err.messages[0].starts_with("Cannot chargeback a withdrawal"));
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,          /* client */
+            (3).into(), /* available */
+            (0).into(), /* held */
+            false,      /* locked */
+        )
+    );
+}
+
+#[test]
+fn replayed_resolves_and_chargebacks_never_make_held_negative_test() {
+    let mut state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    let make_transaction = |transaction_type, tx, amount: Option<u32>| Transaction {
+        transaction_type,
+        client: 1,
+        tx,
+        amount: amount.map(Into::into),
+        expected_balance: None,
+    };
+    let expect_rejected = |state: &mut ClientState, transaction_type, tx| {
+        let before = state.clone();
+        let err = state
+            .process(make_transaction(transaction_type, tx, None))
+            .unwrap_err();
+        assert_eq!(
+            err.rejection_category(),
+            Some(RejectionCategory::InvalidTxState)
+        );
+        // Rejected transactions leave the balances untouched.
+        assert_eq!(*state, before);
+        assert!(state.held >= (0).into());
+    };
+    state
+        .process(make_transaction(TransactionType::Deposit, 1, Some(5)))
+        .unwrap();
+    state
+        .process(make_transaction(TransactionType::Deposit, 2, Some(3)))
+        .unwrap();
+
+    state
+        .process(make_transaction(TransactionType::Dispute, 1, None))
+        .unwrap();
+    state
+        .process(make_transaction(TransactionType::Resolve, 1, None))
+        .unwrap();
+    // Tx 1 is back in the `Deposit` state, so neither a second resolve nor a chargeback of it
+    // is allowed.
+    expect_rejected(&mut state, TransactionType::Resolve, 1);
+    expect_rejected(&mut state, TransactionType::Chargeback, 1);
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,          /* client */
+            (8).into(), /* available */
+            (0).into(), /* held */
+            false,      /* locked */
+        )
+    );
+
+    state
+        .process(make_transaction(TransactionType::Dispute, 2, None))
+        .unwrap();
+    state
+        .process(make_transaction(TransactionType::Chargeback, 2, None))
+        .unwrap();
+    // A chargeback is final.
+    expect_rejected(&mut state, TransactionType::Chargeback, 2);
+    expect_rejected(&mut state, TransactionType::Resolve, 2);
+    expect_rejected(&mut state, TransactionType::Dispute, 2);
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,          /* client */
+            (5).into(), /* available */
+            (0).into(), /* held */
+            true,       /* locked */
+        )
+    );
+}
+
+#[test]
+fn close_account_lifecycle_test() {
+    let make_transaction = |transaction_type, tx, amount: Option<u32>| Transaction {
+        transaction_type,
+        client: 1,
+        tx,
+        amount: amount.map(Into::into),
+        expected_balance: None,
+    };
+
+    // A funded account cannot be closed.
+    let mut funded_state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    funded_state
+        .process(make_transaction(TransactionType::Deposit, 1, Some(5)))
+        .unwrap();
+    let err = funded_state
+        .process(make_transaction(TransactionType::CloseAccount, 2, None))
+        .unwrap_err();
+    assert!(err.messages[0].contains("still has funds"));
+    assert!(!funded_state.closed);
+
+    // An account with an open dispute cannot be closed, even if the amounts are zero. Zero
+    // deposits are rejected, so such a dispute can only come from an older snapshot.
+    let mut disputed_state: ClientState = serde_json::from_str(
+        r#"{"client":1,"available":"0","held":"0","locked":false,"closed":false,
+            "deposited":"0","withdrawn":"0","charged_back":"0",
+            "tx_for_transaction_state":{"1":["dispute","0"]}}"#,
+    )
+    .unwrap();
+    let err = disputed_state
+        .process(make_transaction(TransactionType::CloseAccount, 2, None))
+        .unwrap_err();
+    assert!(err.messages[0].contains("has open disputes"));
+    assert!(!disputed_state.closed);
+
+    // An empty account can be closed and nothing is processed after that.
+    let mut empty_state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    empty_state
+        .process(make_transaction(TransactionType::CloseAccount, 1, None))
+        .unwrap();
+    assert!(empty_state.closed);
+    let err = empty_state
+        .process(make_transaction(TransactionType::Deposit, 2, Some(1)))
+        .unwrap_err();
+    assert!(err.messages[0].starts_with("Account (1) is closed"));
+    assert_eq!(empty_state.available, (0).into());
+}
+
+#[tokio::test]
+async fn verify_conservation_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Deposit, 2, 2, Some(7)),
+        (TransactionType::Withdrawal, 1, 3, Some(3)),
+        (TransactionType::Deposit, 1, 4, Some(2)),
+        (TransactionType::Dispute, 1, 4, None),
+        (TransactionType::Chargeback, 1, 4, None),
+        (TransactionType::Dispute, 2, 2, None),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = account_manager.collect_account_states().await?;
+    verify_conservation(&account_states)?;
+
+    // Simulate a bug that creates funds out of nowhere.
+    account_states[0].held += BigDecimal::from(1);
+    let err = verify_conservation(&account_states).unwrap_err();
+    assert_eq!(
+        err.messages,
+        vec!["Conservation violated: accounts hold 15 but deposits - withdrawals - chargebacks is 14 (discrepancy 1)"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn account_violations_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 2;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            overdraft_limit: Some(BigDecimal::from(5)),
+            ..Default::default()
+        },
+    );
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Dispute, 1, 1, None),
+        (TransactionType::Deposit, 2, 2, Some(7)),
+        // Within the overdraft, so not a violation.
+        (TransactionType::Withdrawal, 2, 3, Some(11)),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = Vec::from(account_manager.collect_account_states().await?);
+    account_states.sort_unstable_by_key(|state| state.client);
+    assert_eq!(account_violations(&account_states), Vec::<String>::new());
+
+    // Simulate bugs that hold a negative amount and withdraw past the overdraft.
+    account_states[0].held = BigDecimal::from(-1);
+    account_states[0].available = BigDecimal::from(11);
+    account_states[1].available -= BigDecimal::from(2);
+    account_states[1].withdrawn += BigDecimal::from(2);
+    assert_eq!(
+        account_violations(&account_states),
+        vec![
+            "Client 1 holds a negative amount (-1)",
+            "Client 2 has -6 available, less than the minimum of -5",
+        ]
+    );
+
+    // Funds that appear out of nowhere break the total.
+    account_states[1].available += BigDecimal::from(7);
+    assert_eq!(
+        account_violations(&account_states),
+        vec![
+            "Client 1 holds a negative amount (-1)",
+            "Client 2 has a total of 1 but deposits - withdrawals - chargebacks is -6",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconcile_ties_input_sums_to_output_totals_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Deposit, 2, 2, Some(7)),
+        (TransactionType::Withdrawal, 1, 3, Some(3)),
+        (TransactionType::Deposit, 1, 4, Some(2)),
+        (TransactionType::Dispute, 1, 4, None),
+        (TransactionType::Chargeback, 1, 4, None),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = account_manager.collect_account_states().await?;
+    let total = |state: &ClientState| &state.available + &state.held;
+    let reconciliation = reconcile(&account_states, total);
+    assert_eq!(
+        reconciliation,
+        Reconciliation {
+            deposited: (19).into(),
+            withdrawn: (3).into(),
+            charged_back: (2).into(),
+            output_total: (14).into(),
+        }
+    );
+    assert_eq!(reconciliation.discrepancy(), (0).into());
+
+    // Simulate a bug that loses funds.
+    account_states[0].available -= BigDecimal::from(1);
+    let reconciliation = reconcile(&account_states, total);
+    assert_eq!(reconciliation.expected_total(), (14).into());
+    assert_eq!(reconciliation.discrepancy(), (-1).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn disputes_of_unseen_clients_do_not_create_them_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Dispute, 1, 1, None),
+        // Client 7 has never been seen, so the dispute references a tx that does not exist.
+        (TransactionType::Dispute, 7, 99, None),
+        (TransactionType::Chargeback, 7, 99, None),
+        // Client 8 is seen after the dispute, so it is not dangling.
+        (TransactionType::Resolve, 8, 2, None),
+        (TransactionType::Deposit, 8, 2, Some(5)),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let results = account_manager.collect_results().await?;
+    let mut clients: Vec<_> = results
+        .client_states
+        .iter()
+        .map(|state| state.client)
+        .collect();
+    clients.sort_unstable();
+    assert_eq!(clients, vec![1, 8]);
+    assert_eq!(results.dangling_clients, vec![7]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn rewind_all_applied_transactions_returns_accounts_to_zero_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    // Client 1 ends up with 7 applied transactions, which is the most of any client.
+    const APPLIED_TRANSACTIONS: usize = 7;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            rewind: Some(APPLIED_TRANSACTIONS),
+            ..Default::default()
+        },
+    );
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Deposit, 1, 2, Some(5)),
+        (TransactionType::Withdrawal, 1, 3, Some(3)),
+        // Rejected, so it is not part of the history.
+        (TransactionType::Withdrawal, 1, 4, Some(300)),
+        (TransactionType::Dispute, 1, 2, None),
+        (TransactionType::Resolve, 1, 2, None),
+        (TransactionType::Dispute, 1, 1, None),
+        (TransactionType::Chargeback, 1, 1, None),
+        (TransactionType::Deposit, 2, 5, Some(4)),
+        (TransactionType::Withdrawal, 2, 6, Some(1)),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = account_manager.collect_account_states().await?;
+    account_states
+        .make_contiguous()
+        .sort_unstable_by_key(|a| a.client);
+
+    for (client, account_state) in [1, 2].into_iter().zip(account_states) {
+        assert_eq!(
+            account_state,
+            ClientState::new(
+                client,     /* client */
+                (0).into(), /* available */
+                (0).into(), /* held */
+                false,      /* locked */
+            )
+        );
+        assert!(!account_state.has_transactions());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn open_disputes_lists_disputes_of_all_clients_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 1, Some(10)),
+        (TransactionType::Deposit, 1, 2, Some(5)),
+        (TransactionType::Deposit, 2, 3, Some(7)),
+        (TransactionType::Deposit, 3, 4, Some(1)),
+        (TransactionType::Dispute, 1, 2, None),
+        (TransactionType::Dispute, 2, 3, None),
+        // Resolved, so no longer open.
+        (TransactionType::Dispute, 3, 4, None),
+        (TransactionType::Resolve, 3, 4, None),
+    ];
+    for (transaction_type, client, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let account_states = account_manager.collect_account_states().await?;
+    assert_eq!(
+        open_disputes(&account_states),
+        vec![
+            OpenDispute {
+                client: 1,
+                tx: 2,
+                amount: (5).into(),
+            },
+            OpenDispute {
+                client: 2,
+                tx: 3,
+                amount: (7).into(),
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn hash_routing_spreads_clustered_clients_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 4;
+    const NUM_CLIENTS: u16 = 64;
+
+    let mut clients_per_worker = Vec::new();
+    for route_by in [RouteBy::Modulus, RouteBy::Hash] {
+        let account_manager = AccountManager::with_config(
+            NUM_WORKERS,
+            ProcessingConfig {
+                route_by,
+                ..Default::default()
+            },
+        );
+        // Every client id is a multiple of the worker count.
+        for client in 0..NUM_CLIENTS {
+            account_manager
+                .process_transaction(Transaction {
+                    transaction_type: TransactionType::Deposit,
+                    client: client * NUM_WORKERS as u16,
+                    tx: client.into(),
+                    amount: Some((1).into()),
+                    expected_balance: None,
+                })
+                .await?;
+        }
+        let results = account_manager.collect_results().await?;
+        assert_eq!(results.client_states.len(), NUM_CLIENTS as usize);
+        let counts: Vec<usize> = results
+            .worker_stats
+            .iter()
+            .map(|stats| stats.clients)
+            .collect();
+        clients_per_worker.push(counts);
+    }
+
+    // Hash routing is what is used unless asked otherwise.
+    assert_eq!(ProcessingConfig::default().route_by, RouteBy::Hash);
+    // Modulus routing puts every client on the first worker.
+    assert_eq!(clients_per_worker[0], vec![NUM_CLIENTS as usize, 0, 0, 0]);
+    // Hash routing gives every worker a reasonable share.
+    let expected_share = NUM_CLIENTS as usize / NUM_WORKERS;
+    for count in &clients_per_worker[1] {
+        assert!(
+            *count >= expected_share / 2,
+            "Unbalanced hash routing: {:?}",
+            clients_per_worker[1]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn routing_is_stable_per_client_test() {
+    for route_by in [RouteBy::Modulus, RouteBy::Hash] {
+        for client in 0..1000 {
+            let worker_index = route_by.worker_index(client, 7);
+            assert!(worker_index < 7);
+            assert_eq!(worker_index, route_by.worker_index(client, 7));
+        }
+    }
+}
+
+#[tokio::test]
+async fn chargeback_on_locked_account_still_removes_held_test() -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections(&[
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Deposit, 2, Some("3")),
+        (TransactionType::Deposit, 3, Some("1")),
+        (TransactionType::Dispute, 1, None),
+        (TransactionType::Dispute, 2, None),
+        (TransactionType::Chargeback, 1, None),
+        // The account is already locked by the first chargeback.
+        (TransactionType::Chargeback, 2, None),
+    ])
+    .await?;
+
+    assert_eq!(rejections, vec![]);
+    assert_eq!(state, ClientState::new(1, (1).into(), (0).into(), true));
+    assert_eq!(state.charged_back, (8).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn withdrawal_of_entire_available_balance_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+
+    let transactions = [
+        (TransactionType::Deposit, 1, 5),
+        (TransactionType::Withdrawal, 2, 5),
+    ];
+    for (transaction_type, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(amount.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let account_states = account_manager.collect_account_states().await?;
+    assert_eq!(
+        account_states,
+        vec![ClientState::new(
+            1,          /* client */
+            (0).into(), /* available */
+            (0).into(), /* held */
+            false,      /* locked */
+        )]
+    );
+    assert_eq!(account_states[0].withdrawn, (5).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn overdraft_limit_allows_negative_available_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            overdraft_limit: Some((10).into()),
+            ..Default::default()
+        },
+    );
+
+    let transactions = [
+        (TransactionType::Deposit, 1, "5"),
+        // Within the overdraft limit, available goes down to -3.
+        (TransactionType::Withdrawal, 2, "8"),
+        // Would take available to -10.5, which is past the limit.
+        (TransactionType::Withdrawal, 3, "7.5"),
+    ];
+    for (transaction_type, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: Some(amount.parse().unwrap()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let account_states = account_manager.collect_account_states().await?;
+    assert_eq!(
+        account_states,
+        vec![ClientState::new(1, (-3).into(), (0).into(), false)]
+    );
+    assert_eq!(account_states[0].withdrawn, (8).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_tx_per_client_rejects_past_limit_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 5;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            max_tx_per_client: Some(2),
+            collect_rejections: true,
+            ..Default::default()
+        },
+    );
+
+    // Client 1 goes over the limit on tx 5 while client 2 keeps being processed.
+    let transactions = [(1, 1), (2, 2), (1, 3), (2, 4), (1, 5)];
+    for (client, tx) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx,
+                amount: Some((1).into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let results = account_manager.collect_results().await?;
+    let mut account_states = Vec::from(results.client_states);
+    account_states.sort_by_key(|state| state.client);
+    assert_eq!(
+        account_states,
+        vec![
+            ClientState::new(1, (2).into(), (0).into(), false),
+            ClientState::new(2, (2).into(), (0).into(), false),
+        ]
+    );
+    assert_eq!(results.rejections.len(), 1);
+    assert_eq!(
+        results.rejections[0].category,
+        RejectionCategory::TransactionLimitExceeded
+    );
+    assert_eq!(results.rejections[0].transaction.as_ref().unwrap().tx, 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn forbid_zero_tx_rejects_zero_tx_deposit_test() -> Result<(), Error> {
+    for forbid_zero_tx in [false, true] {
+        let account_manager = AccountManager::with_config(
+            1,
+            ProcessingConfig {
+                forbid_zero_tx,
+                collect_rejections: true,
+                ..Default::default()
+            },
+        );
+        for tx in [0, 1] {
+            account_manager
+                .process_transaction(Transaction {
+                    transaction_type: TransactionType::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some((1).into()),
+                    expected_balance: None,
+                })
+                .await?;
+        }
+
+        let results = account_manager.collect_results().await?;
+        let categories: Vec<RejectionCategory> = results
+            .rejections
+            .iter()
+            .map(|rejection| rejection.category)
+            .collect();
+        if forbid_zero_tx {
+            assert_eq!(categories, vec![RejectionCategory::ZeroTx]);
+            assert_eq!(results.client_states[0].available, (1).into());
+        } else {
+            assert_eq!(categories, vec![]);
+            assert_eq!(results.client_states[0].available, (2).into());
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn collect_account_states_returns_every_client_once_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 7;
+    const CLIENTS: u16 = 1000;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+    for client in 0..CLIENTS {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx: u32::from(client),
+                amount: Some(client.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = Vec::from(account_manager.collect_account_states().await?);
+    account_states.sort_unstable_by_key(|state| state.client);
+    let expected: Vec<ClientState> = (0..CLIENTS)
+        .map(|client| ClientState::new(client, client.into(), (0).into(), false))
+        .collect();
+    assert_eq!(account_states, expected);
+    Ok(())
+}
+
+/// Runs with whichever hasher the `fast-hash` feature selects, CI tests both.
+#[tokio::test]
+async fn disputes_across_many_tx_ids_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 3;
+    const CLIENTS: u32 = 50;
+    const TXS: u32 = 5000;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+    for tx in 0..TXS {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: (tx % CLIENTS) as u16,
+                tx,
+                amount: Some((1).into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+    // Dispute every other deposit.
+    for tx in (0..TXS).step_by(2) {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Dispute,
+                client: (tx % CLIENTS) as u16,
+                tx,
+                amount: None,
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut account_states = Vec::from(account_manager.collect_account_states().await?);
+    account_states.sort_unstable_by_key(|state| state.client);
+    let per_client = TXS / CLIENTS;
+    let expected: Vec<ClientState> = (0..CLIENTS)
+        .map(|client| {
+            // Clients with an even id only have even tx ids, so every deposit is disputed.
+            let held = if client % 2 == 0 { per_client } else { 0 };
+            ClientState::new(
+                client as u16,
+                (per_client - held).into(),
+                held.into(),
+                false,
+            )
+        })
+        .collect();
+    assert_eq!(account_states, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn seeded_state_has_balance_but_no_history_test() -> Result<(), Error> {
+    let account_manager = AccountManager::with_initial_states(
+        1,
+        ProcessingConfig {
+            collect_rejections: true,
+            ..Default::default()
+        },
+        vec![ClientState::new(1, (10).into(), (0).into(), false)],
+    );
+    for (transaction_type, tx, amount) in [
+        (TransactionType::Withdrawal, 2, Some(4)),
+        // Tx 1 happened before the state was seeded, so it is unknown.
+        (TransactionType::Dispute, 1, None),
+    ] {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: amount.map(BigDecimal::from),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let mut results = account_manager.collect_results().await?;
+    let categories: Vec<RejectionCategory> = results
+        .rejections
+        .iter()
+        .map(|rejection| rejection.category)
+        .collect();
+    assert_eq!(categories, vec![RejectionCategory::UnknownTx]);
+    assert_eq!(
+        results.client_states.pop_front().unwrap(),
+        ClientState::new(1, (6).into(), (0).into(), false)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_client_state_while_processing_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 3;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+    let deposit = |client, tx, amount: u32| Transaction {
+        transaction_type: TransactionType::Deposit,
+        client,
+        tx,
+        amount: Some(amount.into()),
+        expected_balance: None,
+    };
+
+    assert_eq!(account_manager.get_client_state(1).await?, None);
+    account_manager
+        .process_transaction(deposit(1, 1, 5))
+        .await?;
+    account_manager
+        .process_transaction(deposit(2, 2, 7))
+        .await?;
+    assert_eq!(
+        account_manager.get_client_state(1).await?,
+        Some(ClientState::new(1, (5).into(), (0).into(), false))
+    );
+    account_manager
+        .process_transaction(deposit(1, 3, 2))
+        .await?;
+    assert_eq!(
+        account_manager.get_client_state(1).await?,
+        Some(ClientState::new(1, (7).into(), (0).into(), false))
+    );
+    assert_eq!(account_manager.get_client_state(3).await?, None);
+
+    // Querying does not stop the workers.
+    let mut account_states = Vec::from(account_manager.collect_account_states().await?);
+    account_states.sort_unstable_by_key(|state| state.client);
+    assert_eq!(
+        account_states,
+        vec![
+            ClientState::new(1, (7).into(), (0).into(), false),
+            ClientState::new(2, (7).into(), (0).into(), false),
+        ]
+    );
+    Ok(())
+}
+
+/// Processes `transactions` for client 1 and returns its final state along with the category
+/// of every rejected transaction, in order.
+async fn process_and_collect_rejections(
+    transactions: &[(TransactionType, u32, Option<&str>)],
+) -> Result<(ClientState, Vec<(u32, RejectionCategory)>), Error> {
+    process_and_collect_rejections_with_config(ProcessingConfig::default(), transactions).await
+}
+
+/// Same as `process_and_collect_rejections`, but with the given `config` (rejections are
+/// always collected).
+async fn process_and_collect_rejections_with_config(
+    config: ProcessingConfig,
+    transactions: &[(TransactionType, u32, Option<&str>)],
+) -> Result<(ClientState, Vec<(u32, RejectionCategory)>), Error> {
+    let account_manager = AccountManager::with_config(
+        1,
+        ProcessingConfig {
+            collect_rejections: true,
+            ..config
+        },
+    );
+    for (transaction_type, tx, amount) in transactions {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: *transaction_type,
+                client: 1,
+                tx: *tx,
+                amount: amount.map(|amount| amount.parse().unwrap()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+    let mut results = account_manager.collect_results().await?;
+    let rejections = results
+        .rejections
+        .iter()
+        .map(|rejection| {
+            (
+                rejection.transaction.as_ref().unwrap().tx,
+                rejection.category,
+            )
+        })
+        .collect();
+    Ok((results.client_states.pop_front().unwrap(), rejections))
+}
+
+#[tokio::test]
+async fn withdrawal_reusing_deposit_tx_is_duplicate_and_dispute_uses_deposit_test(
+) -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections(&[
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Withdrawal, 1, Some("2")),
+        // References the deposit, the withdrawal was never recorded.
+        (TransactionType::Dispute, 1, None),
+    ])
+    .await?;
+
+    assert_eq!(rejections, vec![(1, RejectionCategory::DuplicateTx)]);
+    assert_eq!(state, ClientState::new(1, (0).into(), (5).into(), false));
+    assert_eq!(state.withdrawn, (0).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn deposit_reusing_withdrawal_tx_is_duplicate_and_cannot_be_disputed_test(
+) -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections(&[
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Withdrawal, 2, Some("2")),
+        (TransactionType::Deposit, 2, Some("4")),
+        // The withdrawal owns tx 2, so there is no deposit to dispute.
+        (TransactionType::Dispute, 2, None),
+    ])
+    .await?;
+
+    assert_eq!(
+        rejections,
+        vec![
+            (2, RejectionCategory::DuplicateTx),
+            (2, RejectionCategory::InvalidTxState),
+        ]
+    );
+    assert_eq!(state, ClientState::new(1, (3).into(), (0).into(), false));
+    Ok(())
+}
+
+#[tokio::test]
+async fn deposit_reusing_charged_back_tx_is_duplicate_test() -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections(&[
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Deposit, 2, Some("3")),
+        (TransactionType::Dispute, 2, None),
+        (TransactionType::Chargeback, 2, None),
+        // The tx id stays owned by the charged back deposit.
+        (TransactionType::Deposit, 2, Some("3")),
+    ])
+    .await?;
+
+    assert_eq!(rejections, vec![(2, RejectionCategory::DuplicateTx)]);
+    assert_eq!(state, ClientState::new(1, (5).into(), (0).into(), true));
+    Ok(())
+}
+
+#[tokio::test]
+async fn ignore_policy_skips_redelivered_deposit_test() -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections_with_config(
+        ProcessingConfig {
+            deposit_collision_policy: DepositCollisionPolicy::Ignore,
+            ..Default::default()
+        },
+        &[
+            (TransactionType::Deposit, 1, Some("5")),
+            (TransactionType::Deposit, 1, Some("5.00")),
+        ],
+    )
+    .await?;
+
+    assert_eq!(rejections, vec![]);
+    assert_eq!(state, ClientState::new(1, (5).into(), (0).into(), false));
+    Ok(())
+}
+
+#[tokio::test]
+async fn ignore_policy_still_rejects_colliding_deposit_test() -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections_with_config(
+        ProcessingConfig {
+            deposit_collision_policy: DepositCollisionPolicy::Ignore,
+            ..Default::default()
+        },
+        &[
+            (TransactionType::Deposit, 1, Some("5")),
+            (TransactionType::Deposit, 1, Some("6")),
+            (TransactionType::Withdrawal, 2, Some("1")),
+            (TransactionType::Deposit, 2, Some("1")),
+        ],
+    )
+    .await?;
+
+    assert_eq!(
+        rejections,
+        vec![
+            (1, RejectionCategory::DuplicateTx),
+            (2, RejectionCategory::DuplicateTx),
+        ]
+    );
+    assert_eq!(state, ClientState::new(1, (4).into(), (0).into(), false));
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_policy_fails_on_colliding_deposit_test() -> Result<(), Error> {
+    let account_manager = AccountManager::with_config(
+        1,
+        ProcessingConfig {
+            deposit_collision_policy: DepositCollisionPolicy::Error,
+            ..Default::default()
+        },
+    );
+    for amount in ["5", "5", "6"] {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(amount.parse().unwrap()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    // The redelivery is skipped, only the deposit of 6 collides.
+    let err = account_manager.collect_results().await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert_eq!(
+        err.messages,
+        vec![
+            "Deposit (tx 1) of 6 for client 1 collides with an earlier transaction with the same tx id"
+                .to_string()
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn duplicate_deposit_messages_distinguish_redelivery_and_collision_test() {
+    let deposit = |tx, amount: &str| Transaction {
+        transaction_type: TransactionType::Deposit,
+        client: 1,
+        tx,
+        amount: Some(amount.parse().unwrap()),
+        expected_balance: None,
+    };
+    let mut state = ClientState::default();
+    state.process(deposit(1, "5")).unwrap();
+
+    let redelivery = state.process(deposit(1, "5")).unwrap_err();
+    assert!(
+        redelivery.messages[0].starts_with("Transaction (1) already processed, redelivery"),
+        "{:?}",
+        redelivery
+    );
+    let collision = state.process(deposit(1, "7")).unwrap_err();
+    assert!(
+        collision.messages[0].starts_with("Transaction (1) collides with an earlier deposit of 5"),
+        "{:?}",
+        collision
+    );
+}
+
+/// Sends `transactions_per_client` deposits and withdrawals for each client in an order where
+/// any reordering causes a rejected withdrawal or a running balance mismatch.
+async fn flood_account_manager(
+    account_manager: &AccountManager,
+    clients: u16,
+    transactions_per_client: u32,
+) -> Result<(), Error> {
+    let mut tx = 0;
+    for i in 0..transactions_per_client {
+        for client in 0..clients {
+            tx += 1;
+            let (transaction_type, amount, expected_balance) = if i % 2 == 0 {
+                (TransactionType::Deposit, 2, 3)
+            } else {
+                (TransactionType::Withdrawal, 2, 1)
+            };
+            account_manager
+                .process_transaction_from_row(
+                    tx as usize,
+                    Transaction {
+                        transaction_type,
+                        client,
+                        tx,
+                        amount: Some(amount.into()),
+                        expected_balance: Some(expected_balance.into()),
+                    },
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn backpressure_single_worker_keeps_order_test() -> Result<(), Error> {
+    const TRANSACTIONS: u32 = 5000;
+    let account_manager = AccountManager::with_config(
+        1,
+        ProcessingConfig {
+            running_balance_tolerance: Some((0).into()),
+            channel_buffer_size: Some(1),
+            ..Default::default()
+        },
+    );
+    // Start with 1 so every withdrawal leaves a positive balance.
+    account_manager
+        .process_transaction(Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 0,
+            tx: u32::MAX,
+            amount: Some((1).into()),
+            expected_balance: None,
+        })
+        .await?;
+    flood_account_manager(&account_manager, 1, TRANSACTIONS).await?;
+
+    let results = account_manager.collect_results().await?;
+    assert_eq!(results.balance_mismatches, vec![]);
+    assert_eq!(
+        results.worker_stats[0].transactions_processed,
+        u64::from(TRANSACTIONS) + 1
+    );
+    assert_eq!(
+        Vec::from(results.client_states),
+        vec![ClientState::new(0, (1).into(), (0).into(), false)]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn backpressure_many_clients_across_workers_test() -> Result<(), Error> {
+    const NUM_WORKERS: usize = 4;
+    const CLIENTS: u16 = 200;
+    const TRANSACTIONS_PER_CLIENT: u32 = 50;
+    let account_manager = AccountManager::with_config(
+        NUM_WORKERS,
+        ProcessingConfig {
+            running_balance_tolerance: Some((0).into()),
+            channel_buffer_size: Some(1),
+            ..Default::default()
+        },
+    );
+    for client in 0..CLIENTS {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx: u32::MAX - u32::from(client),
+                amount: Some((1).into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+    flood_account_manager(&account_manager, CLIENTS, TRANSACTIONS_PER_CLIENT).await?;
+
+    let results = account_manager.collect_results().await?;
+    assert_eq!(results.balance_mismatches, vec![]);
+    let transactions_processed: u64 = results
+        .worker_stats
+        .iter()
+        .map(|stats| stats.transactions_processed)
+        .sum();
+    assert_eq!(
+        transactions_processed,
+        u64::from(CLIENTS) * u64::from(TRANSACTIONS_PER_CLIENT + 1)
+    );
+    let mut account_states = Vec::from(results.client_states);
+    account_states.sort_unstable_by_key(|a| a.client);
+    let expected: Vec<ClientState> = (0..CLIENTS)
+        .map(|client| ClientState::new(client, (1).into(), (0).into(), false))
+        .collect();
+    assert_eq!(account_states, expected);
+    Ok(())
+}
+
+#[test]
+fn balance_quantiles_of_generated_sample_test() {
+    // Final balances of `generated_sample_input.csv`.
+    let decimal = |v: &str| v.parse::<BigDecimal>().unwrap();
+    let account_states = [
+        ClientState::new(1, decimal("2.49"), decimal("0"), false),
+        ClientState::new(2, decimal("0.0012"), decimal("0"), false),
+        ClientState::new(3, decimal("10"), decimal("0"), true),
+        ClientState::new(4, decimal("2.2222"), decimal("3.3333"), false),
+    ];
+
+    assert_eq!(
+        balance_quantiles(&account_states),
+        Some(BalanceQuantiles {
+            clients: 4,
+            min: decimal("0.0012"),
+            p50: decimal("2.49"),
+            p90: decimal("10"),
+            p99: decimal("10"),
+            max: decimal("10"),
+        })
+    );
+    assert_eq!(balance_quantiles(&[]), None);
+}
+
+#[tokio::test]
+async fn deposits_and_withdrawals_of_zero_or_less_are_rejected_test() -> Result<(), Error> {
+    let (state, rejections) = process_and_collect_rejections(&[
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Deposit, 2, Some("-3")),
+        (TransactionType::Deposit, 3, Some("0")),
+        (TransactionType::Withdrawal, 4, Some("-2")),
+        (TransactionType::Withdrawal, 5, Some("0.0000")),
+        (TransactionType::Withdrawal, 6, Some("1")),
+    ])
+    .await?;
+
+    assert_eq!(
+        rejections,
+        vec![
+            (2, RejectionCategory::InvalidAmount),
+            (3, RejectionCategory::InvalidAmount),
+            (4, RejectionCategory::InvalidAmount),
+            (5, RejectionCategory::InvalidAmount),
+        ]
+    );
+    assert_eq!(state, ClientState::new(1, (4).into(), (0).into(), false));
+
+    let mut state = ClientState::new(1, (0).into(), (0).into(), false);
+    let err = state
+        .process(Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some("-3".parse().unwrap()),
+            expected_balance: None,
+        })
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Amount (-3) of a deposit must be more than zero"),
+        "{}",
+        err
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn lock_freezes_deposits_rejects_deposit_after_chargeback_test() -> Result<(), Error> {
+    let transactions = [
+        (TransactionType::Deposit, 1, Some("5")),
+        (TransactionType::Deposit, 2, Some("3")),
+        (TransactionType::Dispute, 2, None),
+        (TransactionType::Chargeback, 2, None),
+        (TransactionType::Deposit, 3, Some("4")),
+    ];
+
+    // By default the deposit is applied even though the account is locked.
+    let (state, rejections) = process_and_collect_rejections(&transactions).await?;
+    assert_eq!(rejections, vec![]);
+    assert_eq!(state, ClientState::new(1, (9).into(), (0).into(), true));
+
+    let (state, rejections) = process_and_collect_rejections_with_config(
+        ProcessingConfig {
+            lock_freezes_deposits: true,
+            ..Default::default()
+        },
+        &transactions,
+    )
+    .await?;
+    assert_eq!(rejections, vec![(3, RejectionCategory::AccountLocked)]);
+    assert_eq!(state, ClientState::new(1, (5).into(), (0).into(), true));
+    Ok(())
+}
+
+#[tokio::test]
+async fn disputed_withdrawal_resolve_and_chargeback_test() -> Result<(), Error> {
+    let config = ProcessingConfig {
+        allow_withdrawal_disputes: true,
+        ..Default::default()
+    };
+    let withdraw_and_dispute = [
+        (TransactionType::Deposit, 1, Some("10")),
+        (TransactionType::Withdrawal, 2, Some("4")),
+        (TransactionType::Dispute, 2, None),
+    ];
+
+    // While disputed the withdrawn amount is held and no longer counts as withdrawn.
+    let (state, rejections) =
+        process_and_collect_rejections_with_config(config.clone(), &withdraw_and_dispute).await?;
+    assert_eq!(rejections, vec![]);
+    assert_eq!(state, ClientState::new(1, (6).into(), (4).into(), false));
+    assert_eq!(state.withdrawn, (0).into());
+    assert_eq!(
+        state.open_disputes().collect::<Vec<_>>(),
+        vec![(2, &(4).into())]
+    );
+
+    // A resolve means the withdrawal stands.
+    let mut transactions = withdraw_and_dispute.to_vec();
+    transactions.push((TransactionType::Resolve, 2, None));
+    let (state, rejections) =
+        process_and_collect_rejections_with_config(config.clone(), &transactions).await?;
+    assert_eq!(rejections, vec![]);
+    assert_eq!(state, ClientState::new(1, (6).into(), (0).into(), false));
+    assert_eq!(state.withdrawn, (4).into());
+
+    // A chargeback reverses the withdrawal and locks the account.
+    let mut transactions = withdraw_and_dispute.to_vec();
+    transactions.extend([
+        (TransactionType::Chargeback, 2, None),
+        (TransactionType::Dispute, 2, None),
+        // Tx 2 is still owned by the withdrawal.
+        (TransactionType::Deposit, 2, Some("4")),
+    ]);
+    let (state, rejections) =
+        process_and_collect_rejections_with_config(config, &transactions).await?;
+    assert_eq!(
+        rejections,
+        vec![
+            (2, RejectionCategory::InvalidTxState),
+            (2, RejectionCategory::DuplicateTx),
+        ]
+    );
+    assert_eq!(state, ClientState::new(1, (10).into(), (0).into(), true));
+    assert_eq!(state.withdrawn, (0).into());
+    assert_eq!(state.charged_back, (0).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn disputed_withdrawal_is_rewound_test() -> Result<(), Error> {
+    let account_manager = AccountManager::with_config(
+        1,
+        ProcessingConfig {
+            allow_withdrawal_disputes: true,
+            rewind: Some(2),
+            ..Default::default()
+        },
+    );
+    for (transaction_type, tx, amount) in [
+        (TransactionType::Deposit, 1, Some(10)),
+        (TransactionType::Withdrawal, 2, Some(4)),
+        (TransactionType::Dispute, 2, None),
+        (TransactionType::Chargeback, 2, None),
+    ] {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+    // Only the dispute and chargeback of the withdrawal are undone.
+    let state = account_manager
+        .collect_account_states()
+        .await?
+        .pop_front()
+        .unwrap();
+    assert_eq!(state, ClientState::new(1, (6).into(), (0).into(), false));
+    assert_eq!(state.withdrawn, (4).into());
+    Ok(())
+}
+
+#[test]
+fn rewinding_a_withdrawal_dispute_forgets_it_test() {
+    let disputed_withdrawals = |state: &ClientState| {
+        serde_json::to_value(state).unwrap()["disputed_withdrawals"]
+            .as_array()
+            .unwrap()
+            .len()
+    };
+    let mut state = ClientState::new(1, (0).into(), (0).into(), false);
+    state.configure(&ProcessingConfig {
+        allow_withdrawal_disputes: true,
+        rewind: Some(1),
+        ..Default::default()
+    });
+    for (transaction_type, tx, amount) in [
+        (TransactionType::Deposit, 1, Some(10)),
+        (TransactionType::Withdrawal, 2, Some(4)),
+        (TransactionType::Dispute, 2, None),
+        (TransactionType::Resolve, 2, None),
+        (TransactionType::Dispute, 2, None),
+    ] {
+        state
+            .process(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: amount.map(|v: u32| v.into()),
+                expected_balance: None,
+            })
+            .unwrap();
+    }
+    assert_eq!(disputed_withdrawals(&state), 1);
+
+    // The withdrawal was already disputed once before the rewound dispute.
+    state.rewind(1);
+    assert_eq!(disputed_withdrawals(&state), 1);
+    assert_eq!(state, ClientState::new(1, (6).into(), (0).into(), false));
+
+    // Undoing the resolve and the first dispute leaves a withdrawal that was never disputed.
+    state.rewind(2);
+    assert_eq!(disputed_withdrawals(&state), 0);
+    assert_eq!(state, ClientState::new(1, (6).into(), (0).into(), false));
+    assert_eq!(state.withdrawn, (4).into());
+}
+
+#[test]
+fn decimal_context_keeps_scale_stable_over_many_disputes_test() {
+    const SCALE: i64 = 4;
+    const CYCLES: u32 = 500;
+    let run = |rounding| {
+        let mut state = ClientState::new(1, (0).into(), (0).into(), false);
+        state.configure(&ProcessingConfig {
+            decimal_context: Some(DecimalContext {
+                scale: SCALE,
+                rounding,
+            }),
+            ..Default::default()
+        });
+        for tx in 0..CYCLES {
+            for (transaction_type, amount) in [
+                (TransactionType::Deposit, Some("0.123456789")),
+                (TransactionType::Dispute, None),
+                (TransactionType::Resolve, None),
+                (TransactionType::Dispute, None),
+                (TransactionType::Resolve, None),
+            ] {
+                state
+                    .process(Transaction {
+                        transaction_type,
+                        client: 1,
+                        tx,
+                        amount: amount.map(|amount| amount.parse().unwrap()),
+                        expected_balance: None,
+                    })
+                    .unwrap();
+                assert_eq!(state.available.as_bigint_and_exponent().1, SCALE);
+                assert_eq!(state.held.as_bigint_and_exponent().1, SCALE);
+            }
+        }
+        state
+    };
+
+    // Every deposit is rounded the same way before it is added, so the result is exact.
+    for (rounding, expected_available) in [
+        (RoundingMode::HalfUp, "61.7500"), // 500 * 0.1235
+        (RoundingMode::Down, "61.7000"),   // 500 * 0.1234
+        (RoundingMode::Up, "61.7500"),     // 500 * 0.1235
+    ] {
+        let state = run(rounding);
+        assert_eq!(
+            state.available,
+            expected_available.parse::<BigDecimal>().unwrap(),
+            "{:?}",
+            rounding
+        );
+        assert_eq!(state.held, (0).into());
+        assert_eq!(state.deposited.as_bigint_and_exponent().1, SCALE);
+    }
+}
+
+#[tokio::test]
+async fn try_new_rejects_out_of_range_worker_counts_test() -> Result<(), Error> {
+    let err = AccountManager::try_new(0).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages[0],
+        "`num_workers` must be at least 1 in AccountManager"
+    );
+    let err = AccountManager::try_new(u16::MAX.into()).err().unwrap();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages[0],
+        "`num_workers` must be less than u16::MAX in AccountManager, got 65535"
+    );
+
+    let account_manager = AccountManager::try_new(2)?;
+    account_manager
+        .process_transaction(Transaction {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(BigDecimal::from(1)),
+            expected_balance: None,
+        })
+        .await?;
+    assert_eq!(
+        account_manager.collect_results().await?.client_states.len(),
+        1
+    );
+    Ok(())
+}
+
+#[test]
+fn rejected_transactions_carry_their_category_test() {
+    let mut state = ClientState::new(
+        1,          /* client */
+        (0).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    let mut process = |transaction_type, tx, amount: Option<&str>| {
+        state
+            .process(Transaction {
+                transaction_type,
+                client: 1,
+                tx,
+                amount: amount.map(|amount| amount.parse().unwrap()),
+                expected_balance: None,
+            })
+            .err()
+            .map(|err| (err.rejection_category(), err.messages[0].clone()))
+    };
+
+    assert_eq!(process(TransactionType::Deposit, 1, Some("5")), None);
+    let (category, message) = process(TransactionType::Withdrawal, 2, Some("6")).unwrap();
+    assert_eq!(category, Some(RejectionCategory::InsufficientFunds));
+    // The human readable message is unchanged.
+    assert_eq!(
+        message,
+        "Account did not have enough available (5) funds in Transaction"
+    );
+    let rejected_category =
+        |rejection: Option<(Option<RejectionCategory>, String)>| rejection.unwrap().0;
+    assert_eq!(
+        rejected_category(process(TransactionType::Deposit, 1, Some("5"))),
+        Some(RejectionCategory::DuplicateTx)
+    );
+    assert_eq!(
+        rejected_category(process(TransactionType::Dispute, 9, None)),
+        Some(RejectionCategory::UnknownTx)
+    );
+    assert_eq!(
+        rejected_category(process(TransactionType::Resolve, 1, None)),
+        Some(RejectionCategory::InvalidTxState)
+    );
+    assert_eq!(
+        rejected_category(process(TransactionType::Chargeback, 1, None)),
+        Some(RejectionCategory::InvalidTxState)
+    );
+    assert_eq!(process(TransactionType::Dispute, 1, None), None);
+    assert_eq!(
+        rejected_category(process(TransactionType::Dispute, 1, None)),
+        Some(RejectionCategory::InvalidTxState)
+    );
+    assert_eq!(process(TransactionType::Chargeback, 1, None), None);
+    assert_eq!(
+        rejected_category(process(TransactionType::Withdrawal, 3, Some("1"))),
+        Some(RejectionCategory::AccountLocked)
+    );
+}
+
+#[tokio::test]
+async fn dispute_policy_allow_negative_holds_spent_funds_test() -> Result<(), Error> {
+    for dispute_policy in [DisputePolicy::Strict, DisputePolicy::AllowNegative] {
+        let account_manager = AccountManager::with_config(
+            1,
+            ProcessingConfig {
+                dispute_policy,
+                collect_rejections: true,
+                ..Default::default()
+            },
+        );
+        let transactions = [
+            (TransactionType::Deposit, 1, Some(10)),
+            (TransactionType::Withdrawal, 2, Some(9)),
+            (TransactionType::Dispute, 1, None),
+        ];
+        for (transaction_type, tx, amount) in transactions {
+            account_manager
+                .process_transaction(Transaction {
+                    transaction_type,
+                    client: 1,
+                    tx,
+                    amount: amount.map(|v: u32| v.into()),
+                    expected_balance: None,
+                })
+                .await?;
+        }
+        let results = account_manager.collect_results().await?;
+
+        let (available, held, rejections) = match dispute_policy {
+            // The client already spent most of the deposit, so the dispute is rejected.
+            DisputePolicy::Strict => (1, 0, 1),
+            // The whole deposit is held even though only 1 of it is still available.
+            DisputePolicy::AllowNegative => (-9, 10, 0),
+        };
+        assert_eq!(results.rejections.len(), rejections);
+        assert_eq!(
+            Vec::from(results.client_states),
+            vec![ClientState::new(
+                1,                /* client */
+                available.into(), /* available */
+                held.into(),      /* held */
+                false,            /* locked */
+            )]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn rejection_log_line_names_the_input_line_test() {
+    let mut state = ClientState::new(
+        1,          /* client */
+        (1).into(), /* available */
+        (0).into(), /* held */
+        false,      /* locked */
+    );
+    let err = state
+        .process(Transaction {
+            transaction_type: TransactionType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some((2).into()),
+            expected_balance: None,
+        })
+        .unwrap_err();
+
+    assert!(rejection_log_line(Some(457), &err)
+        .starts_with("line 457: Account did not have enough available (1) funds in Transaction"));
+    assert_eq!(rejection_log_line(None, &err), err.to_string());
+}
+
+#[test]
+fn snapshot_without_closed_field_loads_test() {
+    // Written before accounts could be closed.
+    let state: ClientState = serde_json::from_str(
+        r#"{"client":1,"available":"2","held":"1","locked":false,
+            "deposited":"3","withdrawn":"0","charged_back":"0",
+            "tx_for_transaction_state":{"1":["dispute","1"],"2":["deposit","2"]}}"#,
+    )
+    .unwrap();
+    assert!(!state.closed);
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,          /* client */
+            (2).into(), /* available */
+            (1).into(), /* held */
+            false,      /* locked */
+        )
+    );
+}
+
+#[test]
+fn snapshot_with_only_original_fields_loads_test() {
+    // Written before deposits, withdrawals and chargebacks were tracked per client.
+    let state: ClientState = serde_json::from_str(
+        r#"{"client":1,"available":"2","held":"0","locked":true,
+            "tx_for_transaction_state":{"1":["deposit","2"]}}"#,
+    )
+    .unwrap();
+    assert_eq!(state.deposited, (0).into());
+    assert_eq!(state.withdrawn, (0).into());
+    assert_eq!(state.charged_back, (0).into());
+    assert_eq!(
+        state,
+        ClientState::new(
+            1,          /* client */
+            (2).into(), /* available */
+            (0).into(), /* held */
+            true,       /* locked */
+        )
+    );
+}
+
+#[tokio::test]
+async fn finished_workers_yields_the_clients_of_each_worker_test() -> Result<(), Error> {
+    use futures::TryStreamExt;
+
+    const NUM_WORKERS: usize = 3;
+    let account_manager = AccountManager::new(NUM_WORKERS);
+    for client in 1..=6 {
+        account_manager
+            .process_transaction(Transaction {
+                transaction_type: TransactionType::Deposit,
+                client,
+                tx: client.into(),
+                amount: Some(client.into()),
+                expected_balance: None,
+            })
+            .await?;
+    }
+
+    let all_worker_results: Vec<_> = account_manager.finished_workers().try_collect().await?;
+    assert_eq!(all_worker_results.len(), NUM_WORKERS);
+    let mut results = ProcessingResults::default();
+    for worker_results in all_worker_results {
+        // Every worker only reports itself, and only its own clients.
+        assert_eq!(worker_results.worker_stats.len(), 1);
+        assert_eq!(
+            worker_results.worker_stats[0].clients,
+            worker_results.client_states.len()
+        );
+        results.append(worker_results);
+    }
+    let worker_indexes: Vec<_> = results
+        .worker_stats
+        .iter()
+        .map(|stats| stats.worker_index)
+        .collect();
+    assert_eq!(worker_indexes, vec![0, 1, 2]);
+    let mut clients: Vec<_> = results
+        .client_states
+        .iter()
+        .map(|state| state.client)
+        .collect();
+    clients.sort_unstable();
+    assert_eq!(clients, vec![1, 2, 3, 4, 5, 6]);
+    Ok(())
+}
+
+#[test]
+fn account_balance_total_is_the_sum_of_the_rounded_columns_test() {
+    let state = ClientState::new(1, "1.004".parse().unwrap(), "1.005".parse().unwrap(), false);
+    let balance = AccountBalance::with_rounding(
+        &state,
+        OutputRounding {
+            precision: 2,
+            available: RoundingMode::HalfUp,
+            held: RoundingMode::Down,
+        },
+    );
+    assert_eq!(
+        balance,
+        AccountBalance {
+            client: 1,
+            available: "1.00".parse().unwrap(),
+            held: "1.00".parse().unwrap(),
+            // Rounding the unrounded total (2.009) would give 2.01.
+            total: "2.00".parse().unwrap(),
+            locked: false,
+        }
+    );
+}
@@ -1,16 +1,51 @@
 // Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
 
+use bigdecimal::BigDecimal;
+use clap::Parser;
 use pretty_assertions::assert_eq; // Gives easier to read output for assert errors.
-use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::try_join;
 
-use crate::{run_with_args, Args, Error};
+use crate::account_manager::{ClientState, ProcessingConfig};
+use crate::common::{Transaction, TransactionType};
+use crate::rejections::RejectionCategory;
+use crate::sink::{Cell, OutputSink};
+use crate::verify_output::VerifyingWriter;
+use crate::{
+    process_stream, process_stream_with_config, resolve_worker_count, reused_tx_ids, run_with_args,
+    run_with_args_and_stdin, run_with_sink, workers_for_input_size, Args, Error, ErrorKind,
+    InputSummary,
+};
+
+/// Runs `run_with_args` and returns everything it wrote to the output, split by line.
+async fn run_and_collect_output(args: Args) -> Result<Vec<String>, Error> {
+    // Configure a future that will process the output of our program into a vector by line.
+    const BUFFER_SIZE: usize = 1024;
+    let (tx, rx) = io::duplex(BUFFER_SIZE);
+    let reader_spawn_fut = async move {
+        let mut buf_reader = BufReader::new(rx);
+        let mut output_data = vec![];
+        loop {
+            let mut line = String::new();
+            let bytes_read = buf_reader.read_line(&mut line).await?;
+            if bytes_read == 0 {
+                break; // EOF.
+            }
+            output_data.push(line);
+        }
+        Result::<Vec<String>, Error>::Ok(output_data)
+    };
+
+    let (_, output_lines) = try_join!(run_with_args(args, tx), reader_spawn_fut)?;
+    Ok(output_lines)
+}
 
 #[tokio::test]
 async fn sanity_check_provided_sample_data_test() -> Result<(), Error> {
-    let args = Args {
-        transactions_file: "src/tests/data/provided_sample_input.csv".to_string(),
-    };
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
 
     // Configure a future that will process the output of our program into a vector by line.
     const BUFFER_SIZE: usize = 1024;
@@ -45,11 +80,49 @@ async fn sanity_check_provided_sample_data_test() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn sanity_check_generated_sample_data_test() -> Result<(), Error> {
-    let args = Args {
-        transactions_file: "src/tests/data/generated_sample_input.csv".to_string(),
+async fn reads_transactions_from_stdin_test() -> Result<(), Error> {
+    let args = Args::parse_from(["account_balance_calculator", "-"]);
+
+    const BUFFER_SIZE: usize = 16;
+    let (mut stdin_tx, stdin_rx) = io::duplex(BUFFER_SIZE);
+    let stdin_writer_fut = async move {
+        let input = tokio::fs::read("src/tests/data/provided_sample_input.csv").await?;
+        stdin_tx.write_all(&input).await?;
+        // Dropping the writer closes stdin.
+        Result::<(), Error>::Ok(())
+    };
+    let (tx, rx) = io::duplex(BUFFER_SIZE);
+    let reader_fut = async move {
+        let mut output = String::new();
+        BufReader::new(rx).read_to_string(&mut output).await?;
+        Result::<String, Error>::Ok(output)
     };
 
+    let (_, _, output) = try_join!(
+        run_with_args_and_stdin(args, stdin_rx, tx),
+        stdin_writer_fut,
+        reader_fut
+    )?;
+    let mut output_lines: Vec<&str> = output.lines().collect();
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked",
+            "1,1.5000,0,1.5000,false",
+            "2,2.0000,0,2.0000,false",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn sanity_check_generated_sample_data_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
     // Configure a future that will process the output of our program into a vector by line.
     const BUFFER_SIZE: usize = 1024;
     let (tx, rx) = io::duplex(BUFFER_SIZE);
@@ -83,3 +156,2736 @@ async fn sanity_check_generated_sample_data_test() -> Result<(), Error> {
     );
     Ok(())
 }
+
+#[tokio::test]
+async fn check_running_balance_parses_expected_balance_column_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--check-running-balance",
+        "src/tests/data/running_balance_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn resolve_worker_count_clamps_env_override_test() -> Result<(), Error> {
+    const MAX_WORKERS: usize = 64;
+    assert_eq!(
+        resolve_worker_count(Some("100000".into()), MAX_WORKERS)?,
+        64
+    );
+    assert_eq!(resolve_worker_count(Some("0".into()), MAX_WORKERS)?, 1);
+    assert_eq!(resolve_worker_count(Some("8".into()), MAX_WORKERS)?, 8);
+    // Detected cpu count is never allowed to be outside the range either.
+    let detected = resolve_worker_count(None, MAX_WORKERS)?;
+    assert!((1..=MAX_WORKERS).contains(&detected));
+    Ok(())
+}
+
+#[test]
+fn resolve_worker_count_rejects_invalid_env_override_test() {
+    let err = resolve_worker_count(Some("eight".into()), 64).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages[0],
+        "ACCOUNT_WORKER_SPAWNS must be a number of workers, got \"eight\""
+    );
+
+    #[cfg(unix)]
+    {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+        // Not valid UTF-8.
+        let err = resolve_worker_count(Some(OsString::from_vec(vec![0xff])), 64).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidInput);
+    }
+}
+
+#[tokio::test]
+async fn workers_flag_sets_the_worker_count_test() -> Result<(), Error> {
+    let dir = make_test_dir("workers_flag")?;
+    let manifest_path = dir.join("manifest.json");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        // Wins over the input size heuristic, which picks a single worker for this input.
+        "--workers",
+        "3",
+        "--manifest-out",
+        manifest_path.to_str().unwrap(),
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    run_and_collect_output(args).await?;
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).unwrap();
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(manifest["workers"], 3);
+
+    for workers in ["0", "65535"] {
+        let args = Args::parse_from([
+            "account_balance_calculator",
+            "--workers",
+            workers,
+            "src/tests/data/provided_sample_input.csv",
+        ]);
+        let err = run_and_collect_output(args).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidInput);
+        assert_eq!(
+            err.messages[0],
+            format!(
+                "--workers must be at least 1 and less than 65535, got {}",
+                workers
+            )
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn channel_buffer_size_must_be_at_least_one_test() -> Result<(), Error> {
+    // The smallest buffer still processes everything, it only waits more often.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--channel-buffer-size",
+        "1",
+        "--batch-size",
+        "1",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    let mut output_lines = run_and_collect_output(args).await?;
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--channel-buffer-size",
+        "0",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages[0],
+        "`channel_buffer_size` must be at least 1 in AccountManager"
+    );
+    Ok(())
+}
+
+#[test]
+fn workers_for_input_size_uses_one_worker_for_small_inputs_test() {
+    const WORKERS: usize = 8;
+    assert_eq!(workers_for_input_size(WORKERS, Some(0)), 1);
+    assert_eq!(workers_for_input_size(WORKERS, Some(10 * 1024)), 1);
+    assert_eq!(
+        workers_for_input_size(WORKERS, Some(10 * 1024 * 1024)),
+        WORKERS
+    );
+    // Size of pipes and other streams is not known up front, so they keep every worker.
+    assert_eq!(workers_for_input_size(WORKERS, None), WORKERS);
+}
+
+#[tokio::test]
+async fn with_netflow_adds_columns_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--with-netflow",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked,deposited,withdrawn,net_flow\n",
+            "1,1.5000,0,1.5000,false,3.0000,1.5000,1.5000\n",
+            "2,2.0000,0,2.0000,false,2.0000,0,2.0000\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn with_peak_balance_reports_highest_available_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--with-peak-balance",
+        "src/tests/data/peak_balance_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    output_lines[1..].sort_unstable();
+    // Client 1 peaked at 50 before withdrawing, client 2 got back to its peak on resolve.
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked,peak_available\n",
+            "1,8.0000,0,8.0000,false,50.0000\n",
+            "2,5.0000,0.0000,5.0000,false,5.0000\n",
+        ]
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reads_transactions_from_named_pipe_test() -> Result<(), Error> {
+    use std::process::Command;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    let fifo_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_fifo_test_{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&fifo_path);
+    let status = Command::new("mkfifo").arg(&fifo_path).status()?;
+    assert!(status.success(), "mkfifo failed");
+
+    // Write the transactions in small pieces with pauses in between to make sure partial
+    // rows are handled and that we only stop reading once the writer closes the pipe.
+    let writer_path = fifo_path.clone();
+    let writer_fut = async move {
+        let mut pipe = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&writer_path)
+            .await?;
+        let chunks = [
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndepo",
+            "sit,2,2,2.0\ndeposit,1,3,2.0\n",
+            "withdrawal,1,4,1.5\nwithdrawal,2,5,3.0\n",
+        ];
+        for chunk in chunks {
+            pipe.write_all(chunk.as_bytes()).await?;
+            pipe.flush().await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Result::<(), Error>::Ok(())
+    };
+
+    let args = Args::parse_from(["account_balance_calculator", fifo_path.to_str().unwrap()]);
+    let result = try_join!(run_and_collect_output(args), writer_fut);
+    std::fs::remove_file(&fifo_path)?;
+    let (mut output_lines, _) = result?;
+
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn flag_held_ratio_marks_mostly_disputed_accounts_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--flag-held-ratio",
+        "0.5",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked,held_ratio_flagged\n",
+            "1,2.4900,0,2.4900,false,false\n",
+            "2,0.0012,0,0.0012,false,false\n",
+            "3,10.0000,0.0000,10.0000,true,false\n",
+            "4,2.2222,3.3333,5.5555,false,true\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn merge_non_overlapping_outputs_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "merge",
+        "src/tests/data/merge_shard_a.csv",
+        "src/tests/data/merge_shard_b.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "3,10.0000,0.0000,10.0000,true\n",
+            "2,2.0000,0,2.0000,false\n",
+            "4,2.2222,3.3333,5.5555,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn merge_keeps_output_precision_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--precision",
+        "8",
+        "merge",
+        "src/tests/data/merge_shard_precise.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "5,1.12345678,0.00000001,1.12345679,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn merge_overlapping_outputs_errors_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "merge",
+        "src/tests/data/merge_shard_a.csv",
+        "src/tests/data/merge_shard_overlap.csv",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert_eq!(
+        err.messages,
+        vec![
+            "Client 3 appears in both 'src/tests/data/merge_shard_a.csv' and \
+             'src/tests/data/merge_shard_overlap.csv', shards must not overlap"
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn dump_final_state_includes_tx_states_test() -> Result<(), Error> {
+    let dump_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_dump_test_{}.json",
+        std::process::id()
+    ));
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--dump-final-state",
+        dump_path.to_str().unwrap(),
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let dump = std::fs::read(&dump_path)?;
+    std::fs::remove_file(&dump_path)?;
+
+    let clients: serde_json::Value = serde_json::from_slice(&dump).unwrap();
+    let client_3 = clients
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|client| client["client"] == 3)
+        .unwrap();
+    assert_eq!(client_3["locked"], true);
+    // Tx 10 went through deposit -> dispute -> chargeback.
+    assert_eq!(client_3["tx_for_transaction_state"]["10"][0], "chargeback");
+    assert_eq!(
+        client_3["tx_for_transaction_state"]["12"],
+        serde_json::json!(["deposit", "10"])
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn batched_dispatch_matches_per_message_dispatch_test() -> Result<(), Error> {
+    let mut outputs = Vec::new();
+    for batch_size in ["1", "3", "1000"] {
+        let args = Args::parse_from([
+            "account_balance_calculator",
+            "--batch-size",
+            batch_size,
+            "src/tests/data/generated_sample_input.csv",
+        ]);
+        let mut output_lines = run_and_collect_output(args).await?;
+        // Ordering in the output is undefined, so we must sort here, but cannot include header
+        // line in our sorting.
+        output_lines[1..].sort_unstable();
+        outputs.push(output_lines);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0], outputs[2]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn no_header_maps_columns_by_position_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--no-header",
+        "src/tests/data/headerless_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    // Same as the provided sample, the rows with the wrong number of columns are skipped.
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn dust_threshold_omits_small_unlocked_accounts_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--dust-threshold",
+        "1",
+        "src/tests/data/dust_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    // Client 1 (0.99999) is just below the threshold, client 2 (1.00001) is just above and
+    // client 3 is empty but locked.
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "2,1.0000,0,1.0000,false\n",
+            "3,0.0000,0.0000,0.0000,true\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn selftest_reports_success_test() -> Result<(), Error> {
+    let args = Args::parse_from(["account_balance_calculator", "selftest"]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(
+        output_lines,
+        vec![
+            "PASS provided_sample_input.csv\n",
+            "PASS generated_sample_input.csv\n",
+            "2/2 self tests passed\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn lint_reports_crafted_anomalies_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "lint",
+        "src/tests/data/lint_anomalies_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    let anomalies: Vec<(String, Option<u64>, Option<u64>)> = output_lines
+        .iter()
+        .map(|line| {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            (
+                value["kind"].as_str().unwrap().to_string(),
+                value["row_number"].as_u64(),
+                value["client"].as_u64(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        anomalies,
+        vec![
+            ("round_amount".to_string(), Some(2), Some(1)),
+            ("duplicate_amount".to_string(), Some(5), Some(1)),
+            (
+                "withdrawal_after_matching_deposit".to_string(),
+                Some(6),
+                Some(1)
+            ),
+            ("tx_id_reuse".to_string(), Some(11), Some(2)),
+            ("high_dispute_rate".to_string(), None, Some(2)),
+            ("tx_id_gap".to_string(), None, None),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_format_writes_array_of_accounts_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--format",
+        "json",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let output = run_and_collect_output(args).await?.concat();
+    let mut accounts: Vec<serde_json::Value> = serde_json::from_str(&output).unwrap();
+    accounts.sort_unstable_by_key(|account| account["client"].as_u64());
+    assert_eq!(
+        accounts,
+        vec![
+            serde_json::json!({"client": 1, "available": "2.4900", "held": "0", "total": "2.4900", "locked": false}),
+            serde_json::json!({"client": 2, "available": "0.0012", "held": "0", "total": "0.0012", "locked": false}),
+            serde_json::json!({"client": 3, "available": "10.0000", "held": "0.0000", "total": "10.0000", "locked": true}),
+            serde_json::json!({"client": 4, "available": "2.2222", "held": "3.3333", "total": "5.5555", "locked": false}),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn precision_rounds_every_amount_column_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--precision",
+        "2",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,2.49,0,2.49,false\n",
+            "2,0.00,0,0.00,false\n",
+            "3,10.00,0.00,10.00,true\n",
+            "4,2.22,3.33,5.55,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn precision_above_maximum_is_rejected_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--precision",
+        "29",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn types_allow_list_skips_disputes_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--types",
+        "deposit,withdrawal",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    output_lines[1..].sort_unstable();
+    // Nothing is held and client 3 is not locked, since disputes and chargebacks are skipped.
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,2.4900,0,2.4900,false\n",
+            "2,0.0012,0,0.0012,false\n",
+            "3,10000000008.9999,0,10000000008.9999,false\n",
+            "4,5.5555,0,5.5555,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn types_allow_list_rejects_unknown_type_test() {
+    let result = Args::try_parse_from([
+        "account_balance_calculator",
+        "--types",
+        "deposit,refund",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn strict_fails_on_first_rejected_row_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--strict",
+        "src/tests/data/net_outflow_input.csv",
+    ]);
+
+    // Row 3 withdraws more than client 1 has.
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert_eq!(
+        err.messages[0],
+        "Transaction 2 of client 1 on row 3 was rejected"
+    );
+    assert_eq!(
+        err.rejection_category(),
+        Some(RejectionCategory::InsufficientFunds)
+    );
+}
+
+#[tokio::test]
+async fn strict_fails_on_unparsable_row_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--strict",
+        "src/tests/data/rejections_input.csv",
+    ]);
+
+    // Row 3 would be rejected when applied, but rows are parsed ahead of being applied so
+    // the unparsable row 6 is found first.
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert_eq!(err.messages[0], "Could not parse line 6");
+    assert_eq!(err.rejection_category(), Some(RejectionCategory::Parse));
+}
+
+#[tokio::test]
+async fn table_format_aligns_columns_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--format",
+        "table",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client  available    held    total  locked\n",
+            "     1     2.4900  0.0000   2.4900      no\n",
+            "     2     0.0012  0.0000   0.0012      no\n",
+            "     3    10.0000  0.0000  10.0000     yes\n",
+            "     4     2.2222  3.3333   5.5555      no\n",
+        ]
+    );
+    // Every value in a column ends at the same position, so the decimal points line up.
+    let decimal_positions: Vec<Option<usize>> = output_lines[1..]
+        .iter()
+        .map(|line| line.find('.'))
+        .collect();
+    assert!(decimal_positions.iter().all(|v| v == &decimal_positions[0]));
+    Ok(())
+}
+
+#[cfg(feature = "kv")]
+#[tokio::test]
+async fn kv_out_writes_balances_by_client_test() -> Result<(), Error> {
+    use crate::kv_store::client_key;
+    use crate::output::AccountBalance;
+
+    let kv_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_kv_test_{}",
+        std::process::id()
+    ));
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--kv-out",
+        kv_path.to_str().unwrap(),
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    // sled releases the lock of the store from a background thread, shortly after the run
+    // has closed it.
+    let open_db = || {
+        for _ in 0..100 {
+            if let Ok(db) = sled::open(&kv_path) {
+                return db;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        sled::open(&kv_path).unwrap()
+    };
+    run_and_collect_output(args).await?;
+    let db = open_db();
+    let read_balance = |db: &sled::Db, client| -> AccountBalance {
+        let value = db.get(client_key(client)).unwrap().unwrap();
+        serde_json::from_slice(&value).unwrap()
+    };
+    let client_2 = read_balance(&db, 2);
+    let client_4 = read_balance(&db, 4);
+    assert!(db.get(client_key(5)).unwrap().is_none());
+    drop(db);
+    std::fs::remove_dir_all(&kv_path)?;
+
+    assert_eq!(
+        client_2,
+        AccountBalance {
+            client: 2,
+            available: "0.0012".parse().unwrap(),
+            held: (0).into(),
+            total: "0.0012".parse().unwrap(),
+            locked: false,
+        }
+    );
+    assert_eq!(
+        client_4,
+        AccountBalance {
+            client: 4,
+            available: "2.2222".parse().unwrap(),
+            held: "3.3333".parse().unwrap(),
+            total: "5.5555".parse().unwrap(),
+            locked: false,
+        }
+    );
+
+    // The values are rounded with the same per-column rounding modes as the output.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--kv-out",
+        kv_path.to_str().unwrap(),
+        "--rounding-available",
+        "down",
+        "--rounding-held",
+        "up",
+        "src/tests/data/rounding_input.csv",
+    ]);
+    let output_lines = run_and_collect_output(args).await?;
+    let db = open_db();
+    let client_1 = read_balance(&db, 1);
+    drop(db);
+    std::fs::remove_dir_all(&kv_path)?;
+
+    assert_eq!(output_lines[1], "1,2.0000,0.0001,2.0001,false\n");
+    assert_eq!(
+        client_1,
+        AccountBalance {
+            client: 1,
+            available: "2.0000".parse().unwrap(),
+            held: "0.0001".parse().unwrap(),
+            total: "2.0001".parse().unwrap(),
+            locked: false,
+        }
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn skips_blank_and_comment_lines_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--comment-char",
+        "#",
+        "src/tests/data/blank_and_comment_lines_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn non_ascii_comment_char_is_rejected_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--comment-char",
+        "é",
+        "src/tests/data/blank_and_comment_lines_input.csv",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[tokio::test]
+async fn semicolon_delimiter_produces_same_output_test() -> Result<(), Error> {
+    let mut outputs = Vec::new();
+    for extra_args in [
+        &["src/tests/data/generated_sample_input.csv"][..],
+        &[
+            "--delimiter",
+            ";",
+            "src/tests/data/generated_sample_input_semicolon.csv",
+        ][..],
+    ] {
+        let mut args = vec!["account_balance_calculator", "--sort"];
+        args.extend(extra_args);
+        outputs.push(run_and_collect_output(Args::parse_from(args)).await?);
+    }
+    assert_eq!(outputs[0].len(), 5);
+    assert_eq!(outputs[0], outputs[1]);
+
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--delimiter",
+        "§",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages[0],
+        "Delimiter '§' must be a single ASCII character"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn padded_fields_produce_same_output_test() -> Result<(), Error> {
+    let mut outputs = Vec::new();
+    for input_file in [
+        "src/tests/data/generated_sample_input.csv",
+        // Same rows, with spaces around the header and fields.
+        "src/tests/data/generated_sample_input_padded.csv",
+    ] {
+        let args = Args::parse_from(["account_balance_calculator", "--sort", input_file]);
+        outputs.push(run_and_collect_output(args).await?);
+    }
+    assert_eq!(outputs[0].len(), 5);
+    assert_eq!(outputs[0], outputs[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn progress_does_not_change_output_test() -> Result<(), Error> {
+    let mut outputs = Vec::new();
+    for extra_args in [&[][..], &["--progress", "--progress-every", "1"][..]] {
+        let mut args = vec!["account_balance_calculator", "--sort"];
+        args.extend(extra_args);
+        args.push("src/tests/data/generated_sample_input.csv");
+        outputs.push(run_and_collect_output(Args::parse_from(args)).await?);
+    }
+    assert_eq!(outputs[0].len(), 5);
+    assert_eq!(outputs[0], outputs[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejections_file_json_test() -> Result<(), Error> {
+    let rejections_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_rejections_test_{}.jsonl",
+        std::process::id()
+    ));
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        rejections_path.to_str().unwrap(),
+        "--rejections-format",
+        "json",
+        "src/tests/data/rejections_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let rejections = std::fs::read_to_string(&rejections_path)?;
+    std::fs::remove_file(&rejections_path)?;
+
+    let rejections: Vec<serde_json::Value> = rejections
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let summary: Vec<(u64, &str)> = rejections
+        .iter()
+        .map(|rejection| {
+            (
+                rejection["row_number"].as_u64().unwrap(),
+                rejection["category"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (3, "duplicate_tx"),
+            (4, "insufficient_funds"),
+            (5, "unknown_tx"),
+            (6, "parse"),
+            (7, "invalid_tx_state"),
+            (8, "unknown_tx"),
+            (12, "account_locked"),
+        ]
+    );
+    // Rejected transactions keep the fields they were parsed with.
+    let transaction = &rejections[1]["transaction"];
+    assert_eq!(transaction["type"], "withdrawal");
+    assert_eq!(transaction["client"], 1);
+    assert_eq!(transaction["tx"], 2);
+    assert_eq!(
+        transaction["amount"]
+            .as_str()
+            .unwrap()
+            .parse::<BigDecimal>()
+            .unwrap(),
+        BigDecimal::from(10)
+    );
+    assert!(rejections[1]["message"]
+        .as_str()
+        .unwrap()
+        .contains("did not have enough available"));
+    // Rows that could not be parsed have no transaction.
+    assert_eq!(rejections[3]["transaction"], serde_json::Value::Null);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scenarios_add_columns_per_dispute_policy_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--scenarios",
+        "strict,allow-negative",
+        "src/tests/data/scenarios_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked,available_strict,held_strict,total_strict,locked_strict,available_allow-negative,held_allow-negative,total_allow-negative,locked_allow-negative\n",
+            // Client 1 disputes a deposit after withdrawing most of it.
+            "1,4.0000,0,4.0000,false,4.0000,0,4.0000,false,-6.0000,10.0000,4.0000,false\n",
+            "2,1.0000,0,1.0000,false,1.0000,0,1.0000,false,1.0000,0,1.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+/// Keeps everything given to it in memory.
+#[derive(Default)]
+struct MemorySink {
+    header: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+    finished: bool,
+}
+
+#[async_trait::async_trait(?Send)]
+impl OutputSink for MemorySink {
+    async fn write_header(&mut self, header: &[String]) -> Result<(), Error> {
+        self.header = header.to_vec();
+        Ok(())
+    }
+
+    async fn write_row(&mut self, row: &[Cell]) -> Result<(), Error> {
+        self.rows.push(row.to_vec());
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(), Error> {
+        self.finished = true;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn process_stream_applies_transactions_test() -> Result<(), Error> {
+    let transaction = |transaction_type, client, tx, amount: Option<u32>| Transaction {
+        transaction_type,
+        client,
+        tx,
+        amount: amount.map(BigDecimal::from),
+        expected_balance: None,
+    };
+    let transactions = futures::stream::iter([
+        transaction(TransactionType::Deposit, 1, 1, Some(10)),
+        transaction(TransactionType::Deposit, 2, 2, Some(5)),
+        transaction(TransactionType::Withdrawal, 1, 3, Some(4)),
+        transaction(TransactionType::Dispute, 2, 2, None),
+        // Rejected, client 2 has no available funds left.
+        transaction(TransactionType::Withdrawal, 2, 4, Some(1)),
+    ]);
+
+    let mut states = process_stream(transactions, 2).await?;
+    states.sort_unstable_by_key(|state| state.client);
+    assert_eq!(
+        states,
+        vec![
+            ClientState::new(1, (6).into(), (0).into(), false),
+            ClientState::new(2, (0).into(), (5).into(), false),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn process_stream_with_config_collects_rejections_test() -> Result<(), Error> {
+    let transaction = |transaction_type, tx, amount: Option<u32>| Transaction {
+        transaction_type,
+        client: 1,
+        tx,
+        amount: amount.map(BigDecimal::from),
+        expected_balance: None,
+    };
+    let transactions = futures::stream::iter([
+        transaction(TransactionType::Deposit, 1, Some(10)),
+        transaction(TransactionType::Withdrawal, 2, Some(20)),
+        transaction(TransactionType::Dispute, 9, None),
+    ]);
+
+    let results = process_stream_with_config(
+        transactions,
+        1,
+        ProcessingConfig {
+            collect_rejections: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let rejections: Vec<(Option<usize>, u16, u32, RejectionCategory)> = results
+        .rejections
+        .iter()
+        .map(|rejection| {
+            let transaction = rejection.transaction.as_ref().unwrap();
+            (
+                rejection.row_number,
+                transaction.client,
+                transaction.tx,
+                rejection.category,
+            )
+        })
+        .collect();
+    assert_eq!(
+        rejections,
+        vec![
+            (Some(2), 1, 2, RejectionCategory::InsufficientFunds),
+            (Some(3), 1, 9, RejectionCategory::UnknownTx),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_output_sink_receives_rows_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    let input = tokio::fs::File::open("src/tests/data/provided_sample_input.csv").await?;
+
+    let mut sink = MemorySink::default();
+    run_with_sink(&args, input, &mut sink).await?;
+
+    assert!(sink.finished);
+    assert_eq!(
+        sink.header,
+        vec!["client", "available", "held", "total", "locked"]
+    );
+    sink.rows
+        .sort_unstable_by_key(|row| row[0].format(crate::output::OutputFormat::Csv, 0));
+    let amount = |amount: &str| Cell::Amount(amount.parse().unwrap());
+    assert_eq!(
+        sink.rows,
+        vec![
+            vec![
+                Cell::Client(1),
+                amount("1.5"),
+                amount("0"),
+                amount("1.5"),
+                Cell::Flag(false),
+            ],
+            vec![
+                Cell::Client(2),
+                amount("2"),
+                amount("0"),
+                amount("2"),
+                Cell::Flag(false),
+            ],
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn output_flag_writes_to_file_test() -> Result<(), Error> {
+    let output_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_output_test_{}.csv",
+        std::process::id()
+    ));
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--output",
+        output_path.to_str().unwrap(),
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    let output = std::fs::read_to_string(&output_path)?;
+    std::fs::remove_file(&output_path)?;
+
+    assert!(output_lines.is_empty());
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort_unstable();
+    assert_eq!(
+        lines,
+        vec![
+            "client,available,held,total,locked",
+            "1,1.5000,0,1.5000,false",
+            "2,2.0000,0,2.0000,false",
+        ]
+    );
+    Ok(())
+}
+
+/// Creates an empty directory only used by the calling test.
+fn make_test_dir(name: &str) -> Result<std::path::PathBuf, Error> {
+    let dir = std::env::temp_dir().join(format!(
+        "account_balance_calculator_{}_{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir)?;
+    Ok(dir)
+}
+
+#[tokio::test]
+async fn directory_as_transactions_file_is_rejected_test() {
+    let args = Args::parse_from(["account_balance_calculator", "src/tests/data"]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages,
+        vec!["'src/tests/data' is a directory, expected a file".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn file_over_max_file_bytes_is_rejected_test() {
+    // The sample input is 108 bytes.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--max-file-bytes",
+        "100",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+    assert_eq!(
+        err.messages,
+        vec![
+            "'src/tests/data/provided_sample_input.csv' is 108 bytes, more than the maximum of 100 bytes"
+                .to_string()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn file_within_max_file_bytes_is_processed_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--max-file-bytes",
+        "108",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(output_lines.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn net_outflow_lists_only_draining_clients_test() -> Result<(), Error> {
+    let dir = make_test_dir("net_outflow")?;
+    let report_path = dir.join("net_outflow.csv");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--overdraft",
+        "10",
+        "--net-outflow",
+        report_path.to_str().unwrap(),
+        "src/tests/data/net_outflow_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let report = std::fs::read_to_string(&report_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    // Client 2 deposited more than it withdrew and client 3 withdrew exactly its deposit.
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[0], "client,net_outflow");
+    assert_eq!(lines.len(), 2);
+    let (client, amount) = lines[1].split_once(',').unwrap();
+    assert_eq!(client, "1");
+    assert_eq!(amount.parse::<BigDecimal>().unwrap(), (3).into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn checkpoints_snapshot_running_balances_test() -> Result<(), Error> {
+    let dir = make_test_dir("checkpoints")?;
+    let checkpoints_path = dir.join("checkpoints.csv");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--checkpoint-at",
+        "2,4",
+        "--checkpoints-file",
+        checkpoints_path.to_str().unwrap(),
+        "src/tests/data/checkpoint_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let checkpoints = std::fs::read_to_string(&checkpoints_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    let mut lines = checkpoints.lines();
+    assert_eq!(
+        lines.next(),
+        Some("checkpoint,client,available,held,total,locked")
+    );
+    let rows: Vec<(u32, u16, BigDecimal, BigDecimal, BigDecimal, bool)> = lines
+        .map(|line| {
+            let cells: Vec<&str> = line.split(',').collect();
+            (
+                cells[0].parse().unwrap(),
+                cells[1].parse().unwrap(),
+                cells[2].parse().unwrap(),
+                cells[3].parse().unwrap(),
+                cells[4].parse().unwrap(),
+                cells[5].parse().unwrap(),
+            )
+        })
+        .collect();
+    // Tx 2 is also referenced by the later dispute and resolve, but only its first row is a
+    // checkpoint. Tx 4 is reached while client 2's deposit is still disputed.
+    assert_eq!(
+        rows,
+        vec![
+            (2, 1, (10).into(), (0).into(), (10).into(), false),
+            (2, 2, (5).into(), (0).into(), (5).into(), false),
+            (4, 1, (7).into(), (0).into(), (7).into(), false),
+            (4, 2, (0).into(), (5).into(), (5).into(), false),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn checkpoints_span_every_transactions_file_test() -> Result<(), Error> {
+    let dir = make_test_dir("checkpoints_multi_file")?;
+    let checkpoints_path = dir.join("checkpoints.csv");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--checkpoint-at",
+        "1,3",
+        "--checkpoints-file",
+        checkpoints_path.to_str().unwrap(),
+        "src/tests/data/multi_file_part1_input.csv",
+        "src/tests/data/multi_file_part2_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let checkpoints = std::fs::read_to_string(&checkpoints_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    let mut lines = checkpoints.lines();
+    assert_eq!(
+        lines.next(),
+        Some("checkpoint,client,available,held,total,locked")
+    );
+    let rows: Vec<(u32, u16, BigDecimal, BigDecimal)> = lines
+        .map(|line| {
+            let cells: Vec<&str> = line.split(',').collect();
+            (
+                cells[0].parse().unwrap(),
+                cells[1].parse().unwrap(),
+                cells[2].parse().unwrap(),
+                cells[3].parse().unwrap(),
+            )
+        })
+        .collect();
+    // The checkpoint of tx 1 from the first file is kept, and the dispute of tx 1 in the
+    // second file does not get another one.
+    assert_eq!(
+        rows,
+        vec![
+            (1, 1, (5).into(), (0).into()),
+            (3, 1, (0).into(), (5).into()),
+            (3, 2, (2).into(), (0).into()),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn leading_plus_and_zeros_in_amounts_are_accepted_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/signed_and_padded_amounts_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    output_lines[1..].sort();
+    let available: Vec<(String, BigDecimal)> = output_lines[1..]
+        .iter()
+        .map(|line| {
+            let mut cells = line.split(',');
+            let client = cells.next().unwrap().to_string();
+            (client, cells.next().unwrap().parse().unwrap())
+        })
+        .collect();
+    // Clients 4 and 5 have malformed amounts, so they never get an account.
+    let amount: BigDecimal = "1.5".parse().unwrap();
+    assert_eq!(
+        available,
+        vec![
+            ("1".to_string(), amount.clone()),
+            ("2".to_string(), amount.clone()),
+            ("3".to_string(), amount),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_types_ignore_case_and_accept_aliases_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/transaction_type_aliases_input.csv",
+        "--sort",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(
+        output_lines[1..],
+        ["1,4.0000,0.0000,4.0000,true\n", "2,3.0000,0,3.0000,false\n",]
+    );
+
+    // Unknown types are still rejected.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/transaction_type_aliases_input.csv",
+        "--strict",
+    ]);
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.messages[0], "Could not parse line 9");
+    assert!(err.messages[1].contains("unknown variant `refund`"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn global_tx_uniqueness_reports_tx_ids_reused_across_clients_test() -> Result<(), Error> {
+    let dir = make_test_dir("global_tx_uniqueness")?;
+    let manifest_path = dir.join("manifest.json");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--global-tx-uniqueness",
+        "--types",
+        "deposit,dispute,resolve,chargeback",
+        "--manifest-out",
+        manifest_path.to_str().unwrap(),
+        "src/tests/data/global_tx_part1_input.csv",
+        "src/tests/data/global_tx_part2_input.csv",
+    ]);
+    let mut output_lines = run_and_collect_output(args).await?;
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).unwrap();
+    std::fs::remove_dir_all(dir)?;
+
+    // Tx 1 is reused within the first file and tx 2 across both files. The withdrawal of
+    // client 4 is skipped by `--types`, so it does not count as a reuse of tx 1.
+    assert_eq!(
+        manifest["reused_tx_ids"],
+        serde_json::json!({ "1": [1, 2], "2": [1, 3] })
+    );
+    // Reused tx ids are only reported, every deposit is still applied.
+    output_lines[1..].sort();
+    assert_eq!(
+        output_lines[1..],
+        [
+            "1,6.0000,0,6.0000,false\n",
+            "2,3.0000,0,3.0000,false\n",
+            "3,3.0000,0,3.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn reused_tx_ids_does_not_depend_on_input_order_test() {
+    let make_summaries = || {
+        (
+            InputSummary {
+                tx_clients: [(1, 1), (2, 1), (3, 2)].into_iter().collect(),
+                reused_tx_ids: vec![(1, 3)],
+                ..Default::default()
+            },
+            InputSummary {
+                tx_clients: [(2, 1), (3, 4), (5, 5)].into_iter().collect(),
+                reused_tx_ids: vec![(2, 6)],
+                ..Default::default()
+            },
+        )
+    };
+    let expected = [
+        (1, [1, 3].into_iter().collect()),
+        (2, [1, 6].into_iter().collect()),
+        (3, [2, 4].into_iter().collect()),
+    ]
+    .into_iter()
+    .collect();
+
+    let (first, second) = make_summaries();
+    assert_eq!(reused_tx_ids(&mut [first, second]), expected);
+    let (first, second) = make_summaries();
+    assert_eq!(reused_tx_ids(&mut [second, first]), expected);
+}
+
+#[tokio::test]
+async fn disjoint_input_dir_matches_sequential_result_test() -> Result<(), Error> {
+    let dir = make_test_dir("disjoint_sequential")?;
+    let combined_path = dir.join("combined.csv");
+    let part1 = std::fs::read_to_string("src/tests/data/disjoint_clients/part1.csv")?;
+    let part2 = std::fs::read_to_string("src/tests/data/disjoint_clients/part2.csv")?;
+    let part2_rows = part2.split_once('\n').unwrap().1;
+    std::fs::write(&combined_path, format!("{}{}", part1, part2_rows))?;
+
+    let mut sequential_lines = run_and_collect_output(Args::parse_from([
+        "account_balance_calculator",
+        combined_path.to_str().unwrap(),
+    ]))
+    .await?;
+    std::fs::remove_dir_all(&dir)?;
+    let mut concurrent_lines = run_and_collect_output(Args::parse_from([
+        "account_balance_calculator",
+        "--disjoint-clients",
+        "--input-dir",
+        "src/tests/data/disjoint_clients",
+    ]))
+    .await?;
+
+    sequential_lines[1..].sort();
+    concurrent_lines[1..].sort();
+    assert_eq!(concurrent_lines, sequential_lines);
+    assert_eq!(concurrent_lines.len(), 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sort_writes_rows_by_client_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--sort",
+        "--route-by",
+        "modulus",
+        "--workers",
+        "2",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    // Not sorted here, clients of both workers must be interleaved by the run.
+    let output_lines = run_and_collect_output(args).await?;
+    let clients: Vec<&str> = output_lines
+        .iter()
+        .map(|line| line.split(',').next().unwrap())
+        .collect();
+    assert_eq!(clients, vec!["client", "1", "2", "3", "4"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn dispute_of_unseen_client_is_not_in_output_test() -> Result<(), Error> {
+    let (mut stdin, stdin_reader) = io::duplex(1024);
+    stdin
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,1.5\ndispute,2,7,\n")
+        .await?;
+    drop(stdin);
+    let args = Args::parse_from(["account_balance_calculator", "-"]);
+    let mut output = Vec::new();
+    run_with_args_and_stdin(args, stdin_reader, &mut output).await?;
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,1.5000,0,1.5000,false\n"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_transactions_files_are_applied_in_order_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/multi_file_part1_input.csv",
+        // Disputes a deposit of the first file.
+        "src/tests/data/multi_file_part2_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,0.0000,5.0000,5.0000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn disjoint_input_dir_detects_overlapping_client_test() -> Result<(), Error> {
+    let dir = make_test_dir("disjoint_overlap")?;
+    std::fs::write(
+        dir.join("a.csv"),
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,1.0\n",
+    )?;
+    std::fs::write(
+        dir.join("b.csv"),
+        "type,client,tx,amount\ndeposit,3,3,1.0\ndeposit,2,4,1.0\n",
+    )?;
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--disjoint-clients",
+        "--input-dir",
+        dir.to_str().unwrap(),
+    ]);
+
+    let result = run_and_collect_output(args).await;
+    std::fs::remove_dir_all(&dir)?;
+
+    let err = result.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert!(
+        err.messages[0].starts_with("Client 2 appears in both"),
+        "{:?}",
+        err
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn closed_output_reader_is_clean_early_exit_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+    // Like `| head` exiting before everything was written.
+    let (writer, reader) = io::duplex(16);
+    drop(reader);
+
+    run_with_args(args, writer).await
+}
+
+/// Writer that fails every write with an error other than a broken pipe.
+struct FailingWriter;
+
+impl tokio::io::AsyncWrite for FailingWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        _buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(std::io::Error::other("disk on fire")))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn output_io_error_is_reported_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let err = run_with_args(args, FailingWriter).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Other);
+    assert_eq!(err.messages, vec!["disk on fire".to_string()]);
+}
+
+/// Writer that keeps everything written to it and counts the calls made to it.
+#[derive(Default)]
+struct CountingWriter {
+    data: Vec<u8>,
+    writes: usize,
+    flushes: usize,
+}
+
+impl tokio::io::AsyncWrite for CountingWriter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.writes += 1;
+        self.data.extend_from_slice(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.flushes += 1;
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn output_is_buffered_and_flushed_once_test() -> Result<(), Error> {
+    for format in ["csv", "json"] {
+        let args = Args::parse_from([
+            "account_balance_calculator",
+            "--format",
+            format,
+            "src/tests/data/generated_sample_input.csv",
+        ]);
+        let mut writer = CountingWriter::default();
+        run_with_args(args, &mut writer).await?;
+
+        let lines = writer.data.iter().filter(|byte| **byte == b'\n').count();
+        assert!(lines > 2, "{}: only {} lines", format, lines);
+        assert_eq!(writer.flushes, 1, "{}", format);
+        assert!(
+            writer.writes < lines,
+            "{}: {} writes",
+            format,
+            writer.writes
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconcile_passes_for_sample_input_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--reconcile",
+        "src/tests/data/generated_sample_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    assert_eq!(output_lines[0], "client,available,held,total,locked\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn atomic_output_appears_sorted_on_success_test() -> Result<(), Error> {
+    let dir = make_test_dir("atomic_success")?;
+    let output_path = dir.join("balances.csv");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--output",
+        output_path.to_str().unwrap(),
+        "--atomic-output",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    run_and_collect_output(args).await?;
+    let output = std::fs::read_to_string(&output_path)?;
+    let files_in_dir = std::fs::read_dir(&dir)?.count();
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,1.5000,0,1.5000,false\n2,2.0000,0,2.0000,false\n"
+    );
+    // Only the final file is left, no temporary file.
+    assert_eq!(files_in_dir, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn parallel_format_matches_sequential_output_test() -> Result<(), Error> {
+    let dir = make_test_dir("parallel_format")?;
+    // Enough clients for several chunks, with amounts that need rounding.
+    let input_path = dir.join("input.csv");
+    let mut input = "type,client,tx,amount\n".to_string();
+    for tx in 0..20_000u32 {
+        let transaction_type = if tx % 4 == 3 { "withdrawal" } else { "deposit" };
+        input.push_str(&format!(
+            "{},{},{},{}.{:06}\n",
+            transaction_type,
+            tx % 10_000,
+            tx,
+            tx % 7,
+            tx * 37 % 1_000_000
+        ));
+    }
+    std::fs::write(&input_path, input)?;
+
+    let mut outputs = Vec::new();
+    for parallel_format in [false, true] {
+        let output_path = dir.join(format!("output_{}.csv", parallel_format));
+        let mut args = vec![
+            "account_balance_calculator",
+            "--with-netflow",
+            "--output",
+            output_path.to_str().unwrap(),
+            // Sorts the rows, so both runs write them in the same order.
+            "--atomic-output",
+            input_path.to_str().unwrap(),
+        ];
+        if parallel_format {
+            args.push("--parallel-format");
+        }
+        run_and_collect_output(Args::parse_from(args)).await?;
+        outputs.push(std::fs::read(&output_path)?);
+    }
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        outputs[0].iter().filter(|&&byte| byte == b'\n').count(),
+        10_001
+    );
+    assert!(outputs[0] == outputs[1], "Outputs are not byte-identical");
+    Ok(())
+}
+
+#[tokio::test]
+async fn atomic_output_leaves_nothing_behind_on_error_test() -> Result<(), Error> {
+    let dir = make_test_dir("atomic_error")?;
+    let output_path = dir.join("balances.csv");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--output",
+        output_path.to_str().unwrap(),
+        "--atomic-output",
+        // Fails once every transaction was processed, right before the output is written.
+        "--dump-final-state",
+        dir.join("missing_dir").join("dump.json").to_str().unwrap(),
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let result = run_and_collect_output(args).await;
+    let files_in_dir = std::fs::read_dir(&dir)?.count();
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(result.unwrap_err().kind, ErrorKind::NotFound);
+    assert_eq!(files_in_dir, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn all_line_endings_produce_same_output_test() -> Result<(), Error> {
+    for (input_file, line_ending) in [
+        ("src/tests/data/provided_sample_input.csv", "\n"),
+        ("src/tests/data/provided_sample_input_crlf.csv", "\r\n"),
+        ("src/tests/data/provided_sample_input_cr.csv", "\r"),
+    ] {
+        // Make sure the fixture was not normalized by git or an editor.
+        let content = std::fs::read_to_string(input_file)?;
+        assert!(content.starts_with(&format!("type,client,tx,amount{}", line_ending)));
+        assert_eq!(content.matches(line_ending).count(), 6, "{}", input_file);
+
+        let args = Args::parse_from(["account_balance_calculator", input_file]);
+        let mut output_lines = run_and_collect_output(args).await?;
+        // Ordering in the output is undefined, so we must sort here, but cannot include header
+        // line in our sorting.
+        output_lines[1..].sort_unstable();
+        assert_eq!(
+            output_lines,
+            vec![
+                "client,available,held,total,locked\n",
+                "1,1.5000,0,1.5000,false\n",
+                "2,2.0000,0,2.0000,false\n",
+            ],
+            "{}",
+            input_file
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn require_metadata_accepts_matching_header_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--require-metadata",
+        "--currency",
+        "USD",
+        "src/tests/data/metadata_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            "2,2.0000,0,2.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn require_metadata_rejects_mismatched_or_missing_header_test() -> Result<(), Error> {
+    for (input_file, settings) in [
+        ("src/tests/data/metadata_input.csv", ["--precision", "2"]),
+        ("src/tests/data/metadata_input.csv", ["--currency", "EUR"]),
+        (
+            "src/tests/data/provided_sample_input.csv",
+            ["--currency", "USD"],
+        ),
+    ] {
+        let mut args = vec!["account_balance_calculator", "--require-metadata"];
+        args.extend(settings);
+        args.push(input_file);
+        let args = Args::parse_from(args);
+
+        let err = run_and_collect_output(args).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidData, "{:?}", settings);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn prefetched_rows_keep_input_order_test() -> Result<(), Error> {
+    // Far more rows than the reader prefetches, where any reordering of a client's rows
+    // causes a rejected withdrawal.
+    const CLIENTS: u32 = 7;
+    const ROUNDS: u32 = 1000;
+    let dir = make_test_dir("prefetch_order")?;
+    let input_path = dir.join("input.csv");
+    let rejections_path = dir.join("rejections.txt");
+    let mut input = "type,client,tx,amount\n".to_string();
+    let mut tx = 0;
+    for round in 0..ROUNDS {
+        for client in 0..CLIENTS {
+            tx += 1;
+            let transaction_type = if round % 2 == 0 {
+                "deposit"
+            } else {
+                "withdrawal"
+            };
+            let amount = if round == 0 { "3" } else { "2" };
+            input.push_str(&format!(
+                "{},{},{},{}\n",
+                transaction_type, client, tx, amount
+            ));
+        }
+    }
+    std::fs::write(&input_path, input)?;
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        rejections_path.to_str().unwrap(),
+        input_path.to_str().unwrap(),
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    let rejections = std::fs::read_to_string(&rejections_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(rejections, "");
+    output_lines[1..].sort_unstable();
+    let mut expected = vec!["client,available,held,total,locked\n".to_string()];
+    expected.extend((0..CLIENTS).map(|client| format!("{},1,0,1,false\n", client)));
+    assert_eq!(output_lines, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn emit_schema_lists_output_columns_test() -> Result<(), Error> {
+    let schema_path = std::env::temp_dir().join(format!(
+        "account_balance_calculator_schema_test_{}.json",
+        std::process::id()
+    ));
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--emit-schema",
+        schema_path.to_str().unwrap(),
+        "--with-netflow",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    let schema = std::fs::read(&schema_path)?;
+    std::fs::remove_file(&schema_path)?;
+
+    let schema: serde_json::Value = serde_json::from_slice(&schema).unwrap();
+    assert_eq!(
+        schema,
+        serde_json::json!({
+            "version": 1,
+            "columns": [
+                {"name": "client", "type": "integer"},
+                {"name": "available", "type": "decimal"},
+                {"name": "held", "type": "decimal"},
+                {"name": "total", "type": "decimal"},
+                {"name": "locked", "type": "boolean"},
+                {"name": "deposited", "type": "decimal"},
+                {"name": "withdrawn", "type": "decimal"},
+                {"name": "net_flow", "type": "decimal"},
+            ],
+        })
+    );
+    // The schema describes exactly the columns of the output.
+    let names: Vec<&str> = schema["columns"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|column| column["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(output_lines[0], format!("{}\n", names.join(",")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_combines_snapshot_with_new_transactions_test() -> Result<(), Error> {
+    let dir = make_test_dir("resume_combine")?;
+    let snapshot_path = dir.join("snapshot.json");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--dump-final-state",
+        snapshot_path.to_str().unwrap(),
+        "src/tests/data/resume_part1_input.csv",
+    ]);
+    run_and_collect_output(args).await?;
+
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--resume",
+        snapshot_path.to_str().unwrap(),
+        "src/tests/data/resume_part2_input.csv",
+    ]);
+    let mut output_lines = run_and_collect_output(args).await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            // The repeated deposit of tx 1 is a duplicate and the dispute from the snapshot
+            // can be resolved.
+            "1,8.0000,0.0000,8.0000,false\n",
+            "2,1.0000,0,1.0000,false\n",
+            "3,3.0000,0,3.0000,false\n",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn snapshot_round_trip_allows_disputing_earlier_transactions_test() -> Result<(), Error> {
+    let dir = make_test_dir("snapshot_round_trip")?;
+    let snapshot_path = dir.join("snapshot.json");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--snapshot-out",
+        snapshot_path.to_str().unwrap(),
+        "src/tests/data/multi_file_part1_input.csv",
+    ]);
+    run_and_collect_output(args).await?;
+
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--snapshot-in",
+        snapshot_path.to_str().unwrap(),
+        "--sort",
+        // Disputes a deposit of the first run.
+        "src/tests/data/multi_file_part2_input.csv",
+    ]);
+    let output_lines = run_and_collect_output(args).await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    // Same as reading both files in a single run.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--sort",
+        "src/tests/data/multi_file_part1_input.csv",
+        "src/tests/data/multi_file_part2_input.csv",
+    ]);
+    assert_eq!(output_lines, run_and_collect_output(args).await?);
+    assert_eq!(output_lines[1], "1,0.0000,5.0000,5.0000,false\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_rejects_duplicate_clients_in_snapshot_test() -> Result<(), Error> {
+    let dir = make_test_dir("resume_duplicate")?;
+    let snapshot_path = dir.join("snapshot.json");
+    let state = serde_json::json!({
+        "client": 1,
+        "available": "1",
+        "held": "0",
+        "locked": false,
+        "closed": false,
+        "deposited": "1",
+        "withdrawn": "0",
+        "charged_back": "0",
+        "tx_for_transaction_state": {"1": ["deposit", "1"]},
+    });
+    std::fs::write(
+        &snapshot_path,
+        serde_json::to_vec(&serde_json::json!([state, state])).unwrap(),
+    )?;
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--resume",
+        snapshot_path.to_str().unwrap(),
+        "src/tests/data/resume_part2_input.csv",
+    ]);
+
+    let result = run_and_collect_output(args).await;
+    std::fs::remove_dir_all(&dir)?;
+
+    let err = result.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert!(err.messages[0].contains("Client 1 appears more than once"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_decimal_digits_rejects_long_amounts_test() -> Result<(), Error> {
+    let dir = make_test_dir("max_decimal_digits")?;
+    let rejections_path = dir.join("rejections.jsonl");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        rejections_path.to_str().unwrap(),
+        "--rejections-format",
+        "json",
+        "src/tests/data/long_amount_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    let rejections = std::fs::read_to_string(&rejections_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    // Ordering in the output is undefined, so we must sort here, but cannot include header
+    // line in our sorting.
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            // 63 digits is within the default limit.
+            "2,2.1111,0,2.1111,false\n",
+        ]
+    );
+    let rejections: Vec<serde_json::Value> = rejections
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0]["row_number"], 3);
+    assert_eq!(rejections[0]["category"], "parse");
+    assert_eq!(
+        rejections[0]["message"],
+        "Column amount has 10000 digits, more than the maximum of 64"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_scale_rejects_amounts_with_too_many_decimal_places_test() -> Result<(), Error> {
+    let dir = make_test_dir("max_scale")?;
+    let rejections_path = dir.join("rejections.jsonl");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--max-scale",
+        "4",
+        "--rejections-file",
+        rejections_path.to_str().unwrap(),
+        "--rejections-format",
+        "json",
+        "src/tests/data/high_scale_input.csv",
+    ]);
+
+    let mut output_lines = run_and_collect_output(args).await?;
+    let rejections = std::fs::read_to_string(&rejections_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    output_lines[1..].sort_unstable();
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,1.5000,0,1.5000,false\n",
+            // 4 decimal places is within the limit.
+            "2,2.1234,0,2.1234,false\n",
+        ]
+    );
+    let rejections: Vec<serde_json::Value> = rejections
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0]["row_number"], 3);
+    assert_eq!(rejections[0]["category"], "parse");
+    assert_eq!(
+        rejections[0]["message"],
+        "Column amount has 12 decimal places, more than the maximum of 4"
+    );
+    Ok(())
+}
+
+#[cfg(feature = "ofx")]
+#[tokio::test]
+async fn ofx_format_writes_statement_per_account_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--format",
+        "ofx",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let document = run_and_collect_output(args).await?.concat();
+    assert!(document.starts_with("<?xml "));
+    assert!(document.contains("<?OFX OFXHEADER=\"200\" VERSION=\"220\""));
+    // Every element must be closed in the order it was opened.
+    let mut open_elements = Vec::new();
+    for tag in document
+        .split('<')
+        .skip(1)
+        .filter(|tag| !tag.starts_with('?'))
+    {
+        let name = &tag[..tag.find('>').expect("Tag is not terminated")];
+        match name.strip_prefix('/') {
+            Some(name) => assert_eq!(open_elements.pop(), Some(name)),
+            None => open_elements.push(name),
+        }
+    }
+    assert_eq!(open_elements, Vec::<&str>::new());
+
+    let mut balances: Vec<(String, BigDecimal, BigDecimal)> = document
+        .split("<STMTRS>")
+        .skip(1)
+        .map(|statement| {
+            let value = |element: &str| {
+                let start = statement.find(&format!("<{}>", element)).unwrap() + element.len() + 2;
+                statement[start..].split('<').next().unwrap().to_string()
+            };
+            let balance = |element: &str| {
+                let start = statement.find(&format!("<{}>", element)).unwrap();
+                let amount = statement[start..].split("<BALAMT>").nth(1).unwrap();
+                amount.split('<').next().unwrap().parse().unwrap()
+            };
+            assert_eq!(value("DTASOF").len(), "YYYYMMDDHHMMSS".len());
+            (value("ACCTID"), balance("LEDGERBAL"), balance("AVAILBAL"))
+        })
+        .collect();
+    balances.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        balances,
+        vec![
+            (
+                "1".to_string(),
+                "1.5".parse().unwrap(),
+                "1.5".parse().unwrap()
+            ),
+            ("2".to_string(), (2).into(), (2).into()),
+        ]
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "ofx"))]
+#[tokio::test]
+async fn ofx_format_requires_feature_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--format",
+        "ofx",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Unsupported);
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn serve_sse_streams_accounts_test() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--serve-sse",
+        &address.to_string(),
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    let input = tokio::fs::File::open("src/tests/data/provided_sample_input.csv").await?;
+
+    let client_fut = async move {
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(b"GET /balances HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Result::<String, Error>::Ok(response)
+    };
+    let (_, response) = try_join!(crate::sse::serve_sse(&args, input, listener), client_fut)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Content-Type: text/event-stream"));
+    let mut events: Vec<&str> = body.split_terminator("\n\n").collect();
+    assert_eq!(events.pop(), Some("event: done\ndata: {}"));
+    // The rejected withdrawal of client 2 is sent along with the other rejections of its
+    // worker, before the accounts of that worker.
+    let rejection_index = events
+        .iter()
+        .position(|event| event.starts_with("event: rejection\n"))
+        .unwrap();
+    let client_2_index = events
+        .iter()
+        .position(|event| event.contains(r#"{"client":2,"#))
+        .unwrap();
+    assert!(rejection_index < client_2_index);
+    events.remove(rejection_index);
+    events.sort_unstable();
+    assert_eq!(
+        events,
+        vec![
+            r#"event: account
+data: {"client":1,"available":"1.5000","held":"0","total":"1.5000","locked":false}"#,
+            r#"event: account
+data: {"client":2,"available":"2.0000","held":"0","total":"2.0000","locked":false}"#,
+        ]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn serve_sse_reports_closed_and_locked_rejections_test() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const INPUT_PATH: &str = "src/tests/data/closed_and_locked_input.csv";
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--serve-sse",
+        &address.to_string(),
+        INPUT_PATH,
+    ]);
+    let input = tokio::fs::File::open(INPUT_PATH).await?;
+
+    let client_fut = async move {
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(b"GET /balances HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Result::<String, Error>::Ok(response)
+    };
+    let (_, response) = try_join!(crate::sse::serve_sse(&args, input, listener), client_fut)?;
+
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let rejections: Vec<serde_json::Value> = body
+        .split_terminator("\n\n")
+        .filter_map(|event| event.strip_prefix("event: rejection\ndata: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let mut summary: Vec<(u64, &str)> = rejections
+        .iter()
+        .map(|rejection| {
+            (
+                rejection["row_number"].as_u64().unwrap(),
+                rejection["category"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    // Each worker sends its own rejections, in whichever order the workers finish.
+    summary.sort_unstable();
+    // The deposit to the closed account and the withdrawal from the locked account.
+    assert_eq!(summary, vec![(5, "account_closed"), (10, "account_locked")]);
+    let closed_rejection = rejections
+        .iter()
+        .find(|rejection| rejection["row_number"] == 5)
+        .unwrap();
+    assert!(closed_rejection["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Account (1) is closed"));
+    // Both clients have a rejection, which is sent before the accounts of their worker.
+    assert!(body.starts_with("event: rejection\n"));
+    assert!(body.ends_with("event: done\ndata: {}\n\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn rounding_modes_per_column_test() -> Result<(), Error> {
+    // Client 1 ends with 2.00005 available and 0.00005 held, exactly between two outputs.
+    for (rounding_available, rounding_held, expected_row) in [
+        ("half-up", "half-up", "1,2.0001,0.0001,2.0002,false\n"),
+        ("half-up", "down", "1,2.0001,0.0000,2.0001,false\n"),
+        ("down", "up", "1,2.0000,0.0001,2.0001,false\n"),
+    ] {
+        let args = Args::parse_from([
+            "account_balance_calculator",
+            "--rounding-available",
+            rounding_available,
+            "--rounding-held",
+            rounding_held,
+            "src/tests/data/rounding_input.csv",
+        ]);
+
+        let output_lines = run_and_collect_output(args).await?;
+        assert_eq!(
+            output_lines,
+            vec!["client,available,held,total,locked\n", expected_row]
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn checkpoint_of_last_tx_matches_rounded_output_test() -> Result<(), Error> {
+    let dir = make_test_dir("checkpoint_rounding")?;
+    let checkpoints_path = dir.join("checkpoints.csv");
+    // Tx 3 is the last row, so its checkpoint holds the final balances.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--sort",
+        "--rounding-available",
+        "down",
+        "--checkpoint-at",
+        "3",
+        "--checkpoints-file",
+        checkpoints_path.to_str().unwrap(),
+        "src/tests/data/rounding_checkpoint_input.csv",
+    ]);
+
+    let output_lines = run_and_collect_output(args).await?;
+    let checkpoints = std::fs::read_to_string(&checkpoints_path)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        output_lines,
+        vec![
+            "client,available,held,total,locked\n",
+            "1,2.0000,0.0001,2.0001,false\n",
+            "2,1.0000,0,1.0000,false\n",
+        ]
+    );
+    let checkpoint_rows: Vec<String> = checkpoints
+        .lines()
+        .skip(1)
+        .map(|line| format!("{}\n", line.strip_prefix("3,").unwrap()))
+        .collect();
+    assert_eq!(checkpoint_rows, output_lines[1..]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn verify_output_accepts_consistent_totals_test() -> Result<(), Error> {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--verify-output",
+        "--rounding-available",
+        "down",
+        "--rounding-held",
+        "up",
+        "src/tests/data/rounding_input.csv",
+    ]);
+    let output_lines = run_and_collect_output(args).await?;
+    assert!(output_lines.len() > 1, "{:?}", output_lines);
+    Ok(())
+}
+
+#[tokio::test]
+async fn verify_output_reports_inconsistent_total_test() -> Result<(), Error> {
+    let mut output = Vec::new();
+    let mut writer = VerifyingWriter::new(&mut output);
+    // Written in pieces, so lines are split across writes.
+    for data in [
+        "client,available,held,total,locked\n1,1.0000,0.5",
+        "000,1.5000,false\n2,0.3333,0.3333,0.6667,false\n",
+        "3,1,1,3,false\n",
+    ] {
+        writer.write_all(data.as_bytes()).await?;
+    }
+    let err = writer.finish().unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidData);
+    assert_eq!(
+        err.messages[0],
+        "Total (0.6667) of client 2 on line 3 of the output is not available (0.3333) + held (0.3333)"
+    );
+    // Everything is still passed through.
+    assert!(output.ends_with(b"3,1,1,3,false\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn verify_output_rejects_non_csv_format_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--verify-output",
+        "--format",
+        "json",
+        "src/tests/data/provided_sample_input.csv",
+    ]);
+    let err = run_with_args(args, io::sink()).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn replay_applies_dispute_once_its_deposit_arrived_test() -> Result<(), Error> {
+    let dir = make_test_dir("replay")?;
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    // Run 1: the dispute of tx 2 arrives before its deposit and is rejected.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        &path("rejections.jsonl"),
+        "--rejections-format",
+        "json",
+        "--dump-final-state",
+        &path("part1.json"),
+        "src/tests/data/replay_part1_input.csv",
+    ]);
+    run_with_args(args, io::sink()).await?;
+    // Run 2: the deposit arrives.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--resume",
+        &path("part1.json"),
+        "--dump-final-state",
+        &path("part2.json"),
+        "src/tests/data/replay_part2_input.csv",
+    ]);
+    run_with_args(args, io::sink()).await?;
+
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "replay",
+        &path("rejections.jsonl"),
+        "--snapshot",
+        &path("part2.json"),
+        "--dump-final-state",
+        &path("replayed.json"),
+    ]);
+    let output_lines = run_and_collect_output(args).await?;
+    let replayed: Vec<ClientState> =
+        serde_json::from_slice(&std::fs::read(path("replayed.json"))?).unwrap();
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        output_lines,
+        vec!["row,type,client,tx,result\n", "3,dispute,1,2,applied\n"]
+    );
+    assert_eq!(
+        replayed,
+        vec![ClientState::new(1, (5).into(), (3).into(), false)]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn replay_reports_transactions_still_rejected_test() -> Result<(), Error> {
+    let dir = make_test_dir("replay_still_rejected")?;
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        &path("rejections.jsonl"),
+        "--rejections-format",
+        "json",
+        "--dump-final-state",
+        &path("part1.json"),
+        "src/tests/data/replay_part1_input.csv",
+    ]);
+    run_with_args(args, io::sink()).await?;
+
+    // Nothing changed since, so the dispute is still orphaned.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "replay",
+        &path("rejections.jsonl"),
+        "--snapshot",
+        &path("part1.json"),
+    ]);
+    let output_lines = run_and_collect_output(args).await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        output_lines,
+        vec!["row,type,client,tx,result\n", "3,dispute,1,2,unknown_tx\n"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn replay_uses_the_given_processing_options_test() -> Result<(), Error> {
+    let dir = make_test_dir("replay_options")?;
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    // The withdrawal is rejected without an overdraft.
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--rejections-file",
+        &path("rejections.jsonl"),
+        "--rejections-format",
+        "json",
+        "--dump-final-state",
+        &path("state.json"),
+        "src/tests/data/replay_overdraft_input.csv",
+    ]);
+    run_with_args(args, io::sink()).await?;
+
+    let replay = |overdraft: Option<&str>| {
+        let mut args = vec!["account_balance_calculator".to_string()];
+        if let Some(overdraft) = overdraft {
+            args.extend(["--overdraft".to_string(), overdraft.to_string()]);
+        }
+        args.extend([
+            "replay".to_string(),
+            path("rejections.jsonl"),
+            "--snapshot".to_string(),
+            path("state.json"),
+        ]);
+        run_and_collect_output(Args::parse_from(args))
+    };
+    let without_overdraft = replay(None).await?;
+    let with_overdraft = replay(Some("5")).await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(
+        without_overdraft,
+        vec![
+            "row,type,client,tx,result\n",
+            "3,withdrawal,1,2,insufficient_funds\n"
+        ]
+    );
+    assert_eq!(
+        with_overdraft,
+        vec!["row,type,client,tx,result\n", "3,withdrawal,1,2,applied\n"]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "gzip")]
+#[tokio::test]
+async fn gzip_input_matches_uncompressed_output_test() -> Result<(), Error> {
+    let mut outputs = Vec::new();
+    for transactions_file in [
+        "src/tests/data/provided_sample_input.csv",
+        "src/tests/data/provided_sample_input.csv.gz",
+    ] {
+        let args = Args::parse_from(["account_balance_calculator", transactions_file]);
+        let mut output_lines = run_and_collect_output(args).await?;
+        output_lines[1..].sort_unstable();
+        outputs.push(output_lines);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+
+    // `--gzip` decompresses files without the extension too, eg: from stdin.
+    let (mut stdin, stdin_reader) = io::duplex(1024);
+    stdin
+        .write_all(&std::fs::read(
+            "src/tests/data/provided_sample_input.csv.gz",
+        )?)
+        .await?;
+    drop(stdin);
+    let args = Args::parse_from(["account_balance_calculator", "--gzip", "-"]);
+    let mut output = Vec::new();
+    run_with_args_and_stdin(args, stdin_reader, &mut output).await?;
+    let mut output_lines: Vec<String> = String::from_utf8(output)
+        .unwrap()
+        .split_inclusive('\n')
+        .map(str::to_string)
+        .collect();
+    output_lines[1..].sort_unstable();
+    assert_eq!(output_lines, outputs[0]);
+    Ok(())
+}
+
+#[cfg(not(feature = "gzip"))]
+#[tokio::test]
+async fn gzip_input_requires_feature_test() {
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "src/tests/data/provided_sample_input.csv.gz",
+    ]);
+
+    let err = run_and_collect_output(args).await.unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Unsupported);
+}
+
+#[tokio::test]
+async fn manifest_out_does_not_count_skipped_redeliveries_as_accepted_test() -> Result<(), Error> {
+    let dir = make_test_dir("manifest_out_redeliveries")?;
+    let manifest_path = dir.join("manifest.json");
+    let args = Args::parse_from([
+        "account_balance_calculator",
+        "--manifest-out",
+        manifest_path.to_str().unwrap(),
+        "--on-deposit-collision",
+        "ignore",
+        "src/tests/data/redelivery_input.csv",
+    ]);
+    run_and_collect_output(args).await?;
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).unwrap();
+    std::fs::remove_dir_all(dir)?;
+    assert_eq!(
+        manifest["rows"],
+        serde_json::json!({
+            "read": 4,
+            "skipped_by_type": 0,
+            "redeliveries_skipped": 1,
+            "accepted": 2,
+            "rejected": { "insufficient_funds": 1 },
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn manifest_out_summarizes_the_run_test() -> Result<(), Error> {
+    const INPUT_PATH: &str = "src/tests/data/provided_sample_input.csv";
+    let dir = make_test_dir("manifest_out")?;
+    let manifest_path = dir.join("manifest.json");
+    let output_path = dir.join("output.csv");
+    // Rows are sorted with `--atomic-output`, so both runs write exactly the same bytes.
+    let args_with_manifest = || {
+        Args::parse_from([
+            "account_balance_calculator",
+            "--manifest-out",
+            manifest_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+            "--atomic-output",
+            INPUT_PATH,
+        ])
+    };
+    run_and_collect_output(args_with_manifest()).await?;
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).unwrap();
+    assert_eq!(
+        manifest["inputs"],
+        serde_json::json!([{
+            "name": INPUT_PATH,
+            "bytes": std::fs::metadata(INPUT_PATH)?.len(),
+            "rows": 5,
+        }])
+    );
+    assert_eq!(
+        manifest["rows"],
+        serde_json::json!({
+            "read": 5,
+            "skipped_by_type": 0,
+            "redeliveries_skipped": 0,
+            "accepted": 4,
+            "rejected": { "insufficient_funds": 1 },
+        })
+    );
+    assert!(manifest["workers"].as_u64().unwrap() >= 1);
+    assert_eq!(manifest["options"]["precision"], 4);
+    assert_eq!(manifest["options"]["route_by"], "hash");
+    assert!(manifest["finished_at_ms"].as_u64() >= manifest["started_at_ms"].as_u64());
+    let fingerprint = manifest["output_fingerprint"].as_str().unwrap();
+    assert_eq!(fingerprint.len(), 16);
+    assert!(fingerprint.bytes().all(|byte| byte.is_ascii_hexdigit()));
+
+    // The same output always gives the same fingerprint.
+    run_and_collect_output(args_with_manifest()).await?;
+    let rerun_manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path)?).unwrap();
+    assert_eq!(rerun_manifest["output_fingerprint"], fingerprint);
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_keeps_io_error_as_source_test() {
+    let io_err = tokio::fs::File::open("src/tests/data/does_not_exist.csv")
+        .await
+        .unwrap_err();
+    let err = Error::from(io_err);
+    assert_eq!(err.kind, ErrorKind::NotFound);
+    let source = std::error::Error::source(&err).unwrap();
+    assert_eq!(
+        source.downcast_ref::<std::io::Error>().unwrap().kind(),
+        ErrorKind::NotFound
+    );
+
+    // Errors built from a message have no source.
+    let err = Error::new(ErrorKind::InvalidInput, "bad input");
+    assert!(std::error::Error::source(&err).is_none());
+    let boxed: Box<dyn std::error::Error + Send + Sync> = err.into();
+    assert!(boxed.to_string().contains("bad input"));
+}
@@ -0,0 +1,165 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use bigdecimal::BigDecimal;
+use tokio::io::AsyncWrite;
+
+use crate::{Error, ErrorKind};
+
+/// Columns of the csv output `VerifyingWriter` needs, in the order of its column indexes.
+const VERIFIED_COLUMNS: [&str; 4] = ["client", "available", "held", "total"];
+
+/// Passes everything written to it through to `inner` while parsing it back as csv output, to
+/// check that the `total` of every row is exactly its `available` plus `held` as they were
+/// written (after rounding and formatting). Call `finish` once everything is written to get
+/// the result of the check.
+pub struct VerifyingWriter<W> {
+    inner: W,
+    /// Bytes written after the last newline.
+    partial_line: Vec<u8>,
+    /// Index of each of `VERIFIED_COLUMNS`, known once the header has been written.
+    column_indexes: Option<[usize; 4]>,
+    lines_checked: usize,
+    first_error: Option<Error>,
+}
+
+impl<W> VerifyingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            partial_line: Vec::new(),
+            column_indexes: None,
+            lines_checked: 0,
+            first_error: None,
+        }
+    }
+
+    /// Checks the last line (if it has no trailing newline) and returns the first
+    /// inconsistency found in the output, if any.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if !self.partial_line.is_empty() {
+            let line = std::mem::take(&mut self.partial_line);
+            self.check_line(&line);
+        }
+        match self.first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn check_written(&mut self, data: &[u8]) {
+        self.partial_line.extend_from_slice(data);
+        while let Some(newline) = self.partial_line.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.partial_line.drain(..=newline).collect();
+            self.check_line(&line[..newline]);
+        }
+    }
+
+    fn check_line(&mut self, line: &[u8]) {
+        self.lines_checked += 1;
+        if self.first_error.is_some() {
+            return;
+        }
+        if let Err(err) = self.try_check_line(line) {
+            self.first_error = Some(err);
+        }
+    }
+
+    fn try_check_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let line_number = self.lines_checked;
+        let line = std::str::from_utf8(line).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Line {} of the output is not valid utf8", line_number),
+            )
+        })?;
+        let fields: Vec<&str> = line.split(',').collect();
+        let column_indexes = match self.column_indexes {
+            Some(column_indexes) => column_indexes,
+            None => {
+                let mut column_indexes = [0; 4];
+                for (index, name) in column_indexes.iter_mut().zip(VERIFIED_COLUMNS) {
+                    *index = fields
+                        .iter()
+                        .position(|field| *field == name)
+                        .ok_or_else(|| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Output has no '{}' column", name),
+                            )
+                        })?;
+                }
+                self.column_indexes = Some(column_indexes);
+                return Ok(());
+            }
+        };
+        let field = |column: usize| -> Result<&str, Error> {
+            let index = column_indexes[column];
+            fields.get(index).copied().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Line {} of the output has no '{}' column",
+                        line_number, VERIFIED_COLUMNS[column]
+                    ),
+                )
+            })
+        };
+        let amount = |column: usize| -> Result<BigDecimal, Error> {
+            let value = field(column)?;
+            BigDecimal::from_str(value).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Could not parse {} '{}' on line {} of the output",
+                        VERIFIED_COLUMNS[column], value, line_number
+                    ),
+                )
+            })
+        };
+        let (available, held, total) = (amount(1)?, amount(2)?, amount(3)?);
+        if total != &available + &held {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Total ({}) of client {} on line {} of the output is not available ({}) + held ({})",
+                    total,
+                    field(0)?,
+                    line_number,
+                    available,
+                    held
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for VerifyingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.check_written(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}